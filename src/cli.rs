@@ -0,0 +1,173 @@
+use std::fs;
+
+use serde::de::DeserializeOwned;
+
+use crate::world::analysis::CatalogGraph;
+use crate::world::biome::BiomeMap;
+use crate::world::deserialize::{pack_dir, BlockId, BridgeTable, GlbCollections, DEFAULT_PACK};
+
+const USAGE: &str = "usage: bevy_github_ci_template [--seed <u64>] [--summary <summary.json>] [--voxels <voxels.json>]
+       bevy_github_ci_template analyze [--biomes <biomes.json> | --catalog <data.json>] [--seeds A,pack/B,...] [--size <blocks>] [--dot <out.dot>]";
+
+/// Runs a command line tool if one was requested. Returns true when the game
+/// should not be started.
+pub fn run() -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("analyze") => {
+            if let Err(err) = analyze(&args[1..]) {
+                eprintln!("{}", err);
+                eprintln!("{}", USAGE);
+                std::process::exit(1);
+            }
+            true
+        }
+        Some("help" | "--help" | "-h") => {
+            println!("{}", USAGE);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The `--seed <u64>` the game was started with, if any.
+pub fn seed() -> Option<u64> {
+    let seed = option_value("--seed")?;
+    match seed.parse() {
+        Ok(seed) => Some(seed),
+        Err(err) => {
+            eprintln!("invalid --seed {}: {}", seed, err);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The `--summary <path>` the game was started with, if any.
+pub fn summary_path() -> Option<String> {
+    option_value("--summary")
+}
+
+/// The `--voxels <path>` the game was started with, if any.
+pub fn voxels_path() -> Option<String> {
+    option_value("--voxels")
+}
+
+fn option_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    args.find(|arg| arg == name)?;
+    args.next()
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("could not parse {}: {}", path, e))
+}
+
+/// Every pack the biome config lists, or just `catalog_path` as the default pack.
+fn read_packs(
+    biomes_path: &str,
+    catalog_path: Option<String>,
+) -> Result<(Vec<(String, GlbCollections)>, Option<BridgeTable>), String> {
+    if let Some(catalog_path) = catalog_path {
+        return Ok((
+            vec![(DEFAULT_PACK.to_string(), read_json(&catalog_path)?)],
+            None,
+        ));
+    }
+
+    // the game falls back to the default pack without a biome config too
+    let biome_map: BiomeMap = if fs::metadata(biomes_path).is_ok() {
+        read_json(biomes_path)?
+    } else {
+        BiomeMap::default()
+    };
+    let packs: Vec<(String, GlbCollections)> = biome_map
+        .packs
+        .iter()
+        .map(|pack| {
+            let path = format!("assets/{}/data.json", pack_dir(pack));
+            Ok((pack.clone(), read_json(&path)?))
+        })
+        .collect::<Result<_, String>>()?;
+    let bridges = biome_map
+        .bridges
+        .map(|path| read_json(&format!("assets/{}", path)))
+        .transpose()?;
+    Ok((packs, bridges))
+}
+
+fn analyze(args: &[String]) -> Result<(), String> {
+    let mut biomes_path = String::from("assets/biomes.json");
+    let mut catalog_path: Option<String> = None;
+    let mut seeds: Option<Vec<String>> = None;
+    let mut size = 50;
+    let mut dot_path: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+        match arg.as_str() {
+            "--biomes" => biomes_path = value()?,
+            "--catalog" => catalog_path = Some(value()?),
+            // bare block names are in the default pack
+            "--seeds" => {
+                seeds = Some(
+                    value()?
+                        .split(',')
+                        .map(|seed| BlockId::parse(seed).to_string())
+                        .collect(),
+                )
+            }
+            "--size" => {
+                size = value()?
+                    .parse()
+                    .map_err(|e| format!("invalid --size: {}", e))?
+            }
+            "--dot" => dot_path = Some(value()?),
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    let (packs, bridges) = read_packs(&biomes_path, catalog_path)?;
+    let graph = CatalogGraph::new(&packs, bridges.as_ref());
+    if let Some(seeds) = &seeds {
+        let unknown: Vec<&str> = seeds
+            .iter()
+            .filter(|seed| !graph.names.contains(seed))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!("unknown seed blocks: {}", unknown.join(", ")));
+        }
+    }
+    // the generator seeds islands from every block with a seed weight
+    let seeds = seeds.unwrap_or_else(|| {
+        let mut seeds: Vec<String> = packs
+            .iter()
+            .flat_map(|(pack, collections)| {
+                collections
+                    .0
+                    .iter()
+                    .filter(|(_, data)| data.rules.seed_weight > 0.0)
+                    .map(|(name, _)| BlockId::new(pack, name).to_string())
+            })
+            .collect();
+        seeds.sort();
+        seeds
+    });
+
+    print!("{}", graph.report(&seeds, size));
+
+    if let Some(dot_path) = dot_path {
+        fs::write(&dot_path, graph.to_dot())
+            .map_err(|e| format!("could not write {}: {}", dot_path, e))?;
+        println!("wrote {}", dot_path);
+    }
+    Ok(())
+}
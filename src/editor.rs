@@ -0,0 +1,609 @@
+use std::{collections::BTreeMap, f32::consts::FRAC_PI_2, fs};
+
+use bevy::{prelude::*, window::CursorGrabMode};
+use bevy_fps_controller::controller::{FpsController, RenderPlayer};
+use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
+use serde::Serialize;
+
+use crate::loading::AppState;
+use crate::world::deserialize::{
+    pack_dir, BlockId, CollectionData, GlbCollections, MarkovCollection, TransformMatrix,
+};
+use crate::world::markov::BlockAssets;
+use crate::world::obb::Obb;
+
+// blocks are edited high above the world so the two don't get in each other's way
+const EDITOR_ORIGIN: Vec3 = Vec3::new(0.0, 300.0, 0.0);
+const SNAP_STEPS: [f32; 4] = [1.0, 0.5, 0.25, 0.1];
+const WEIGHT_STEP: f32 = 0.25;
+
+const HELP: &str = "Tab pack  Z/X anchor  C/V block  arrows/PgUp/PgDn move  R rotate  G snap step  \
+F snap to anchor\nT next transition  Enter record  Delete remove  +/- weight  Ctrl+S save  F1 back to game";
+
+/// Adjacency authoring: an anchor block stays at the origin, a second block is moved around
+/// it and its transform relative to the anchor can be recorded as a transition.
+pub struct EditorPlugin;
+
+impl Plugin for EditorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(PanOrbitCameraPlugin)
+            .init_resource::<Editor>()
+            .add_systems(
+                Update,
+                toggle_editor
+                    .run_if(in_state(AppState::Playing).or_else(in_state(AppState::Editing))),
+            )
+            .add_systems(OnEnter(AppState::Editing), enter_editor)
+            .add_systems(OnExit(AppState::Editing), exit_editor)
+            .add_systems(
+                Update,
+                (
+                    editor_input,
+                    spawn_editor_blocks,
+                    draw_editor_gizmos,
+                    update_editor_panel,
+                )
+                    .chain()
+                    .run_if(in_state(AppState::Editing)),
+            );
+    }
+}
+
+#[derive(Resource)]
+pub struct Editor {
+    pub pack: String,
+    pub anchor: usize,
+    pub moving: usize,
+    /// the moving block's transform relative to the anchor
+    pub offset: Transform,
+    /// the anchor transition being previewed, as an index into its `transforms`
+    pub selected: Option<usize>,
+    pub snap: usize,
+    /// blocks currently spawned, respawned when the selection changes
+    spawned: Option<(BlockId, BlockId)>,
+    status: String,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Editor {
+            pack: String::new(),
+            anchor: 0,
+            moving: 0,
+            offset: Transform::from_xyz(4.0, 0.0, 0.0),
+            selected: None,
+            snap: 0,
+            spawned: None,
+            status: String::new(),
+        }
+    }
+}
+
+impl Editor {
+    fn snap_step(&self) -> f32 {
+        SNAP_STEPS[self.snap % SNAP_STEPS.len()]
+    }
+}
+
+#[derive(Component)]
+struct EditorCamera;
+
+#[derive(Component)]
+struct EditorBlock;
+
+#[derive(Component)]
+struct EditorPanel;
+
+/// Blocks that can be edited, in a stable order. Prefabs are left out, they're authored as parts.
+fn block_names(collection: &GlbCollections) -> Vec<String> {
+    let mut names: Vec<String> = collection
+        .0
+        .iter()
+        .filter(|(_, data)| !data.is_prefab())
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+fn toggle_editor(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    state: Res<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F1) {
+        return;
+    }
+    match state.get() {
+        AppState::Playing => next_state.set(AppState::Editing),
+        AppState::Editing => next_state.set(AppState::Playing),
+        _ => {}
+    }
+}
+
+fn enter_editor(
+    mut commands: Commands,
+    mut editor: ResMut<Editor>,
+    markov_collection: Res<MarkovCollection>,
+    mut window_query: Query<&mut Window>,
+    mut controller_query: Query<&mut FpsController>,
+    mut camera_query: Query<&mut Camera, With<RenderPlayer>>,
+) {
+    if !markov_collection.packs.contains_key(&editor.pack) {
+        let mut packs: Vec<&String> = markov_collection.packs.keys().collect();
+        packs.sort();
+        editor.pack = packs
+            .first()
+            .map(|pack| pack.to_string())
+            .unwrap_or_default();
+    }
+
+    // hand the mouse over from the player to the orbit camera
+    for mut window in window_query.iter_mut() {
+        window.cursor.grab_mode = CursorGrabMode::None;
+        window.cursor.visible = true;
+    }
+    for mut controller in controller_query.iter_mut() {
+        controller.enable_input = false;
+    }
+    for mut camera in camera_query.iter_mut() {
+        camera.is_active = false;
+    }
+
+    commands.spawn((
+        Camera3dBundle {
+            transform: Transform::from_translation(EDITOR_ORIGIN + Vec3::new(0.0, 8.0, 16.0))
+                .looking_at(EDITOR_ORIGIN, Vec3::Y),
+            ..default()
+        },
+        PanOrbitCamera {
+            focus: EDITOR_ORIGIN,
+            button_pan: MouseButton::Left,
+            modifier_pan: Some(KeyCode::ShiftLeft),
+            ..default()
+        },
+        EditorCamera,
+    ));
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 16.0,
+                color: Color::srgb(0.1, 0.1, 0.1),
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            left: Val::Px(8.0),
+            ..default()
+        }),
+        EditorPanel,
+    ));
+
+    editor.spawned = None;
+}
+
+fn exit_editor(
+    mut commands: Commands,
+    editor_query: Query<Entity, Or<(With<EditorCamera>, With<EditorBlock>, With<EditorPanel>)>>,
+    mut window_query: Query<&mut Window>,
+    mut controller_query: Query<&mut FpsController>,
+    mut camera_query: Query<&mut Camera, With<RenderPlayer>>,
+) {
+    for entity in editor_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    // and hand the mouse back to the player
+    for mut window in window_query.iter_mut() {
+        window.cursor.grab_mode = CursorGrabMode::Locked;
+        window.cursor.visible = false;
+    }
+    for mut controller in controller_query.iter_mut() {
+        controller.enable_input = true;
+    }
+    for mut camera in camera_query.iter_mut() {
+        camera.is_active = true;
+    }
+}
+
+fn editor_input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut editor: ResMut<Editor>,
+    markov_collection: Res<MarkovCollection>,
+    mut collections: ResMut<Assets<GlbCollections>>,
+) {
+    let pressed = |key: KeyCode| keyboard_input.just_pressed(key);
+    let shift = keyboard_input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let ctrl = keyboard_input.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+
+    if pressed(KeyCode::Tab) {
+        let mut packs: Vec<&String> = markov_collection.packs.keys().collect();
+        packs.sort();
+        let current = packs.iter().position(|pack| **pack == editor.pack);
+        let next = current.map_or(0, |i| (i + 1) % packs.len());
+        editor.pack = packs[next].to_string();
+        editor.anchor = 0;
+        editor.moving = 0;
+        editor.selected = None;
+    }
+
+    let Some(block_pack) = markov_collection.packs.get(&editor.pack) else {
+        return;
+    };
+    // read through `get`, `get_mut` marks the catalog modified so it's only taken to edit it
+    let handle = block_pack.collections_handle.clone();
+    let Some(collection) = collections.get(&handle) else {
+        return;
+    };
+    let names = block_names(collection);
+    if names.is_empty() {
+        return;
+    }
+
+    // block selection
+    let cycle = |index: usize, forward: bool| {
+        if forward {
+            (index + 1) % names.len()
+        } else {
+            (index + names.len() - 1) % names.len()
+        }
+    };
+    if pressed(KeyCode::KeyZ) || pressed(KeyCode::KeyX) {
+        editor.anchor = cycle(editor.anchor, pressed(KeyCode::KeyX));
+        editor.selected = None;
+    }
+    if pressed(KeyCode::KeyC) || pressed(KeyCode::KeyV) {
+        editor.moving = cycle(editor.moving, pressed(KeyCode::KeyV));
+        editor.selected = None;
+    }
+    editor.anchor %= names.len();
+    editor.moving %= names.len();
+    let anchor_name = names[editor.anchor].clone();
+    let moving_name = names[editor.moving].clone();
+
+    // moving and snapping
+    let step = editor.snap_step();
+    let mut movement = Vec3::ZERO;
+    for (key, direction) in [
+        (KeyCode::ArrowLeft, Vec3::NEG_X),
+        (KeyCode::ArrowRight, Vec3::X),
+        (KeyCode::ArrowUp, Vec3::NEG_Z),
+        (KeyCode::ArrowDown, Vec3::Z),
+        (KeyCode::PageUp, Vec3::Y),
+        (KeyCode::PageDown, Vec3::NEG_Y),
+    ] {
+        if pressed(key) {
+            movement += direction * step;
+        }
+    }
+    if movement != Vec3::ZERO {
+        editor.offset.translation += movement;
+        editor.selected = None;
+    }
+    if pressed(KeyCode::KeyR) {
+        let angle = if shift { -FRAC_PI_2 } else { FRAC_PI_2 };
+        editor.offset.rotate_y(angle);
+        editor.selected = None;
+    }
+    if pressed(KeyCode::KeyG) {
+        editor.snap = (editor.snap + 1) % SNAP_STEPS.len();
+        editor.status = format!("Snap step {}", editor.snap_step());
+    }
+    if pressed(KeyCode::KeyF) {
+        let anchor = &collection.0[&anchor_name];
+        let moving = &collection.0[&moving_name];
+        editor.offset = snap_to_anchor(anchor, moving, editor.offset);
+        editor.selected = None;
+    }
+
+    // browsing existing transitions
+    if pressed(KeyCode::KeyT) {
+        let transforms = &collection.0[&anchor_name].transforms;
+        if transforms.is_empty() {
+            editor.status = format!("{} has no transitions", anchor_name);
+        } else {
+            let next = editor.selected.map_or(0, |i| (i + 1) % transforms.len());
+            let (name, matrix) = &transforms[next];
+            match names.iter().position(|n| n == name) {
+                Some(moving) => editor.moving = moving,
+                None => editor.status = format!("{} is a prefab and can't be previewed", name),
+            }
+            editor.offset = matrix.to_transform();
+            editor.selected = Some(next);
+        }
+    }
+
+    if pressed(KeyCode::Enter) {
+        let Some(collection) = collections.get_mut(&handle) else {
+            return;
+        };
+        // transitions are stored both ways, like the Blender export
+        let matrix = TransformMatrix::from_transform(&editor.offset);
+        let reverse = TransformMatrix(editor.offset.compute_matrix().inverse().to_cols_array_2d());
+        collection
+            .0
+            .get_mut(&anchor_name)
+            .unwrap()
+            .add_transition(&moving_name, matrix);
+        collection
+            .0
+            .get_mut(&moving_name)
+            .unwrap()
+            .add_transition(&anchor_name, reverse);
+        editor.selected = Some(collection.0[&anchor_name].transforms.len() - 1);
+        editor.status = format!("Recorded {} -> {}", anchor_name, moving_name);
+    }
+
+    let Some(collection) = collections.get(&handle) else {
+        return;
+    };
+    let transition_count = collection.0[&anchor_name].transforms.len();
+    if let Some(selected) = editor.selected.filter(|i| *i < transition_count) {
+        if pressed(KeyCode::Delete) || pressed(KeyCode::Backspace) {
+            let (name, matrix) = collection.0[&anchor_name].transforms[selected].clone();
+            let Some(collection) = collections.get_mut(&handle) else {
+                return;
+            };
+            collection
+                .0
+                .get_mut(&anchor_name)
+                .unwrap()
+                .remove_transition(selected);
+
+            // and the same transition seen from the other block
+            let reverse = Transform::from_matrix(matrix.to_transform().compute_matrix().inverse());
+            if let Some(other) = collection.0.get_mut(&name) {
+                let found = other.transforms.iter().position(|(back, m)| {
+                    *back == anchor_name && same_transform(&m.to_transform(), &reverse)
+                });
+                if let Some(index) = found {
+                    other.remove_transition(index);
+                }
+            }
+            editor.selected = None;
+            editor.status = format!("Removed {} -> {}", anchor_name, name);
+        } else if pressed(KeyCode::Equal) || pressed(KeyCode::Minus) {
+            let Some(collection) = collections.get_mut(&handle) else {
+                return;
+            };
+            let data = collection.0.get_mut(&anchor_name).unwrap();
+            let change = if pressed(KeyCode::Equal) {
+                WEIGHT_STEP
+            } else {
+                -WEIGHT_STEP
+            };
+            let weight = (data.transition_weight(selected) + change).max(0.0);
+            data.set_transition_weight(selected, weight);
+            editor.status = format!("Weight {:.2}", weight);
+        }
+    }
+
+    if ctrl && pressed(KeyCode::KeyS) {
+        let Some(collection) = collections.get(&handle) else {
+            return;
+        };
+        let path = format!("assets/{}/data.json", pack_dir(&editor.pack));
+        editor.status = match save_collection(collection, &path) {
+            Ok(()) => format!("Saved {}", path),
+            Err(err) => format!("Could not save {}: {}", path, err),
+        };
+        info!("{}", editor.status);
+    }
+}
+
+/// Moves `offset` along the axis it's furthest out on until the two blocks' boxes touch.
+fn snap_to_anchor(
+    anchor: &CollectionData,
+    moving: &CollectionData,
+    offset: Transform,
+) -> Transform {
+    let anchor_box = Obb::from_aabb(&anchor.aabb, &Transform::IDENTITY);
+    let moving_box = Obb::from_aabb(&moving.aabb, &offset);
+
+    let delta = moving_box.center - anchor_box.center;
+    let axis = if delta.x.abs() >= delta.y.abs() && delta.x.abs() >= delta.z.abs() {
+        Vec3::X
+    } else if delta.y.abs() >= delta.z.abs() {
+        Vec3::Y
+    } else {
+        Vec3::Z
+    };
+
+    let gap = anchor_box.world_half_extents().dot(axis) + moving_box.world_half_extents().dot(axis);
+    let along = delta.dot(axis);
+    let wanted = if along < 0.0 { -gap } else { gap };
+
+    let mut snapped = offset;
+    snapped.translation += axis * (wanted - along);
+    snapped
+}
+
+fn same_transform(a: &Transform, b: &Transform) -> bool {
+    a.translation.distance(b.translation) < 1e-3 && a.rotation.angle_between(b.rotation) < 1e-3
+}
+
+/// Writes a catalog out with its blocks in name order and the same indentation as the Blender export.
+fn save_collection(collection: &GlbCollections, path: &str) -> Result<(), String> {
+    let sorted: BTreeMap<&String, &CollectionData> = collection.0.iter().collect();
+
+    let mut json = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+    sorted
+        .serialize(&mut serializer)
+        .map_err(|err| err.to_string())?;
+
+    fs::write(path, json).map_err(|err| err.to_string())
+}
+
+fn spawn_editor_blocks(
+    mut commands: Commands,
+    mut editor: ResMut<Editor>,
+    block_assets: BlockAssets,
+    mut block_query: Query<(Entity, &mut Transform, &EditorBlockRole)>,
+) {
+    let Some(collection) = block_assets.collection(&editor.pack) else {
+        return;
+    };
+    let names = block_names(collection);
+    if names.is_empty() {
+        return;
+    }
+    let anchor = BlockId::new(&editor.pack, &names[editor.anchor % names.len()]);
+    let moving = BlockId::new(&editor.pack, &names[editor.moving % names.len()]);
+    let moving_transform = Transform::from_translation(EDITOR_ORIGIN) * editor.offset;
+
+    if editor.spawned.as_ref() == Some(&(anchor.clone(), moving.clone())) {
+        for (_, mut transform, role) in block_query.iter_mut() {
+            if *role == EditorBlockRole::Moving {
+                *transform = moving_transform;
+            }
+        }
+        return;
+    }
+
+    for (entity, _, _) in block_query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for (id, role, transform) in [
+        (
+            &anchor,
+            EditorBlockRole::Anchor,
+            Transform::from_translation(EDITOR_ORIGIN),
+        ),
+        (&moving, EditorBlockRole::Moving, moving_transform),
+    ] {
+        let Some(scene) = block_assets
+            .gltf(id)
+            .and_then(|gltf| gltf.scenes.first().cloned())
+        else {
+            continue;
+        };
+        commands.spawn((
+            SceneBundle {
+                scene,
+                transform,
+                ..Default::default()
+            },
+            EditorBlock,
+            role,
+        ));
+    }
+    editor.spawned = Some((anchor, moving));
+}
+
+#[derive(Component, Clone, Copy, PartialEq, Eq)]
+enum EditorBlockRole {
+    Anchor,
+    Moving,
+}
+
+fn draw_editor_gizmos(mut gizmos: Gizmos, editor: Res<Editor>, block_assets: BlockAssets) {
+    let Some((anchor, moving)) = &editor.spawned else {
+        return;
+    };
+    let (Some(anchor_data), Some(moving_data)) =
+        (block_assets.block(anchor), block_assets.block(moving))
+    else {
+        return;
+    };
+
+    let anchor_transform = Transform::from_translation(EDITOR_ORIGIN);
+    let moving_transform = anchor_transform * editor.offset;
+    let anchor_box = Obb::from_aabb(&anchor_data.aabb, &anchor_transform);
+    let moving_box = Obb::from_aabb(&moving_data.aabb, &moving_transform);
+
+    let box_transform = |obb: &Obb| Transform {
+        translation: obb.center,
+        rotation: obb.rotation,
+        scale: obb.half_extents * 2.0,
+    };
+    // touching is fine, overlapping isn't
+    let overlaps = anchor_box.intersects(&moving_box.shrunk(0.05));
+    let moving_color = if overlaps {
+        Color::srgb(0.9, 0.2, 0.2)
+    } else {
+        Color::srgb(0.2, 0.8, 0.3)
+    };
+    gizmos.cuboid(box_transform(&anchor_box), Color::srgb(0.3, 0.3, 0.3));
+    gizmos.cuboid(box_transform(&moving_box), moving_color);
+
+    // the moving block's axes, and where the anchor's existing transitions lead
+    let origin = moving_transform.translation;
+    gizmos.arrow(
+        origin,
+        origin + moving_transform.rotation * Vec3::X,
+        Color::srgb(1.0, 0.0, 0.0),
+    );
+    gizmos.arrow(
+        origin,
+        origin + moving_transform.rotation * Vec3::Y,
+        Color::srgb(0.0, 1.0, 0.0),
+    );
+    gizmos.arrow(
+        origin,
+        origin + moving_transform.rotation * Vec3::Z,
+        Color::srgb(0.0, 0.0, 1.0),
+    );
+    gizmos.line(
+        anchor_transform.translation,
+        origin,
+        Color::srgb(0.5, 0.5, 0.5),
+    );
+
+    for (i, (_, matrix)) in anchor_data.transforms.iter().enumerate() {
+        let color = if editor.selected == Some(i) {
+            Color::srgb(1.0, 0.8, 0.0)
+        } else {
+            Color::srgb(0.4, 0.4, 0.8)
+        };
+        let position = (anchor_transform * matrix.to_transform()).translation;
+        gizmos.sphere(position, Quat::IDENTITY, 0.2, color);
+    }
+}
+
+fn update_editor_panel(
+    editor: Res<Editor>,
+    block_assets: BlockAssets,
+    mut panel_query: Query<&mut Text, With<EditorPanel>>,
+) {
+    let mut lines = vec![format!("pack: {}", editor.pack)];
+    if let Some((anchor, moving)) = &editor.spawned {
+        lines.push(format!("anchor: {}   moving: {}", anchor.name, moving.name));
+        let translation = editor.offset.translation;
+        let (yaw, _, _) = editor.offset.rotation.to_euler(EulerRot::YXZ);
+        lines.push(format!(
+            "offset: ({:.2}, {:.2}, {:.2})  yaw {:.0}°  snap {}",
+            translation.x,
+            translation.y,
+            translation.z,
+            yaw.to_degrees(),
+            editor.snap_step()
+        ));
+
+        if let Some(data) = block_assets.block(anchor) {
+            lines.push(format!("{} transitions:", anchor.name));
+            for (i, (name, _)) in data.transforms.iter().enumerate() {
+                let marker = if editor.selected == Some(i) { ">" } else { " " };
+                lines.push(format!(
+                    "{} {:>3} -> {}  weight {:.2}",
+                    marker,
+                    i,
+                    name,
+                    data.transition_weight(i)
+                ));
+            }
+        }
+    }
+    lines.push(editor.status.clone());
+    lines.push(HELP.to_string());
+
+    let text = lines.join("\n");
+    for mut panel in panel_query.iter_mut() {
+        panel.sections[0].value.clone_from(&text);
+    }
+}
@@ -0,0 +1,407 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::prelude::*;
+use bevy_fps_controller::controller::*;
+use bevy_rapier3d::prelude::*;
+
+use super::BikeModel;
+use crate::loading::AppState;
+use crate::world::checkpoints::ActivatesCheckpoints;
+use crate::world::spawn::SpawnPoint;
+
+const GRAVITY: f32 = 9.81;
+const WHEEL_RADIUS: f32 = 0.35;
+const WHEELBASE: f32 = 1.1;
+// how far the suspension reaches past the wheel's rest position
+const SUSPENSION_LENGTH: f32 = 0.25;
+const CHASSIS_HALF_EXTENTS: Vec3 = Vec3::new(0.12, 0.2, 0.6);
+// the model's origin sits on the ground under the chassis
+const MODEL_OFFSET: Vec3 = Vec3::new(0.0, -0.55, 0.0);
+// rider's eye, and where the player is carried, relative to the chassis
+const EYE: Vec3 = Vec3::new(0.0, 1.1, 0.25);
+const SEAT: Vec3 = Vec3::new(0.0, 0.6, 0.25);
+// steering lock is divided by 1 + speed / this, so fast turns don't throw the bike over
+const STEER_FALLOFF_SPEED: f32 = 6.0;
+// share of a wheel's sideways slip cancelled each step
+const SLIP_CORRECTION: f32 = 0.8;
+const ROLLING_RESISTANCE: f32 = 0.015;
+// how quickly the rider brings the lean round, in radians per second
+const LEAN_FREQUENCY: f32 = 8.0;
+const HOP_COOLDOWN: f32 = 0.5;
+const MOUNT_DISTANCE: f32 = 2.5;
+// the rider steps off to this side of the bike
+const DISMOUNT_OFFSET: Vec3 = Vec3::new(1.0, 0.6, 0.0);
+// share of the lean the camera rolls with
+const CAMERA_LEAN: f32 = 0.5;
+
+#[derive(Resource)]
+pub struct BikeSettings {
+    /// bike and rider together
+    pub mass: f32,
+    /// how far the suspension settles under the bike's weight
+    pub sag: f32,
+    /// fraction of critical damping
+    pub damping: f32,
+    pub pedal_force: f32,
+    /// pedalling stops adding speed past this
+    pub top_speed: f32,
+    /// pedal force and top speed are scaled by this while sprinting
+    pub sprint_factor: f32,
+    pub brake_force: f32,
+    /// tyre friction, the most a wheel can push against the ground per unit of load
+    pub grip: f32,
+    pub max_steer: f32,
+    /// radians per second the handlebars turn
+    pub steer_rate: f32,
+    pub max_lean: f32,
+    pub hop_speed: f32,
+}
+
+impl Default for BikeSettings {
+    fn default() -> Self {
+        BikeSettings {
+            mass: 90.0,
+            sag: 0.1,
+            damping: 0.5,
+            pedal_force: 450.0,
+            top_speed: 11.0,
+            sprint_factor: 1.5,
+            brake_force: 1200.0,
+            grip: 1.1,
+            max_steer: 35f32.to_radians(),
+            steer_rate: 2.5,
+            max_lean: 45f32.to_radians(),
+            hop_speed: 4.5,
+        }
+    }
+}
+
+/// A bicycle: a chassis body held up by a ray cast suspension at each wheel.
+#[derive(Component, Default)]
+pub struct Bike {
+    /// handlebar angle, positive to the left
+    pub steer: f32,
+    /// whether either wheel touched the ground last step
+    pub grounded: bool,
+    hop_cooldown: f32,
+}
+
+/// On the player while they ride a bike. Their own body is switched off and carried on the seat.
+#[derive(Component)]
+pub struct Riding(pub Entity);
+
+fn mount(commands: &mut Commands, player: Entity, bike: Entity) {
+    commands
+        .entity(player)
+        .insert((Riding(bike), RigidBodyDisabled, ColliderDisabled));
+    commands.entity(bike).insert(ActivatesCheckpoints);
+}
+
+fn dismount(commands: &mut Commands, player: Entity, bike: Entity) {
+    commands
+        .entity(player)
+        .remove::<(Riding, RigidBodyDisabled, ColliderDisabled)>();
+    commands.entity(bike).remove::<ActivatesCheckpoints>();
+}
+
+/// Heading of a forward vector, in the same sense as the controller's yaw.
+fn heading(forward: Vec3) -> f32 {
+    (-forward.x).atan2(-forward.z)
+}
+
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(TAU) - PI
+}
+
+/// Lean to the left, from the chassis' right side rising.
+fn lean(transform: &Transform) -> f32 {
+    transform.right().y.clamp(-1.0, 1.0).asin()
+}
+
+/// Puts a bike at the spawn point the first time the game starts and sits the player on it.
+pub fn spawn_bike(
+    mut commands: Commands,
+    settings: Res<BikeSettings>,
+    spawn_point: Res<SpawnPoint>,
+    bike_model: Res<BikeModel>,
+    gltf_assets: Res<Assets<Gltf>>,
+    player_query: Query<(Entity, &FpsControllerInput), With<LogicalPlayer>>,
+    bike_query: Query<(), With<Bike>>,
+) {
+    // back from the editor, the bike is still about
+    if !bike_query.is_empty() {
+        return;
+    }
+
+    let scene = gltf_assets
+        .get(&bike_model.handle)
+        .and_then(|gltf| gltf.scenes.first().cloned())
+        .unwrap_or_default();
+    let yaw = player_query
+        .get_single()
+        .map_or(0.0, |(_, input)| input.yaw);
+    let transform =
+        Transform::from_translation(spawn_point.0).with_rotation(Quat::from_rotation_y(yaw));
+
+    let bike = commands
+        .spawn((
+            SpatialBundle::from_transform(transform),
+            RigidBody::Dynamic,
+            Collider::cuboid(
+                CHASSIS_HALF_EXTENTS.x,
+                CHASSIS_HALF_EXTENTS.y,
+                CHASSIS_HALF_EXTENTS.z,
+            ),
+            ColliderMassProperties::Mass(settings.mass),
+            Friction::coefficient(0.3),
+            Damping {
+                linear_damping: 0.05,
+                angular_damping: 0.5,
+            },
+            Velocity::zero(),
+            ExternalForce::default(),
+            ExternalImpulse::default(),
+            Ccd { enabled: true },
+            Sleeping::disabled(),
+            Bike::default(),
+        ))
+        .with_children(|children| {
+            children.spawn(SceneBundle {
+                scene,
+                transform: Transform::from_translation(MODEL_OFFSET),
+                ..default()
+            });
+        })
+        .id();
+    info!("Bike at {}", spawn_point.0);
+
+    if let Ok((player, _)) = player_query.get_single() {
+        mount(&mut commands, player, bike);
+    }
+}
+
+/// E gets on the nearest bike in reach, or off the one being ridden.
+pub fn toggle_riding(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut player_query: Query<
+        (
+            Entity,
+            &mut Transform,
+            &mut Velocity,
+            &mut FpsControllerInput,
+            Option<&Riding>,
+        ),
+        With<LogicalPlayer>,
+    >,
+    bike_query: Query<(Entity, &Transform, &Velocity), (With<Bike>, Without<LogicalPlayer>)>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::KeyE) {
+        return;
+    }
+    let Ok((player, mut transform, mut velocity, mut input, riding)) =
+        player_query.get_single_mut()
+    else {
+        return;
+    };
+
+    if let Some(Riding(bike)) = riding {
+        if let Ok((_, bike_transform, bike_velocity)) = bike_query.get(*bike) {
+            transform.translation = bike_transform.translation
+                + Quat::from_rotation_y(heading(*bike_transform.forward())) * DISMOUNT_OFFSET;
+            velocity.linvel = bike_velocity.linvel;
+        }
+        dismount(&mut commands, player, *bike);
+        return;
+    }
+
+    let nearest = bike_query
+        .iter()
+        .map(|(bike, bike_transform, _)| {
+            (
+                bike,
+                bike_transform,
+                bike_transform.translation.distance(transform.translation),
+            )
+        })
+        .filter(|(_, _, distance)| *distance <= MOUNT_DISTANCE)
+        .min_by(|a, b| a.2.total_cmp(&b.2));
+    if let Some((bike, bike_transform, _)) = nearest {
+        // face the way the bike points so the handlebars don't swing round
+        input.yaw = heading(*bike_transform.forward());
+        mount(&mut commands, player, bike);
+    }
+}
+
+/// Suspension, tyre grip, pedalling, braking, steering and lean for every bike. Only the bike
+/// being ridden takes input, the rest just roll.
+pub fn drive_bikes(
+    time: Res<Time>,
+    state: Res<State<AppState>>,
+    settings: Res<BikeSettings>,
+    rapier_context: Res<RapierContext>,
+    player_query: Query<
+        (Entity, &FpsController, &FpsControllerInput, Option<&Riding>),
+        With<LogicalPlayer>,
+    >,
+    mut bike_query: Query<(
+        Entity,
+        &Transform,
+        &Velocity,
+        &mut Bike,
+        &mut ExternalForce,
+        &mut ExternalImpulse,
+    )>,
+) {
+    let dt = time.delta_seconds();
+    if dt <= 0.0 {
+        return;
+    }
+    let player = player_query.get_single().ok();
+
+    let reach = SUSPENSION_LENGTH + WHEEL_RADIUS;
+    let stiffness = settings.mass * GRAVITY / (2.0 * settings.sag);
+    let damping = settings.damping * 2.0 * (stiffness * settings.mass / 2.0).sqrt();
+    let roll_inertia =
+        settings.mass * (CHASSIS_HALF_EXTENTS.x.powi(2) + CHASSIS_HALF_EXTENTS.y.powi(2)) / 3.0;
+
+    for (entity, transform, velocity, mut bike, mut force, mut impulse) in &mut bike_query {
+        let input = player
+            .filter(|(_, controller, _, riding)| {
+                controller.enable_input
+                    && *state.get() == AppState::Playing
+                    && riding.is_some_and(|riding| riding.0 == entity)
+            })
+            .map(|(_, _, input, _)| input);
+        let mut filter = QueryFilter::default()
+            .exclude_rigid_body(entity)
+            .exclude_sensors();
+        if let Some((player, ..)) = player {
+            filter = filter.exclude_collider(player);
+        }
+
+        let up = *transform.up();
+        let forward = *transform.forward();
+        let speed = velocity.linvel.dot(forward);
+
+        // steer toward where the rider looks, with less lock the faster the bike goes
+        let max_steer = settings.max_steer / (1.0 + speed.abs() / STEER_FALLOFF_SPEED);
+        let target_steer = input.map_or(0.0, |input| {
+            wrap_angle(input.yaw - heading(forward)).clamp(-max_steer, max_steer)
+        });
+        let steer_step = settings.steer_rate * dt;
+        bike.steer += (target_steer - bike.steer).clamp(-steer_step, steer_step);
+
+        let throttle = input.map_or(0.0, |input| input.movement.z);
+        let sprint = input.is_some_and(|input| input.sprint);
+        let (pedal_force, top_speed) = if sprint {
+            (
+                settings.pedal_force * settings.sprint_factor,
+                settings.top_speed * settings.sprint_factor,
+            )
+        } else {
+            (settings.pedal_force, settings.top_speed)
+        };
+
+        let mut total_force = Vec3::ZERO;
+        let mut total_torque = Vec3::ZERO;
+        let mut contacts = 0;
+        let mut ground_normal = Vec3::ZERO;
+        for (offset, front) in [(-WHEELBASE / 2.0, true), (WHEELBASE / 2.0, false)] {
+            let mount = transform.transform_point(Vec3::new(0.0, 0.0, offset));
+            let Some((_, hit)) =
+                rapier_context.cast_ray_and_get_normal(mount, -up, reach, true, filter)
+            else {
+                continue;
+            };
+            contacts += 1;
+            ground_normal += hit.normal;
+
+            let point_velocity =
+                velocity.linvel + velocity.angvel.cross(mount - transform.translation);
+            let compression = reach - (hit.point - mount).dot(-up);
+            let load = (stiffness * compression + damping * point_velocity.dot(-up)).max(0.0);
+            let grip = settings.grip * load;
+
+            // the wheel rolls along the ground, the front one turned by the handlebars
+            let wheel_forward = if front {
+                Quat::from_axis_angle(up, bike.steer) * forward
+            } else {
+                forward
+            };
+            let along =
+                (wheel_forward - hit.normal * wheel_forward.dot(hit.normal)).normalize_or_zero();
+            let side = along.cross(hit.normal);
+            let rolling = point_velocity.dot(along);
+            let wheel_mass = settings.mass / 2.0;
+
+            let mut push = -rolling.clamp(-1.0, 1.0) * ROLLING_RESISTANCE * load;
+            if !front && throttle > 0.0 && rolling < top_speed {
+                push += throttle * pedal_force;
+            }
+            if throttle < 0.0 {
+                let brake = -throttle * settings.brake_force / 2.0;
+                push -= rolling.signum() * brake.min(rolling.abs() * wheel_mass / dt);
+            }
+            let push = push.clamp(-grip, grip);
+            let slip = point_velocity.dot(side);
+            let hold = (-slip * SLIP_CORRECTION * wheel_mass / dt).clamp(-grip, grip);
+
+            // applied level with the centre of mass so only the rider decides the lean
+            let wheel_force = hit.normal * load + along * push + side * hold;
+            total_force += wheel_force;
+            total_torque += (mount - transform.translation).cross(wheel_force);
+        }
+        bike.grounded = contacts > 0;
+
+        // the rider leans into turns, as far as the speed and steering call for
+        let curvature = bike.steer.tan() / WHEELBASE;
+        let target_lean = (speed * speed * curvature / GRAVITY)
+            .atan()
+            .clamp(-settings.max_lean, settings.max_lean);
+        let lean_rate = -velocity.angvel.dot(forward);
+        let lean_torque = roll_inertia
+            * (LEAN_FREQUENCY.powi(2) * (target_lean - lean(transform))
+                - 2.0 * LEAN_FREQUENCY * lean_rate);
+        total_torque += -forward * lean_torque;
+
+        force.force = total_force;
+        force.torque = total_torque;
+
+        bike.hop_cooldown = (bike.hop_cooldown - dt).max(0.0);
+        if contacts == 2 && bike.hop_cooldown == 0.0 && input.is_some_and(|input| input.jump) {
+            impulse.impulse =
+                ground_normal.normalize_or_zero() * settings.mass * settings.hop_speed;
+            bike.hop_cooldown = HOP_COOLDOWN;
+        }
+    }
+}
+
+/// Carries the player on the seat of the bike they ride and puts the camera at the rider's eye.
+/// Runs after the physics has moved the bike so the camera doesn't trail it by a frame.
+pub fn follow_bike(
+    bike_query: Query<&Transform, (With<Bike>, Without<LogicalPlayer>, Without<RenderPlayer>)>,
+    mut player_query: Query<
+        (&mut Transform, &FpsControllerInput, &Riding),
+        (With<LogicalPlayer>, Without<RenderPlayer>),
+    >,
+    mut camera_query: Query<&mut Transform, (With<RenderPlayer>, Without<LogicalPlayer>)>,
+) {
+    let Ok((mut transform, input, riding)) = player_query.get_single_mut() else {
+        return;
+    };
+    let Ok(bike) = bike_query.get(riding.0) else {
+        return;
+    };
+
+    transform.translation = bike.transform_point(SEAT);
+    for mut camera in &mut camera_query {
+        camera.translation = bike.transform_point(EYE);
+        camera.rotation = Quat::from_euler(
+            EulerRot::YXZ,
+            input.yaw,
+            input.pitch,
+            lean(bike) * CAMERA_LEAN,
+        );
+    }
+}
@@ -1,45 +1,77 @@
-use bevy::{prelude::*, render::camera::Exposure, window::CursorGrabMode};
+pub mod bike;
+pub mod respawn;
+
+use bevy::{
+    prelude::*, render::camera::Exposure, transform::TransformSystem, window::CursorGrabMode,
+};
 use bevy_fps_controller::controller::*;
 use bevy_rapier3d::prelude::*;
 use std::f32::consts::TAU;
 
+use crate::loading::AppState;
+use crate::world::checkpoints::ActivatesCheckpoints;
 use crate::BG_COLOR;
+use bike::{drive_bikes, follow_bike, spawn_bike, toggle_riding, BikeSettings};
+use respawn::{recover_bikes, respawn, spawn_fade_overlay, RespawnFade, RespawnSettings};
 
-const SPAWN_POINT: Vec3 = Vec3::new(15.0, 12.625, 15.0);
+/// Where the player waits while the world generates around it.
+pub const SPAWN_POINT: Vec3 = Vec3::new(15.0, 12.625, 15.0);
+pub const PLAYER_HEIGHT: f32 = 2.0;
+pub const PLAYER_RADIUS: f32 = 0.5;
 
 pub struct FpsPlugin;
 
 impl Plugin for FpsPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(FpsControllerPlugin)
-            .add_systems(Startup, setup)
-            // .add_systems(Startup, load_bike_scene)
+            .init_resource::<BikeSettings>()
+            .init_resource::<RespawnSettings>()
+            .init_resource::<RespawnFade>()
+            .add_systems(Startup, (setup, load_bike_scene, spawn_fade_overlay))
             // .add_systems(Startup, spawn_handle_bars)
-            .add_systems(Update, (manage_cursor, respawn));
+            .add_systems(
+                Update,
+                // the editor has its own camera and keeps the cursor free
+                (
+                    manage_cursor.run_if(not(in_state(AppState::Editing))),
+                    respawn,
+                    recover_bikes,
+                ),
+            )
+            .add_systems(OnEnter(AppState::Playing), spawn_bike)
+            .add_systems(
+                Update,
+                (
+                    toggle_riding.run_if(in_state(AppState::Playing)),
+                    drive_bikes,
+                )
+                    .chain()
+                    .before(respawn)
+                    .before(recover_bikes),
+            )
+            .add_systems(
+                PostUpdate,
+                follow_bike
+                    .after(PhysicsSet::Writeback)
+                    .before(TransformSystem::TransformPropagate)
+                    .run_if(in_state(AppState::Playing)),
+            );
         // .add_systems(Update, update_handle_bars);
     }
 }
 
 #[derive(Resource)]
 pub struct BikeModel {
-    handle: Handle<Gltf>,
-    is_loaded: bool,
+    pub handle: Handle<Gltf>,
 }
 
-// fn load_bike_scene(mut commands: Commands, assets: Res<AssetServer>) {
-//     commands.insert_resource(BikeModel {
-//         handle: assets.load("bike.glb"),
-//         is_loaded: false,
-//     });
-// }
+fn load_bike_scene(mut commands: Commands, assets: Res<AssetServer>) {
+    commands.insert_resource(BikeModel {
+        handle: assets.load("bike.glb"),
+    });
+}
 
-fn setup(
-    mut commands: Commands,
-    mut window: Query<&mut Window>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    assets: Res<AssetServer>,
-) {
+fn setup(mut commands: Commands, mut window: Query<&mut Window>) {
     let mut window = window.single_mut();
     window.title = String::from("Strawberry Jam");
 
@@ -48,14 +80,13 @@ fn setup(
     // The other is a "render" player that is what is displayed to the user
     // This distinction is useful for later on if you want to add multiplayer,
     // where often time these two ideas are not exactly synced up
-    let height = 2.0;
     let logical_entity = commands
         .spawn((
-            Collider::cylinder(height / 2.0, 0.5),
+            Collider::cylinder(PLAYER_HEIGHT / 2.0, PLAYER_RADIUS),
             // A capsule can be used but is NOT recommended
             // If you use it, you have to make sure each segment point is
             // equidistant from the translation of the player transform
-            // Collider::capsule_y(height / 2.0, 0.5),
+            // Collider::capsule_y(PLAYER_HEIGHT / 2.0, PLAYER_RADIUS),
             Friction {
                 coefficient: 0.0,
                 combine_rule: CoefficientCombineRule::Min,
@@ -87,67 +118,30 @@ fn setup(
                 ..default()
             },
         ))
-        .insert(CameraConfig {
-            height_offset: -0.5,
-        })
-        .id();
-
-    commands
-        .spawn((
-            Camera3dBundle {
-                projection: Projection::Perspective(PerspectiveProjection {
-                    fov: TAU / 5.0,
-                    ..default()
-                }),
-                exposure: Exposure::INDOOR,
-                ..default()
+        .insert((
+            CameraConfig {
+                height_offset: -0.5,
             },
-            FogSettings {
-                color: BG_COLOR,
-                falloff: FogFalloff::Exponential { density: 0.005 },
-                ..default()
-            },
-            RenderPlayer { logical_entity },
+            ActivatesCheckpoints,
         ))
-        .with_children(|children| {
-            // spawn bike!
-            let cyclinder = Cylinder::new(0.05, 1.0);
-            let mesh = meshes.add(Mesh::from(cyclinder));
-            let material = StandardMaterial {
-                base_color: Color::srgb(0.5, 0.5, 0.5),
-                ..Default::default()
-            };
-
-            // we want the cycliner to be 'handle bars' so we rotate it 90 degrees around the x-axis and then move it forward a bit
-            let transform = Transform::from_rotation(Quat::from_rotation_z(TAU / 4.0));
-            let transform = transform * Transform::from_translation(Vec3::new(0.00, 0.00, -0.45));
-
-            let scene_handle: Handle<Scene> = assets.load("bike.glb#Scene0");
-
-            // children.spawn(PbrBundle {
-            //     mesh,
-            //     material: materials.add(material),
-            //     transform,
-            //     ..default()
-            // });
-
-            children.spawn(SceneBundle {
-                scene: scene_handle,
-                transform: Transform::from_xyz(0.0, -1.6, -0.8),
-                ..Default::default()
-            });
-        });
-}
-
-fn respawn(mut query: Query<(&mut Transform, &mut Velocity)>) {
-    for (mut transform, mut velocity) in &mut query {
-        if transform.translation.y > -50.0 {
-            continue;
-        }
+        .id();
 
-        velocity.linvel = Vec3::ZERO;
-        transform.translation = SPAWN_POINT;
-    }
+    commands.spawn((
+        Camera3dBundle {
+            projection: Projection::Perspective(PerspectiveProjection {
+                fov: TAU / 5.0,
+                ..default()
+            }),
+            exposure: Exposure::INDOOR,
+            ..default()
+        },
+        FogSettings {
+            color: BG_COLOR,
+            falloff: FogFalloff::Exponential { density: 0.005 },
+            ..default()
+        },
+        RenderPlayer { logical_entity },
+    ));
 }
 
 fn manage_cursor(
@@ -0,0 +1,149 @@
+use bevy::prelude::*;
+use bevy_fps_controller::controller::LogicalPlayer;
+use bevy_rapier3d::prelude::*;
+
+use super::bike::{Bike, Riding};
+use crate::world::checkpoints::ActiveCheckpoint;
+use crate::world::spawn::SpawnPoint;
+
+#[derive(Resource)]
+pub struct RespawnSettings {
+    /// falling below this puts the player and bikes back at the last checkpoint, debris is
+    /// despawned
+    pub kill_height: f32,
+    /// seconds each of the fade out and back in takes
+    pub fade_time: f32,
+}
+
+impl Default for RespawnSettings {
+    fn default() -> Self {
+        RespawnSettings {
+            kill_height: -50.0,
+            fade_time: 0.4,
+        }
+    }
+}
+
+/// Seconds into the fade, the player is moved while the screen is black.
+#[derive(Resource, Default)]
+pub enum RespawnFade {
+    #[default]
+    None,
+    Out(f32),
+    /// the player is held at the target until the screen has cleared
+    In(f32),
+}
+
+#[derive(Component)]
+pub struct FadeOverlay;
+
+pub fn spawn_fade_overlay(mut commands: Commands) {
+    commands.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                ..default()
+            },
+            background_color: Color::NONE.into(),
+            z_index: ZIndex::Global(100),
+            ..default()
+        },
+        FadeOverlay,
+    ));
+}
+
+/// Puts a body down upright at `target`, turned the way it was heading.
+fn put_back(transform: &mut Transform, velocity: &mut Velocity, target: Vec3) {
+    let forward = transform.forward();
+    transform.translation = target;
+    transform.rotation = Quat::from_rotation_y((-forward.x).atan2(-forward.z));
+    *velocity = Velocity::zero();
+}
+
+/// Fades out when the player falls below the kill height, puts them back at the last checkpoint
+/// they passed, or the spawn point if there's none, and fades back in. A bike being ridden is
+/// put back with them.
+pub fn respawn(
+    time: Res<Time>,
+    settings: Res<RespawnSettings>,
+    spawn_point: Res<SpawnPoint>,
+    checkpoint: Res<ActiveCheckpoint>,
+    mut fade: ResMut<RespawnFade>,
+    mut player_query: Query<(&mut Transform, &mut Velocity, Option<&Riding>), With<LogicalPlayer>>,
+    mut bike_query: Query<(&mut Transform, &mut Velocity), (With<Bike>, Without<LogicalPlayer>)>,
+    mut overlay_query: Query<&mut BackgroundColor, With<FadeOverlay>>,
+) {
+    let Ok((mut transform, mut velocity, riding)) = player_query.get_single_mut() else {
+        return;
+    };
+    let target = checkpoint.position.unwrap_or(spawn_point.0);
+    let fallen = transform.translation.y <= settings.kill_height;
+    let mut put_back_all = || {
+        put_back(&mut transform, &mut velocity, target);
+        if let Some(Ok((mut bike_transform, mut bike_velocity))) =
+            riding.map(|riding| bike_query.get_mut(riding.0))
+        {
+            put_back(&mut bike_transform, &mut bike_velocity, target);
+        }
+    };
+    let fade_time = settings.fade_time.max(f32::EPSILON);
+    let delta = time.delta_seconds();
+
+    let alpha = match *fade {
+        RespawnFade::None => {
+            if !fallen {
+                return;
+            }
+            *fade = RespawnFade::Out(0.0);
+            0.0
+        }
+        RespawnFade::Out(elapsed) => {
+            let elapsed = elapsed + delta;
+            if elapsed < fade_time {
+                *fade = RespawnFade::Out(elapsed);
+                elapsed / fade_time
+            } else {
+                *fade = RespawnFade::In(0.0);
+                put_back_all();
+                1.0
+            }
+        }
+        RespawnFade::In(elapsed) => {
+            let elapsed = elapsed + delta;
+            // give the target's island a moment to load back in under the player
+            put_back_all();
+            if elapsed < fade_time {
+                *fade = RespawnFade::In(elapsed);
+                1.0 - elapsed / fade_time
+            } else {
+                *fade = RespawnFade::None;
+                0.0
+            }
+        }
+    };
+
+    for mut color in &mut overlay_query {
+        *color = Color::BLACK.with_alpha(alpha).into();
+    }
+}
+
+/// Puts bikes left to fall off the world back at the last checkpoint, or the spawn point if
+/// there's none. The one being ridden comes back with the player instead.
+pub fn recover_bikes(
+    settings: Res<RespawnSettings>,
+    spawn_point: Res<SpawnPoint>,
+    checkpoint: Res<ActiveCheckpoint>,
+    player_query: Query<&Riding, With<LogicalPlayer>>,
+    mut bike_query: Query<(Entity, &mut Transform, &mut Velocity), With<Bike>>,
+) {
+    let ridden = player_query.get_single().ok().map(|riding| riding.0);
+    let target = checkpoint.position.unwrap_or(spawn_point.0);
+    for (bike, mut transform, mut velocity) in &mut bike_query {
+        if Some(bike) != ridden && transform.translation.y <= settings.kill_height {
+            put_back(&mut transform, &mut velocity, target);
+            info!("Bike fell off the world, put back at {}", target);
+        }
+    }
+}
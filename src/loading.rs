@@ -0,0 +1,220 @@
+use bevy::{
+    asset::{LoadState, RecursiveDependencyLoadState, UntypedAssetId},
+    prelude::*,
+};
+use bevy_rapier3d::plugin::RapierConfiguration;
+
+use crate::fps::BikeModel;
+use crate::world::biome::{BiomeMap, BiomeMapHandle};
+use crate::world::deserialize::{pack_dir, MarkovCollection};
+use crate::world::progress::GenerationProgress;
+use crate::world::shape::ShapeMaskHandles;
+use crate::BG_VALUE;
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
+pub enum AppState {
+    /// waiting for the biome config, catalogs, block GLBs, island masks and the bike to finish loading
+    #[default]
+    Loading,
+    Generating,
+    Playing,
+    /// adjacency authoring, toggled from play with F1
+    Editing,
+}
+
+pub struct LoadingPlugin;
+
+impl Plugin for LoadingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_state::<AppState>()
+            .init_resource::<LoadingProgress>()
+            .add_systems(Startup, (spawn_loading_screen, pause_physics))
+            .add_systems(
+                Update,
+                check_assets_loaded.run_if(in_state(AppState::Loading)),
+            )
+            .add_systems(
+                Update,
+                update_loading_screen
+                    .run_if(in_state(AppState::Loading).or_else(in_state(AppState::Generating))),
+            )
+            .add_systems(
+                OnEnter(AppState::Playing),
+                (despawn_loading_screen, resume_physics),
+            );
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct LoadingProgress {
+    pub loaded: usize,
+    pub total: usize,
+    pub failed: usize,
+}
+
+#[derive(Component)]
+struct LoadingScreen;
+
+#[derive(Component)]
+struct LoadingText;
+
+fn spawn_loading_screen(mut commands: Commands) {
+    commands
+        .spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(100.0),
+                    height: Val::Percent(100.0),
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    ..default()
+                },
+                background_color: Color::srgb(BG_VALUE, BG_VALUE, BG_VALUE).into(),
+                ..default()
+            },
+            LoadingScreen,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                TextBundle::from_section(
+                    "Loading",
+                    TextStyle {
+                        font_size: 32.0,
+                        color: Color::srgb(0.2, 0.2, 0.2),
+                        ..default()
+                    },
+                ),
+                LoadingText,
+            ));
+        });
+}
+
+/// Moves on to generation once the biome config and every asset the world needs has loaded along
+/// with its dependencies. A pack whose catalog fails to load is left out of the world.
+fn check_assets_loaded(
+    asset_server: Res<AssetServer>,
+    mut markov_collection: ResMut<MarkovCollection>,
+    mut biome_map: ResMut<BiomeMap>,
+    biome_handle: Res<BiomeMapHandle>,
+    mask_handles: Res<ShapeMaskHandles>,
+    bike_model: Res<BikeModel>,
+    mut loading_progress: ResMut<LoadingProgress>,
+    mut next_state: ResMut<NextState<AppState>>,
+    mut exit_events: EventWriter<AppExit>,
+) {
+    // which packs to load is only known once biomes.json is in
+    if !biome_handle.applied || markov_collection.packs.is_empty() {
+        return;
+    }
+
+    let mut failed_packs: Vec<String> = Vec::new();
+    for (name, pack) in markov_collection.packs.iter() {
+        if let Some(LoadState::Failed(err)) =
+            asset_server.get_load_state(pack.collections_handle.id())
+        {
+            error!(
+                "Could not load the {} pack from {}: {}",
+                name,
+                pack_dir(name),
+                err
+            );
+            failed_packs.push(name.clone());
+        }
+    }
+    if !failed_packs.is_empty() {
+        markov_collection
+            .packs
+            .retain(|name, _| !failed_packs.contains(name));
+        biome_map.packs.retain(|name| !failed_packs.contains(name));
+        if markov_collection.packs.is_empty() {
+            error!("No block pack could be loaded");
+            exit_events.send(AppExit::error());
+            return;
+        }
+    }
+
+    let mut ids: Vec<UntypedAssetId> = vec![bike_model.handle.id().untyped()];
+    // a pack's GLB handles only exist once its catalog has loaded
+    let mut catalogs_pending = false;
+
+    for pack in markov_collection.packs.values() {
+        ids.push(pack.collections_handle.id().untyped());
+        ids.extend(pack.glb_assets.values().map(|handle| handle.id().untyped()));
+        catalogs_pending |= !pack.glbs_requested;
+    }
+    if let Some(bridges_handle) = &markov_collection.bridges_handle {
+        ids.push(bridges_handle.id().untyped());
+    }
+    ids.extend(
+        mask_handles
+            .0
+            .iter()
+            .map(|(handle, _)| handle.id().untyped()),
+    );
+    // masks are only handed to the generator once every one of them is in
+    let masks_pending = !mask_handles.0.is_empty();
+
+    let states: Vec<RecursiveDependencyLoadState> = ids
+        .iter()
+        .map(|id| asset_server.recursive_dependency_load_state(*id))
+        .collect();
+
+    loading_progress.total = ids.len();
+    loading_progress.loaded = states
+        .iter()
+        .filter(|state| **state == RecursiveDependencyLoadState::Loaded)
+        .count();
+    loading_progress.failed = states
+        .iter()
+        .filter(|state| **state == RecursiveDependencyLoadState::Failed)
+        .count();
+
+    if !catalogs_pending && !masks_pending && loading_progress.loaded == loading_progress.total {
+        info!("Loaded {} assets", loading_progress.total);
+        next_state.set(AppState::Generating);
+    }
+}
+
+fn update_loading_screen(
+    state: Res<State<AppState>>,
+    loading_progress: Res<LoadingProgress>,
+    generation_progress: Res<GenerationProgress>,
+    mut text_query: Query<&mut Text, With<LoadingText>>,
+) {
+    let message = match state.get() {
+        AppState::Loading if loading_progress.failed > 0 => format!(
+            "Failed to load {} of {} assets",
+            loading_progress.failed, loading_progress.total
+        ),
+        AppState::Loading => format!(
+            "Loading assets {}/{}",
+            loading_progress.loaded, loading_progress.total
+        ),
+        _ => format!(
+            "Generating world {:.0}%  ({}/{} islands, {} blocks)",
+            generation_progress.percent,
+            generation_progress.islands_done,
+            generation_progress.islands_total,
+            generation_progress.blocks_placed
+        ),
+    };
+
+    for mut text in text_query.iter_mut() {
+        text.sections[0].value.clone_from(&message);
+    }
+}
+
+/// The ground only exists once the first chunks are generated, so the player is held in place until then.
+fn pause_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = false;
+}
+
+fn resume_physics(mut rapier_config: ResMut<RapierConfiguration>) {
+    rapier_config.physics_pipeline_active = true;
+}
+
+fn despawn_loading_screen(mut commands: Commands, query: Query<Entity, With<LoadingScreen>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
@@ -9,7 +9,10 @@
     // unused_imports
 )]
 
+mod cli;
+mod editor;
 mod fps;
+mod loading;
 mod water;
 mod world;
 
@@ -17,17 +20,25 @@ use std::f32::consts::PI;
 
 use bevy::prelude::*;
 use bevy::{asset::AssetMetaCheck, pbr::CascadeShadowConfigBuilder};
-use bevy_panorbit_camera::{PanOrbitCamera, PanOrbitCameraPlugin};
+use bevy_panorbit_camera::PanOrbitCamera;
 use bevy_rapier3d::prelude::*;
+use editor::EditorPlugin;
 use fps::FpsPlugin;
+use loading::LoadingPlugin;
 use water::WaterPlugin;
-use world::markov::BlockInstanceCollider;
+use world::chunks::SeedOverride;
+use world::diagnostics::GenerationSummary;
+use world::voxels::VoxelExport;
 use world::WorldPlugin;
 
 const BG_COLOR: Color = Color::WHITE;
 const BG_VALUE: f32 = 0.75;
 
 fn main() {
+    if cli::run() {
+        return;
+    }
+
     App::new()
         .add_plugins(DefaultPlugins.set(AssetPlugin {
             // Wasm builds will check for meta files (that don't exist) if this isn't set.
@@ -38,17 +49,21 @@ fn main() {
         }))
         .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
         // .add_plugins(RapierDebugRenderPlugin::default())
+        .add_plugins(LoadingPlugin)
         .add_plugins(FpsPlugin)
         .add_plugins(WorldPlugin)
+        .add_plugins(EditorPlugin)
         // .add_plugins(WaterPlugin)
-        // .add_plugins(PanOrbitCameraPlugin)
+        .insert_resource(SeedOverride(cli::seed()))
+        .insert_resource(GenerationSummary {
+            path: cli::summary_path(),
+        })
+        .insert_resource(VoxelExport {
+            path: cli::voxels_path(),
+        })
         .insert_resource(ClearColor(Color::srgb(BG_VALUE, BG_VALUE, BG_VALUE)))
         .add_systems(Startup, setup_sun)
         // .add_systems(Startup, setup_pan_camera)
-        // .add_systems(
-        //     Update,
-        //     on_enter_turn_fixed_rigid_bodies_into_static_rigid_bodies,
-        // )
         // .add_systems(Update, rotate_sun)
         .run();
 }
@@ -131,28 +146,3 @@ fn spawn_gltf(mut commands: Commands, ass: Res<AssetServer>) {
         ..Default::default()
     });
 }
-
-fn on_enter_turn_fixed_rigid_bodies_into_static_rigid_bodies(
-    mut commands: Commands,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    rigid_body_query: Query<(Entity, &RigidBody), With<BlockInstanceCollider>>,
-) {
-    let rigid_body: Option<RigidBody>;
-
-    if keyboard_input.just_pressed(KeyCode::KeyP) {
-        rigid_body = Some(RigidBody::Dynamic);
-    } else if keyboard_input.just_pressed(KeyCode::KeyO) {
-        rigid_body = Some(RigidBody::Fixed);
-    } else {
-        return;
-    }
-
-    for (entity, body) in rigid_body_query.iter() {
-        if *body != rigid_body.unwrap() {
-            // println!("rigid body is fixed");
-
-            commands.entity(entity).remove::<RigidBody>();
-            commands.entity(entity).insert(rigid_body.unwrap());
-        }
-    }
-}
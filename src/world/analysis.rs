@@ -0,0 +1,554 @@
+use std::fmt;
+
+use bevy::utils::HashMap;
+
+use super::deserialize::{BlockId, BridgeTable, GlbCollections};
+
+/// The catalog viewed as a directed weighted graph. Edge weights are the
+/// probability of the generator picking that transition from the source block.
+/// Blocks are named `pack/block`, bridges join the packs.
+pub struct CatalogGraph {
+    pub names: Vec<String>,
+    pub weights: Vec<Vec<f32>>,
+    /// the plain blocks placed along with each prefab, nested prefabs flattened
+    pub parts: Vec<Vec<usize>>,
+    /// blocks the bridges between islands are laid with, one per pack at most
+    pub connectors: Vec<usize>,
+    /// transitions that point at a block missing from the catalog
+    pub missing: Vec<(String, String)>,
+}
+
+impl CatalogGraph {
+    pub fn new(packs: &[(String, GlbCollections)], bridges: Option<&BridgeTable>) -> Self {
+        let mut names: Vec<String> = packs
+            .iter()
+            .flat_map(|(pack, collections)| {
+                collections
+                    .0
+                    .keys()
+                    .map(|name| BlockId::new(pack, name).to_string())
+            })
+            .collect();
+        names.sort();
+
+        let index: HashMap<String, usize> = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.clone(), i))
+            .collect();
+
+        let mut weights = vec![vec![0.0; names.len()]; names.len()];
+        let mut parts = vec![Vec::new(); names.len()];
+        let mut missing = Vec::new();
+
+        for (pack, collections) in packs {
+            for (name, data) in collections.0.iter() {
+                let id = BlockId::new(pack, name).to_string();
+                let from = index[id.as_str()];
+
+                for part in leaf_parts(collections, name) {
+                    let target = BlockId::new(pack, &part).to_string();
+                    match index.get(target.as_str()) {
+                        Some(&to) => parts[from].push(to),
+                        None => missing.push((id.clone(), target)),
+                    }
+                }
+
+                let mut edges: Vec<(String, f32)> = data
+                    .transforms
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (target, _))| {
+                        (
+                            BlockId::new(pack, target).to_string(),
+                            data.transition_weight(i),
+                        )
+                    })
+                    .collect();
+                // the generator takes bridges with a weight of 1
+                if let Some(bridged) = bridges.and_then(|bridges| bridges.0.get(&id)) {
+                    edges.extend(
+                        bridged
+                            .iter()
+                            .map(|(target, _)| (BlockId::parse(target).to_string(), 1.0)),
+                    );
+                }
+
+                for (target, weight) in edges {
+                    match index.get(target.as_str()) {
+                        Some(&to) => weights[from][to] += weight,
+                        None => missing.push((id.clone(), target)),
+                    }
+                }
+
+                let total: f32 = weights[from].iter().sum();
+                if total > 0.0 {
+                    weights[from].iter_mut().for_each(|w| *w /= total);
+                }
+            }
+        }
+        missing.sort();
+        missing.dedup();
+
+        // bridges out of a pack use its first connector, or the first of another pack's
+        let mut pack_connectors: Vec<(&String, String)> = packs
+            .iter()
+            .filter_map(|(pack, collections)| {
+                let mut names: Vec<&String> = collections
+                    .0
+                    .iter()
+                    .filter(|(_, data)| data.rules.connector && !data.is_prefab())
+                    .map(|(name, _)| name)
+                    .collect();
+                names.sort();
+                Some((pack, BlockId::new(pack, names.first()?).to_string()))
+            })
+            .collect();
+        pack_connectors.sort();
+        let mut connectors: Vec<usize> = packs
+            .iter()
+            .filter_map(|(pack, _)| {
+                let (_, connector) = pack_connectors
+                    .iter()
+                    .find(|(other, _)| *other == pack)
+                    .or_else(|| pack_connectors.first())?;
+                Some(index[connector.as_str()])
+            })
+            .collect();
+        connectors.sort();
+        connectors.dedup();
+
+        CatalogGraph {
+            names,
+            weights,
+            parts,
+            connectors,
+            missing,
+        }
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|n| n == name)
+    }
+
+    fn has_exits(&self, i: usize) -> bool {
+        self.weights[i].iter().any(|w| *w > 0.0)
+    }
+
+    /// Blocks that can never be grown from any of the seeds, or laid in a bridge.
+    pub fn unreachable_from(&self, seeds: &[String]) -> Vec<String> {
+        let mut visited = vec![false; self.names.len()];
+        let mut stack: Vec<usize> = seeds.iter().filter_map(|s| self.index_of(s)).collect();
+        // once there are islands there are bridges between them
+        if !stack.is_empty() {
+            stack.extend(&self.connectors);
+        }
+
+        while let Some(i) = stack.pop() {
+            if visited[i] {
+                continue;
+            }
+            visited[i] = true;
+            // parts are placed with their prefab, but islands don't grow out of them
+            for j in &self.parts[i] {
+                visited[*j] = true;
+            }
+            for (j, w) in self.weights[i].iter().enumerate() {
+                if *w > 0.0 && !visited[j] {
+                    stack.push(j);
+                }
+            }
+        }
+
+        self.names
+            .iter()
+            .zip(visited)
+            .filter(|(_, v)| !v)
+            .map(|(n, _)| n.clone())
+            .collect()
+    }
+
+    /// Blocks with no outgoing transitions at all.
+    pub fn dead_ends(&self) -> Vec<String> {
+        (0..self.names.len())
+            .filter(|i| !self.has_exits(*i))
+            .map(|i| self.names[i].clone())
+            .collect()
+    }
+
+    /// Blocks whose transitions all lead to a single other block, e.g. `E` only leads to `A`.
+    pub fn single_exits(&self) -> Vec<(String, String)> {
+        (0..self.names.len())
+            .filter_map(|i| {
+                let exits: Vec<usize> = (0..self.names.len())
+                    .filter(|j| self.weights[i][*j] > 0.0)
+                    .collect();
+                match exits[..] {
+                    [j] => Some((self.names[i].clone(), self.names[j].clone())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Stationary distribution of the chain found by power iteration. Dead ends
+    /// keep their probability mass, as if they transitioned to themselves.
+    pub fn stationary_distribution(&self) -> Vec<f32> {
+        let n = self.names.len();
+        if n == 0 {
+            return Vec::new();
+        }
+
+        let mut dist = vec![1.0 / n as f32; n];
+        for _ in 0..1000 {
+            let mut next = vec![0.0; n];
+            for (i, p) in dist.iter().enumerate() {
+                if !self.has_exits(i) {
+                    next[i] += p;
+                    continue;
+                }
+                for (j, w) in self.weights[i].iter().enumerate() {
+                    next[j] += p * w;
+                }
+            }
+
+            // average with the previous step so periodic chains still converge
+            let next: Vec<f32> = next.iter().zip(&dist).map(|(a, b)| 0.5 * (a + b)).collect();
+            let delta: f32 = next.iter().zip(&dist).map(|(a, b)| (a - b).abs()).sum();
+            dist = next;
+            if delta < 1e-7 {
+                break;
+            }
+        }
+        dist
+    }
+
+    /// Expected number of each block in an island of `size` blocks grown from a
+    /// seed picked uniformly from `seeds`. Each step picks a random block that has
+    /// exits and follows one of its transitions; overlap rejections are ignored.
+    /// A prefab counts as one block, and adds one of each of its parts.
+    pub fn expected_mix(&self, seeds: &[String], size: usize) -> Vec<f32> {
+        let n = self.names.len();
+        let mut counts = vec![0.0; n];
+
+        let seeds: Vec<usize> = seeds.iter().filter_map(|s| self.index_of(s)).collect();
+        if seeds.is_empty() || size == 0 {
+            return counts;
+        }
+        for i in &seeds {
+            counts[*i] += 1.0 / seeds.len() as f32;
+        }
+
+        for _ in 1..size {
+            let live: f32 = (0..n)
+                .filter(|i| self.has_exits(*i))
+                .map(|i| counts[i])
+                .sum();
+            if live <= 0.0 {
+                break;
+            }
+
+            let mut next = counts.clone();
+            for i in (0..n).filter(|i| self.has_exits(*i)) {
+                for (j, w) in self.weights[i].iter().enumerate() {
+                    next[j] += counts[i] / live * w;
+                }
+            }
+            counts = next;
+        }
+
+        let mut placed = counts.clone();
+        for (i, count) in counts.iter().enumerate() {
+            for j in &self.parts[i] {
+                placed[*j] += count;
+            }
+        }
+        placed
+    }
+
+    /// Graphviz DOT of the catalog, edges labelled with their probability.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph catalog {\n");
+        for name in &self.names {
+            dot += &format!("    {};\n", quoted(name));
+        }
+        for (i, from) in self.names.iter().enumerate() {
+            for (j, to) in self.names.iter().enumerate() {
+                let w = self.weights[i][j];
+                if w > 0.0 {
+                    dot += &format!(
+                        "    {} -> {} [label=\"{:.2}\", penwidth={:.2}];\n",
+                        quoted(from),
+                        quoted(to),
+                        w,
+                        0.5 + 3.0 * w
+                    );
+                }
+            }
+        }
+        for (i, from) in self.names.iter().enumerate() {
+            for j in &self.parts[i] {
+                dot += &format!(
+                    "    {} -> {} [style=dotted, arrowhead=diamond];\n",
+                    quoted(from),
+                    quoted(&self.names[*j])
+                );
+            }
+        }
+        for (from, to) in &self.missing {
+            dot += &format!(
+                "    {} -> {} [style=dashed, color=red];\n",
+                quoted(from),
+                quoted(to)
+            );
+        }
+        dot += "}\n";
+        dot
+    }
+
+    pub fn report(&self, seeds: &[String], island_size: usize) -> CatalogReport {
+        let stationary = self.stationary_distribution();
+        let mix = self.expected_mix(seeds, island_size);
+
+        CatalogReport {
+            seeds: seeds.to_vec(),
+            island_size,
+            unreachable: self.unreachable_from(seeds),
+            connectors: self
+                .connectors
+                .iter()
+                .map(|i| self.names[*i].clone())
+                .collect(),
+            dead_ends: self.dead_ends(),
+            single_exits: self.single_exits(),
+            missing: self.missing.clone(),
+            stationary: self.names.iter().cloned().zip(stationary).collect(),
+            expected_mix: self.names.iter().cloned().zip(mix).collect(),
+        }
+    }
+}
+
+/// A block name as a quoted DOT identifier.
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The plain blocks a catalog entry is made of, nested prefabs flattened. Empty for plain
+/// blocks, and for parts missing from the catalog only their name.
+fn leaf_parts(collections: &GlbCollections, name: &str) -> Vec<String> {
+    let Some(data) = collections.0.get(name) else {
+        return Vec::new();
+    };
+    data.parts
+        .iter()
+        .flat_map(|(part, _)| match collections.0.get(part) {
+            Some(part_data) if part_data.is_prefab() => leaf_parts(collections, part),
+            _ => vec![part.clone()],
+        })
+        .collect()
+}
+
+pub struct CatalogReport {
+    pub seeds: Vec<String>,
+    pub island_size: usize,
+    pub unreachable: Vec<String>,
+    pub connectors: Vec<String>,
+    pub dead_ends: Vec<String>,
+    pub single_exits: Vec<(String, String)>,
+    pub missing: Vec<(String, String)>,
+    pub stationary: Vec<(String, f32)>,
+    pub expected_mix: Vec<(String, f32)>,
+}
+
+impl fmt::Display for CatalogReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seeds: {}", self.seeds.join(", "))?;
+        writeln!(f, "unreachable from seeds: {:?}", self.unreachable)?;
+        if self.connectors.is_empty() {
+            writeln!(f, "no connector blocks, islands are left unlinked")?;
+        } else {
+            writeln!(f, "bridge connectors: {:?}", self.connectors)?;
+        }
+        writeln!(f, "dead ends: {:?}", self.dead_ends)?;
+        for (from, to) in &self.single_exits {
+            writeln!(f, "single exit: {} only leads to {}", from, to)?;
+        }
+        for (from, to) in &self.missing {
+            writeln!(f, "missing block: {} -> {}", from, to)?;
+        }
+
+        writeln!(f, "stationary distribution:")?;
+        for (name, p) in &self.stationary {
+            writeln!(f, "    {:>8} {:>6.2}%", name, p * 100.0)?;
+        }
+
+        writeln!(
+            f,
+            "expected mix for an island of {} blocks:",
+            self.island_size
+        )?;
+        for (name, count) in &self.expected_mix {
+            writeln!(f, "    {:>8} {:>6.2}", name, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::deserialize::DEFAULT_PACK;
+
+    fn catalog(json: &str) -> CatalogGraph {
+        let collections: GlbCollections = serde_json::from_str(json).unwrap();
+        CatalogGraph::new(&[(DEFAULT_PACK.to_string(), collections)], None)
+    }
+
+    fn ids(names: &[&str]) -> Vec<String> {
+        names
+            .iter()
+            .map(|name| BlockId::parse(name).to_string())
+            .collect()
+    }
+
+    fn block(transforms: &[&str]) -> String {
+        let identity = "[[1,0,0,0],[0,1,0,0],[0,0,1,0],[0,0,0,1]]";
+        let transforms: Vec<String> = transforms
+            .iter()
+            .map(|to| format!("[\"{}\", {}]", to, identity))
+            .collect();
+        format!(
+            r#"{{"file": "x.glb", "aabb": {{"center": [0,0,0], "half_extents": [1,1,1]}}, "transforms": [{}]}}"#,
+            transforms.join(", ")
+        )
+    }
+
+    #[test]
+    fn unreachable_from_follows_transitions() {
+        let graph = catalog(&format!(
+            r#"{{"A": {}, "B": {}, "C": {}, "D": {}}}"#,
+            block(&["B"]),
+            block(&["C"]),
+            block(&[]),
+            block(&["A"]),
+        ));
+
+        assert_eq!(graph.unreachable_from(&ids(&["A"])), ids(&["D"]));
+        assert_eq!(graph.unreachable_from(&ids(&["C"])), ids(&["A", "B", "D"]));
+        assert!(graph.unreachable_from(&ids(&["D"])).is_empty());
+        assert_eq!(graph.dead_ends(), ids(&["C"]));
+    }
+
+    #[test]
+    fn missing_targets_are_not_reachable_blocks() {
+        let graph = catalog(&format!(r#"{{"A": {}}}"#, block(&["Z"])));
+
+        assert_eq!(
+            graph.missing,
+            vec![(ids(&["A"])[0].clone(), ids(&["Z"])[0].clone())]
+        );
+        assert!(graph.unreachable_from(&ids(&["A"])).is_empty());
+        assert_eq!(graph.dead_ends(), ids(&["A"]));
+    }
+
+    #[test]
+    fn bridges_join_packs() {
+        let pack = |json: String| serde_json::from_str::<GlbCollections>(&json).unwrap();
+        let packs = [
+            (
+                DEFAULT_PACK.to_string(),
+                pack(format!(r#"{{"A": {}}}"#, block(&[]))),
+            ),
+            (
+                "desert".to_string(),
+                pack(format!(r#"{{"A": {}}}"#, block(&[]))),
+            ),
+        ];
+        let seeds = ids(&["A"]);
+
+        let graph = CatalogGraph::new(&packs, None);
+        assert_eq!(graph.unreachable_from(&seeds), ids(&["desert/A"]));
+
+        let bridges: BridgeTable = serde_json::from_str(
+            r#"{"default/A": [["desert/A", [[1,0,0,0],[0,1,0,0],[0,0,1,0],[0,0,0,1]]]]}"#,
+        )
+        .unwrap();
+        let graph = CatalogGraph::new(&packs, Some(&bridges));
+        assert!(graph.unreachable_from(&seeds).is_empty());
+    }
+
+    #[test]
+    fn prefab_parts_are_reached_with_their_prefab() {
+        let prefab = r#"{"aabb": {"center": [0,0,0], "half_extents": [1,1,1]}, "transforms": [], "parts": [["B", [[1,0,0,0],[0,1,0,0],[0,0,1,0],[0,0,0,1]]]]}"#;
+        let graph = catalog(&format!(
+            r#"{{"A": {}, "P": {}, "B": {}}}"#,
+            block(&["P"]),
+            prefab,
+            block(&[]),
+        ));
+
+        assert!(graph.unreachable_from(&ids(&["A"])).is_empty());
+        let mix = graph.expected_mix(&ids(&["P"]), 1);
+        let count = |name: &str| mix[graph.index_of(&ids(&[name])[0]).unwrap()];
+        assert_eq!(count("P"), 1.0);
+        assert_eq!(count("B"), 1.0);
+    }
+
+    #[test]
+    fn connectors_are_reached_through_bridges() {
+        let connector = block(&[]).replace(
+            r#""transforms""#,
+            r#""rules": {"connector": true}, "transforms""#,
+        );
+        let graph = catalog(&format!(r#"{{"A": {}, "C": {}}}"#, block(&[]), connector,));
+
+        assert_eq!(
+            graph.connectors,
+            vec![graph.index_of(&ids(&["C"])[0]).unwrap()]
+        );
+        assert!(graph.unreachable_from(&ids(&["A"])).is_empty());
+        assert_eq!(graph.unreachable_from(&[]), ids(&["A", "C"]));
+    }
+
+    #[test]
+    fn stationary_distribution_of_two_states() {
+        // A stays or moves to B evenly, B always goes back to A
+        let graph = catalog(&format!(
+            r#"{{"A": {}, "B": {}}}"#,
+            block(&["A", "B"]),
+            block(&["A"]),
+        ));
+
+        let stationary = graph.stationary_distribution();
+        assert!((stationary[0] - 2.0 / 3.0).abs() < 1e-4);
+        assert!((stationary[1] - 1.0 / 3.0).abs() < 1e-4);
+
+        let mix = graph.expected_mix(&ids(&["A"]), 3);
+        assert!((mix[0] - 2.125).abs() < 1e-5);
+        assert!((mix[1] - 0.875).abs() < 1e-5);
+    }
+
+    #[test]
+    fn dead_ends_keep_their_mass() {
+        let graph = catalog(&format!(
+            r#"{{"A": {}, "B": {}}}"#,
+            block(&["B"]),
+            block(&[]),
+        ));
+
+        let stationary = graph.stationary_distribution();
+        assert!(stationary[0] < 1e-4);
+        assert!((stationary[1] - 1.0).abs() < 1e-4);
+
+        // the seed keeps growing into B, B never grows
+        assert_eq!(graph.expected_mix(&ids(&["A"]), 3), vec![1.0, 2.0]);
+        assert_eq!(graph.expected_mix(&ids(&["B"]), 3), vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn dot_escapes_quotes() {
+        let graph = catalog(&format!(r#"{{"say \"hi\"": {}}}"#, block(&[])));
+
+        assert!(graph.to_dot().contains(r#"    "default/say \"hi\"";"#));
+    }
+}
@@ -0,0 +1,389 @@
+use bevy::{
+    gltf::{Gltf, GltfNode},
+    prelude::*,
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        render_asset::RenderAssetUsages,
+        render_resource::PrimitiveTopology,
+    },
+    utils::{HashMap, HashSet},
+};
+use bevy_rapier3d::prelude::*;
+
+use super::lod::{set_block_lod, IslandLod};
+use super::markov::{BlockAssets, Island};
+use super::routes::Connected;
+
+#[derive(Resource)]
+pub struct BakeSettings {
+    /// merge finished islands into a few meshes and one collider
+    pub enabled: bool,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        BakeSettings { enabled: true }
+    }
+}
+
+/// A finished island whose blocks have been merged into one mesh per material. The island
+/// root carries the merged collider, its `BlockInstance`s stay around, hidden and without colliders.
+#[derive(Component)]
+pub struct Baked {
+    pub meshes: Vec<Entity>,
+}
+
+/// Keeps an unbaked island from being baked again, e.g. while it's being edited.
+#[derive(Component)]
+pub struct KeepUnbaked;
+
+/// Splits a baked island back into its blocks.
+#[derive(Event)]
+pub struct UnbakeIsland(pub Entity);
+
+pub fn bake_islands(
+    mut commands: Commands,
+    settings: Res<BakeSettings>,
+    // the block assets hold the mesh assets too, so they can't be borrowed at the same time
+    mut assets: ParamSet<(BlockAssets, ResMut<Assets<Mesh>>)>,
+    island_query: Query<
+        (Entity, &Island, &Transform, Option<&IslandLod>),
+        (With<Connected>, Without<Baked>, Without<KeepUnbaked>),
+    >,
+    children_query: Query<&Children>,
+    collider_query: Query<(), With<Collider>>,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    for (island_entity, island, island_transform, lod) in island_query.iter() {
+        let merged = {
+            let block_assets = assets.p0();
+            let complete = block_assets
+                .collection(&island.pack)
+                .is_some_and(|collection| island.is_complete(collection));
+            if !complete {
+                continue;
+            }
+            let _span = info_span!("bake_island").entered();
+            merge_island(&block_assets, island, island_transform)
+        };
+        let Some((meshes, collider)) = merged else {
+            continue;
+        };
+
+        let full = lod.is_none_or(|lod| *lod == IslandLod::Full);
+        let visibility = if full {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+
+        let mut mesh_entities = Vec::new();
+        for (material, mesh) in meshes {
+            let mesh = assets.p1().add(mesh);
+            let entity = commands
+                .spawn(PbrBundle {
+                    mesh,
+                    material,
+                    visibility,
+                    ..Default::default()
+                })
+                .id();
+            mesh_entities.push(entity);
+        }
+
+        commands
+            .entity(island_entity)
+            .push_children(&mesh_entities)
+            .insert((
+                Baked {
+                    meshes: mesh_entities,
+                },
+                RigidBody::Fixed,
+                collider,
+            ));
+        if !full {
+            commands.entity(island_entity).insert(ColliderDisabled);
+        }
+
+        for block in &island.blocks {
+            set_block_lod(
+                &mut commands,
+                block.entity,
+                false,
+                &children_query,
+                &collider_query,
+            );
+        }
+    }
+}
+
+pub fn unbake_islands(
+    mut commands: Commands,
+    mut events: EventReader<UnbakeIsland>,
+    island_query: Query<(&Island, &Baked, Option<&IslandLod>)>,
+    children_query: Query<&Children>,
+    collider_query: Query<(), With<Collider>>,
+) {
+    for UnbakeIsland(island_entity) in events.read() {
+        let Ok((island, baked, lod)) = island_query.get(*island_entity) else {
+            continue;
+        };
+
+        for mesh in &baked.meshes {
+            commands.entity(*mesh).despawn_recursive();
+        }
+        commands
+            .entity(*island_entity)
+            .remove::<(Baked, Collider, RigidBody, ColliderDisabled)>()
+            .insert(KeepUnbaked);
+
+        // far islands stay hidden behind their proxy
+        if lod.is_none_or(|lod| *lod == IslandLod::Full) {
+            for block in &island.blocks {
+                set_block_lod(
+                    &mut commands,
+                    block.entity,
+                    true,
+                    &children_query,
+                    &collider_query,
+                );
+            }
+        }
+    }
+}
+
+/// Shows or hides a baked island's meshes and enables or disables its collider.
+pub fn set_baked_lod(commands: &mut Commands, island_entity: Entity, baked: &Baked, full: bool) {
+    let visibility = if full {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    for mesh in &baked.meshes {
+        commands.entity(*mesh).insert(visibility);
+    }
+
+    if full {
+        commands.entity(island_entity).remove::<ColliderDisabled>();
+    } else {
+        commands.entity(island_entity).insert(ColliderDisabled);
+    }
+}
+
+/// Merges every mesh on the island into one mesh per material, all in the island's local space,
+/// and gathers the blocks' own trimesh colliders into one compound collider. None if any
+/// block's meshes are missing or there are none.
+fn merge_island(
+    block_assets: &BlockAssets,
+    island: &Island,
+    island_transform: &Transform,
+) -> Option<(Vec<(Handle<StandardMaterial>, Mesh)>, Collider)> {
+    let to_local = island_transform.compute_matrix().inverse();
+    let mut by_material: HashMap<Handle<StandardMaterial>, MeshMerger> = HashMap::new();
+    let mut shapes: Vec<(Vec3, Quat, Collider)> = Vec::new();
+
+    for block in &island.blocks {
+        for (id, xform) in block_assets.leaf_blocks(&block.id, &block.transform) {
+            let gltf = block_assets.gltf(&id)?;
+            let block_matrix = to_local * xform.compute_matrix();
+            let (scale, rotation, translation) = block_matrix.to_scale_rotation_translation();
+
+            for (node, node_matrix) in gltf_mesh_nodes(gltf, &block_assets.gltf_node_assets) {
+                let Some(gltf_mesh) = &node.mesh else {
+                    continue;
+                };
+                let gltf_mesh = block_assets.gltf_mesh_assets.get(gltf_mesh)?;
+                let matrix = block_matrix * node_matrix;
+
+                for primitive in &gltf_mesh.primitives {
+                    let mesh = block_assets.mesh_assets.get(&primitive.mesh)?;
+                    let material = primitive.material.clone().unwrap_or_default();
+                    by_material.entry(material).or_default().push(mesh, matrix);
+                }
+            }
+
+            // the same shapes the block's collider children have, which leave out the node transforms
+            for node in gltf
+                .nodes
+                .iter()
+                .filter_map(|node| block_assets.gltf_node_assets.get(node))
+            {
+                let Some(gltf_mesh) = &node.mesh else {
+                    continue;
+                };
+                let gltf_mesh = block_assets.gltf_mesh_assets.get(gltf_mesh)?;
+                for primitive in &gltf_mesh.primitives {
+                    let mesh = block_assets.mesh_assets.get(&primitive.mesh)?;
+                    if let Some(collider) = scaled_trimesh(mesh, scale) {
+                        shapes.push((translation, rotation, collider));
+                    }
+                }
+            }
+        }
+    }
+
+    if shapes.is_empty() {
+        return None;
+    }
+
+    let meshes = by_material
+        .into_iter()
+        .map(|(material, merger)| (material, merger.build()))
+        .collect();
+    Some((meshes, Collider::compound(shapes)))
+}
+
+/// A mesh's trimesh collider with `scale` baked into its vertices. Compound parts can't be
+/// scaled on their own, and a mirroring scale has its triangles turned back to face outwards.
+fn scaled_trimesh(mesh: &Mesh, scale: Vec3) -> Option<Collider> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        return None;
+    }
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return None;
+    };
+
+    let vertices: Vec<Vec3> = positions
+        .iter()
+        .map(|position| Vec3::from(*position) * scale)
+        .collect();
+    let indices: Vec<u32> = match mesh.indices() {
+        Some(indices) => indices.iter().map(|index| index as u32).collect(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    let mirrored = scale.x * scale.y * scale.z < 0.0;
+    let triangles: Vec<[u32; 3]> = indices
+        .chunks_exact(3)
+        .map(|triangle| {
+            if mirrored {
+                [triangle[0], triangle[2], triangle[1]]
+            } else {
+                [triangle[0], triangle[1], triangle[2]]
+            }
+        })
+        .collect();
+    if triangles.is_empty() {
+        return None;
+    }
+    Some(Collider::trimesh(vertices, triangles))
+}
+
+/// Every node of a GLB's scene along with its transform relative to the scene root.
+pub fn gltf_mesh_nodes<'a>(
+    gltf: &Gltf,
+    gltf_node_assets: &'a Assets<GltfNode>,
+) -> Vec<(&'a GltfNode, Mat4)> {
+    // each node carries its children, so the roots are the nodes no other node lists
+    let children: HashSet<usize> = gltf
+        .nodes
+        .iter()
+        .filter_map(|handle| gltf_node_assets.get(handle))
+        .flat_map(|node| node.children.iter().map(|child| child.index))
+        .collect();
+
+    let mut stack: Vec<(&GltfNode, Mat4)> = gltf
+        .nodes
+        .iter()
+        .filter_map(|handle| gltf_node_assets.get(handle))
+        .filter(|node| !children.contains(&node.index))
+        .map(|node| (node, Mat4::IDENTITY))
+        .collect();
+
+    let mut nodes = Vec::new();
+    while let Some((node, parent_matrix)) = stack.pop() {
+        let matrix = parent_matrix * node.transform.compute_matrix();
+        stack.extend(node.children.iter().map(|child| (child, matrix)));
+        nodes.push((node, matrix));
+    }
+    nodes
+}
+
+#[derive(Default)]
+struct MeshMerger {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    tangents: Vec<[f32; 4]>,
+    /// tangents are only kept if every merged mesh had them
+    missing_tangents: bool,
+    indices: Vec<u32>,
+}
+
+impl MeshMerger {
+    fn push(&mut self, mesh: &Mesh, matrix: Mat4) {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return;
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => Some(normals),
+            _ => None,
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+            _ => None,
+        };
+        let tangents = match mesh.attribute(Mesh::ATTRIBUTE_TANGENT) {
+            Some(VertexAttributeValues::Float32x4(tangents)) => Some(tangents),
+            _ => None,
+        };
+        self.missing_tangents |= tangents.is_none();
+
+        let normal_matrix = Mat3::from_mat4(matrix).inverse().transpose();
+        let base = self.positions.len() as u32;
+        for (i, position) in positions.iter().enumerate() {
+            let position = matrix.transform_point3(Vec3::from(*position));
+            self.positions.push(position.to_array());
+
+            let normal = normals.map_or(Vec3::Y, |normals| Vec3::from(normals[i]));
+            self.normals
+                .push((normal_matrix * normal).normalize_or_zero().to_array());
+            self.uvs.push(uvs.map_or([0.0, 0.0], |uvs| uvs[i]));
+
+            if let Some(tangents) = tangents {
+                let [x, y, z, w] = tangents[i];
+                let tangent = matrix
+                    .transform_vector3(Vec3::new(x, y, z))
+                    .normalize_or_zero();
+                self.tangents.push([tangent.x, tangent.y, tangent.z, w]);
+            }
+        }
+
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(indices) => indices.iter().map(|index| base + index as u32).collect(),
+            None => (base..self.positions.len() as u32).collect(),
+        };
+        // a mirroring matrix turns the triangles inside out, wind them back the other way
+        if matrix.determinant() < 0.0 {
+            for triangle in indices.chunks_exact(3) {
+                self.indices.extend([triangle[0], triangle[2], triangle[1]]);
+            }
+        } else {
+            self.indices.extend(indices);
+        }
+    }
+
+    fn build(self) -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::RENDER_WORLD,
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs)
+        .with_inserted_indices(Indices::U32(self.indices));
+        if !self.missing_tangents {
+            mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, self.tangents);
+        }
+        mesh
+    }
+}
@@ -0,0 +1,110 @@
+use bevy::{asset::LoadState, prelude::*, reflect::TypePath};
+use serde::Deserialize;
+
+use super::deserialize::DEFAULT_PACK;
+use super::noise::fbm;
+
+// relative to the assets folder, loaded like the catalogs so it works on the web too
+const BIOMES_PATH: &str = "biomes.json";
+
+/// Decides which block pack an island is generated from.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BiomeSource {
+    /// every island uses the first pack
+    Single,
+    /// concentric rings around the origin, each `width` wide, cycling through the packs
+    Rings { width: f32 },
+    /// a low frequency noise field, its value range split between the packs
+    Noise { seed: u32, scale: f32 },
+}
+
+/// Read from `assets/biomes.json`, a missing file keeps the single default pack.
+#[derive(Resource, Asset, TypePath, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BiomeMap {
+    /// packs to load, each from `exports/<pack>/data.json`
+    pub packs: Vec<String>,
+    pub source: BiomeSource,
+    /// optional table of transitions between packs, e.g. `exports/bridges.json`
+    pub bridges: Option<String>,
+}
+
+impl Default for BiomeMap {
+    fn default() -> Self {
+        BiomeMap {
+            packs: vec![DEFAULT_PACK.to_string()],
+            source: BiomeSource::Single,
+            bridges: None,
+        }
+    }
+}
+
+impl BiomeMap {
+    pub fn pack_at(&self, position: Vec3) -> &str {
+        if self.packs.is_empty() {
+            return DEFAULT_PACK;
+        }
+        let index = match self.source {
+            BiomeSource::Single => 0,
+            BiomeSource::Rings { width } => {
+                (position.xz().length() / width.max(f32::EPSILON)) as usize % self.packs.len()
+            }
+            BiomeSource::Noise { seed, scale } => {
+                let value = fbm(seed, position.xz() * scale, 3);
+                ((value * self.packs.len() as f32) as usize).min(self.packs.len() - 1)
+            }
+        };
+        &self.packs[index]
+    }
+}
+
+/// The biome config while it loads, the packs to load aren't known until it has.
+#[derive(Resource)]
+pub struct BiomeMapHandle {
+    pub handle: Handle<BiomeMap>,
+    /// set once the config has loaded, or failed to and the default was kept
+    pub applied: bool,
+}
+
+pub fn load_biome_map(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(BiomeMapHandle {
+        handle: asset_server.load(BIOMES_PATH),
+        applied: false,
+    });
+}
+
+/// Takes the biome config over once it has loaded, a missing or empty one keeps the default.
+pub fn apply_biome_map(
+    asset_server: Res<AssetServer>,
+    biome_maps: Res<Assets<BiomeMap>>,
+    mut biome_handle: ResMut<BiomeMapHandle>,
+    mut biome_map: ResMut<BiomeMap>,
+) {
+    if biome_handle.applied {
+        return;
+    }
+
+    if let Some(loaded) = biome_maps.get(&biome_handle.handle) {
+        if loaded.packs.is_empty() {
+            warn!("{} lists no packs, keeping {}", BIOMES_PATH, DEFAULT_PACK);
+        } else {
+            info!("Loaded {} with packs {:?}", BIOMES_PATH, loaded.packs);
+            *biome_map = loaded.clone();
+        }
+        biome_handle.applied = true;
+    } else if let Some(LoadState::Failed(err)) =
+        asset_server.get_load_state(biome_handle.handle.id())
+    {
+        warn!(
+            "Could not load {}, keeping {}: {}",
+            BIOMES_PATH, DEFAULT_PACK, err
+        );
+        biome_handle.applied = true;
+    }
+}
+
+/// Run condition for the frame the biome config was taken over.
+pub fn biome_map_applied(biome_handle: Res<BiomeMapHandle>) -> bool {
+    biome_handle.applied && biome_handle.is_changed()
+}
@@ -0,0 +1,242 @@
+use bevy::{ecs::system::SystemParam, input::mouse::MouseWheel, prelude::*};
+use bevy_fps_controller::controller::{LogicalPlayer, RenderPlayer};
+use bevy_rapier3d::prelude::*;
+
+use super::bake::{Baked, UnbakeIsland};
+use super::biome::BiomeMap;
+use super::checkpoints::spawn_checkpoint;
+use super::chunks::{ChunkSettings, WorldSeed};
+use super::edits::{island_chunk, WorldEdits};
+use super::markov::{check_transition, BlockAssets, BlockInstance, Island};
+use super::spatial::BlockSpatialIndex;
+use super::spawn::standing_spot;
+use super::support::{targeted_block, DestroyBlock};
+
+// checkpoints are cast for from this far over the targeted point
+const CHECKPOINT_CAST: f32 = 0.5;
+
+/// Creative mode: B toggles it, the mouse wheel picks one of the transitions out of the
+/// targeted block, left click places it and right click removes the targeted block. K puts a
+/// checkpoint on the targeted top.
+#[derive(Resource, Default)]
+pub struct BuildMode {
+    pub active: bool,
+    pub choice: usize,
+}
+
+#[derive(Resource)]
+pub struct GhostAssets {
+    cube: Handle<Mesh>,
+    valid: Handle<StandardMaterial>,
+    invalid: Handle<StandardMaterial>,
+}
+
+/// Where placing and removing blocks is recorded: the saved edits and the events for the
+/// blocks' islands.
+#[derive(SystemParam)]
+pub struct BuildEdits<'w> {
+    world_edits: ResMut<'w, WorldEdits>,
+    world_seed: Res<'w, WorldSeed>,
+    chunk_settings: Res<'w, ChunkSettings>,
+    destroy_events: EventWriter<'w, DestroyBlock>,
+    unbake_events: EventWriter<'w, UnbakeIsland>,
+}
+
+/// Preview of the block that would be placed, one box per box of its footprint. The boxes are
+/// kept around and hidden when there's nothing to preview.
+#[derive(Component)]
+pub struct Ghost;
+
+pub fn setup_ghost_assets(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let ghost_material = |color: Color| StandardMaterial {
+        base_color: color,
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..Default::default()
+    };
+
+    commands.insert_resource(GhostAssets {
+        cube: meshes.add(Cuboid::new(1.0, 1.0, 1.0)),
+        valid: materials.add(ghost_material(Color::srgba(0.3, 0.9, 0.4, 0.35))),
+        invalid: materials.add(ghost_material(Color::srgba(0.9, 0.3, 0.3, 0.35))),
+    });
+}
+
+pub fn toggle_build_mode(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut build_mode: ResMut<BuildMode>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyB) {
+        build_mode.active = !build_mode.active;
+        info!(
+            "Build mode {}",
+            if build_mode.active { "on" } else { "off" }
+        );
+    }
+}
+
+pub fn update_build_mode(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mouse_input: Res<ButtonInput<MouseButton>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    mut build_mode: ResMut<BuildMode>,
+    mut edits: BuildEdits,
+    rapier_context: Res<RapierContext>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    biome_map: Res<BiomeMap>,
+    ghost_assets: Res<GhostAssets>,
+    camera_query: Query<&GlobalTransform, With<RenderPlayer>>,
+    player_query: Query<Entity, With<LogicalPlayer>>,
+    block_query: Query<(&BlockInstance, &Transform), Without<Ghost>>,
+    mut island_query: Query<(&mut Island, Has<Baked>)>,
+    mut ghost_query: Query<
+        (
+            &mut Transform,
+            &mut Handle<StandardMaterial>,
+            &mut Visibility,
+        ),
+        With<Ghost>,
+    >,
+) {
+    // shown again below if there's still something to preview
+    for (_, _, mut visibility) in &mut ghost_query {
+        visibility.set_if_neq(Visibility::Hidden);
+    }
+    if !build_mode.active {
+        mouse_wheel.clear();
+        return;
+    }
+
+    for event in mouse_wheel.read() {
+        if event.y > 0.0 {
+            build_mode.choice = build_mode.choice.wrapping_add(1);
+        } else if event.y < 0.0 {
+            build_mode.choice = build_mode.choice.wrapping_sub(1);
+        }
+    }
+
+    let (Ok(camera), Ok(player)) = (camera_query.get_single(), player_query.get_single()) else {
+        return;
+    };
+    let Some((target, point, _)) = targeted_block(&rapier_context, &spatial_index, camera, player)
+    else {
+        return;
+    };
+    let Ok((block, block_transform)) = block_query.get(target) else {
+        return;
+    };
+    let Ok((mut island, baked)) = island_query.get_mut(block.island) else {
+        return;
+    };
+    let coord = island_chunk(&edits.chunk_settings, &island);
+
+    if keyboard_input.just_pressed(KeyCode::KeyK) {
+        let filter = QueryFilter::default()
+            .exclude_rigid_body(player)
+            .exclude_sensors();
+        let spot = standing_spot(
+            &rapier_context,
+            &spatial_index,
+            filter,
+            point.xz(),
+            point.y + CHECKPOINT_CAST,
+        );
+        match spot {
+            Some(position) => {
+                spawn_checkpoint(&mut commands, block.island, position);
+                edits.world_edits.record_checkpoint(coord, position);
+                edits.world_edits.save(edits.world_seed.0);
+                info!("Placed a checkpoint at {}", position);
+            }
+            None => info!("No room for a checkpoint at {}", point),
+        }
+        return;
+    }
+
+    if mouse_input.just_pressed(MouseButton::Right) {
+        edits
+            .world_edits
+            .record_removed(coord, &block.id(), block_transform);
+        edits.world_edits.save(edits.world_seed.0);
+        edits.destroy_events.send(DestroyBlock(target));
+        return;
+    }
+
+    // the same transitions the generator grows islands with, under the same rules
+    let parent_id = block.id();
+    let transitions = block_assets.transitions(&parent_id);
+    if transitions.is_empty() {
+        return;
+    }
+    let transition = &transitions[build_mode.choice % transitions.len()];
+    let candidate = check_transition(
+        &spatial_index,
+        &biome_map,
+        &block_assets,
+        &island,
+        &parent_id,
+        block_transform,
+        transition,
+    );
+
+    let material = if candidate.rejected.is_none() {
+        ghost_assets.valid.clone()
+    } else {
+        ghost_assets.invalid.clone()
+    };
+    let mut ghosts = ghost_query.iter_mut();
+    for obb in &candidate.footprint {
+        let transform = Transform {
+            translation: obb.center,
+            rotation: obb.rotation,
+            scale: obb.half_extents * 2.0 + Vec3::splat(0.02),
+        };
+        match ghosts.next() {
+            Some((mut ghost_transform, mut ghost_material, mut visibility)) => {
+                *ghost_transform = transform;
+                *ghost_material = material.clone();
+                *visibility = Visibility::Inherited;
+            }
+            None => {
+                commands.spawn((
+                    PbrBundle {
+                        mesh: ghost_assets.cube.clone(),
+                        material: material.clone(),
+                        transform,
+                        ..Default::default()
+                    },
+                    Ghost,
+                ));
+            }
+        }
+    }
+
+    if !mouse_input.just_pressed(MouseButton::Left) {
+        return;
+    }
+    if let Some(reason) = candidate.rejected {
+        info!("Can't place {}: {}", candidate.to, reason.name());
+        return;
+    }
+    if baked {
+        edits.unbake_events.send(UnbakeIsland(block.island));
+    }
+    let (id, xform) = (&candidate.to, candidate.transform);
+    let entity =
+        block_assets.spawn_block(&mut commands, &mut spatial_index, id, xform, block.island);
+    spatial_index.set_parent(entity, target);
+    island.add_block(id, entity, xform);
+    island.storeys = island
+        .storeys
+        .max(island.vertical.storey(&candidate.footprint) + 1);
+
+    edits.world_edits.record_placed(coord, id, &xform);
+    edits.world_edits.save(edits.world_seed.0);
+    info!("Placed {} at {}", id, xform.translation);
+}
@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+use bevy_fps_controller::controller::LogicalPlayer;
+use bevy_rapier3d::prelude::*;
+
+use super::markov::Island;
+use super::routes::Connected;
+use super::spatial::BlockSpatialIndex;
+use super::spawn::island_spawn;
+use crate::fps::{PLAYER_HEIGHT, PLAYER_RADIUS};
+
+// the trigger reaches this far around the spot, and this far above the player's head
+const TRIGGER_RADIUS: f32 = 1.5;
+const TRIGGER_HEADROOM: f32 = 1.0;
+
+/// A trigger volume that becomes the respawn target when the player passes through it.
+#[derive(Component)]
+pub struct Checkpoint {
+    /// despawned along with this island
+    pub island: Entity,
+    /// where the player is put back, the centre of their collider
+    pub position: Vec3,
+}
+
+/// Whatever carries the player through checkpoints: the player, or the bike they're riding.
+#[derive(Component)]
+pub struct ActivatesCheckpoints;
+
+/// Marks an island the generator has tried to put a checkpoint on.
+#[derive(Component)]
+pub struct CheckpointPlaced;
+
+/// The last checkpoint the player passed through.
+#[derive(Resource, Default)]
+pub struct ActiveCheckpoint {
+    pub entity: Option<Entity>,
+    /// kept after the checkpoint unloads, its island regenerates when the player is put back
+    pub position: Option<Vec3>,
+}
+
+pub fn spawn_checkpoint(commands: &mut Commands, island: Entity, position: Vec3) -> Entity {
+    commands
+        .spawn((
+            TransformBundle::from_transform(Transform::from_translation(position)),
+            Collider::cylinder(PLAYER_HEIGHT / 2.0 + TRIGGER_HEADROOM, TRIGGER_RADIUS),
+            Sensor,
+            ActiveEvents::COLLISION_EVENTS,
+            Checkpoint { island, position },
+        ))
+        .id()
+}
+
+/// Puts a checkpoint on each island once it has been linked up. Runs ahead of the linking so
+/// the bridges built last frame are in the physics scene before the spot is cast for.
+pub fn place_checkpoints(
+    mut commands: Commands,
+    rapier_context: Res<RapierContext>,
+    spatial_index: Res<BlockSpatialIndex>,
+    player_query: Query<Entity, With<LogicalPlayer>>,
+    island_query: Query<(Entity, &Island), (With<Connected>, Without<CheckpointPlaced>)>,
+) {
+    let mut filter = QueryFilter::default().exclude_sensors();
+    if let Ok(player) = player_query.get_single() {
+        filter = filter.exclude_rigid_body(player);
+    }
+
+    for (island_entity, island) in island_query.iter() {
+        match island_spawn(&rapier_context, &spatial_index, filter, island) {
+            Some(position) => {
+                spawn_checkpoint(&mut commands, island_entity, position);
+            }
+            None => debug!("No room for a checkpoint on island {}", island_entity),
+        }
+        commands.entity(island_entity).insert(CheckpointPlaced);
+    }
+}
+
+/// Checkpoints go when their island unloads.
+pub fn despawn_orphan_checkpoints(
+    mut commands: Commands,
+    checkpoint_query: Query<(Entity, &Checkpoint)>,
+    island_query: Query<(), With<Island>>,
+) {
+    for (entity, checkpoint) in checkpoint_query.iter() {
+        if !island_query.contains(checkpoint.island) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+pub fn activate_checkpoints(
+    mut collision_events: EventReader<CollisionEvent>,
+    mut active: ResMut<ActiveCheckpoint>,
+    checkpoint_query: Query<&Checkpoint>,
+    activator_query: Query<(), With<ActivatesCheckpoints>>,
+) {
+    for event in collision_events.read() {
+        let CollisionEvent::Started(a, b, _) = event else {
+            continue;
+        };
+        for (checkpoint_entity, other) in [(*a, *b), (*b, *a)] {
+            let Ok(checkpoint) = checkpoint_query.get(checkpoint_entity) else {
+                continue;
+            };
+            if !activator_query.contains(other) || active.entity == Some(checkpoint_entity) {
+                continue;
+            }
+            info!("Checkpoint at {}", checkpoint.position);
+            active.entity = Some(checkpoint_entity);
+            active.position = Some(checkpoint.position);
+        }
+    }
+}
+
+pub fn draw_checkpoints(
+    mut gizmos: Gizmos,
+    active: Res<ActiveCheckpoint>,
+    checkpoint_query: Query<(Entity, &Checkpoint)>,
+) {
+    for (entity, checkpoint) in checkpoint_query.iter() {
+        let color = if active.entity == Some(entity) {
+            Color::srgb(0.2, 0.9, 0.4)
+        } else {
+            Color::srgb(0.9, 0.8, 0.2)
+        };
+        let feet = checkpoint.position - Vec3::Y * PLAYER_HEIGHT / 2.0;
+        gizmos.circle(feet, Dir3::Y, TRIGGER_RADIUS, color);
+        gizmos.circle(feet, Dir3::Y, PLAYER_RADIUS, color);
+    }
+}
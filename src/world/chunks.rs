@@ -0,0 +1,310 @@
+use std::ops::Range;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_fps_controller::controller::LogicalPlayer;
+use bevy_rapier3d::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use super::biome::BiomeMap;
+use super::markov::{spawn_island, BlockAssets, Island};
+use super::progress::{GenerationBudget, GenerationProgress};
+use super::reachability::MovementLimits;
+use super::shape::IslandShapes;
+use super::spatial::BlockSpatialIndex;
+use super::vertical::VerticalSettings;
+
+// mixed into an island's seed for the stream its shape and height limits are picked from
+const LAYOUT_SEED: u64 = 0x5348_4150_4553;
+
+/// Seed the whole world is generated from, every chunk derives its own seed from it.
+#[derive(Resource)]
+pub struct WorldSeed(pub u64);
+
+impl Default for WorldSeed {
+    fn default() -> Self {
+        WorldSeed(rand::random())
+    }
+}
+
+/// Seed given with `--seed <u64>`, so a world can be generated again. It wins over the seed of a
+/// saved world.
+#[derive(Resource, Default)]
+pub struct SeedOverride(pub Option<u64>);
+
+#[derive(Resource)]
+pub struct ChunkSettings {
+    pub chunk_size: f32,
+    /// chunks within this many chunks of the player are generated
+    pub load_radius: i32,
+    /// chunks further than this are despawned, larger than `load_radius` so chunks don't flicker
+    pub unload_radius: i32,
+    /// each chunk is split into cells x cells island plots, islands never grow out of their plot
+    pub cells: u32,
+    pub island_chance: f64,
+    pub island_size: Range<usize>,
+}
+
+impl Default for ChunkSettings {
+    fn default() -> Self {
+        ChunkSettings {
+            chunk_size: 128.0,
+            load_radius: 2,
+            unload_radius: 3,
+            cells: 2,
+            island_chance: 0.6,
+            island_size: 20..80,
+        }
+    }
+}
+
+impl ChunkSettings {
+    pub fn chunk_coord(&self, position: Vec3) -> IVec2 {
+        (position.xz() / self.chunk_size).floor().as_ivec2()
+    }
+
+    pub fn chunk_origin(&self, coord: IVec2) -> Vec3 {
+        let corner = coord.as_vec2() * self.chunk_size;
+        Vec3::new(corner.x, 0.0, corner.y)
+    }
+}
+
+#[derive(Component)]
+pub struct Chunk {
+    pub coord: IVec2,
+    pub islands: Vec<Entity>,
+}
+
+#[derive(Resource, Default)]
+pub struct LoadedChunks(pub HashMap<IVec2, Entity>);
+
+#[derive(Resource)]
+pub struct GroundAssets {
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<StandardMaterial>,
+}
+
+/// Mixes the world seed with a chunk coordinate, so a chunk always generates the same way.
+pub fn chunk_seed(world_seed: u64, coord: IVec2) -> u64 {
+    let mut h = world_seed ^ 0x9e37_79b9_7f4a_7c15;
+    for v in [coord.x as u32 as u64, coord.y as u32 as u64] {
+        h ^= v
+            .wrapping_add(0x9e37_79b9_7f4a_7c15)
+            .wrapping_add(h << 6)
+            .wrapping_add(h >> 2);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+    h
+}
+
+pub fn setup_ground(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    settings: Res<ChunkSettings>,
+    world_seed: Res<WorldSeed>,
+) {
+    info!("World seed: {}", world_seed.0);
+
+    let half_size = settings.chunk_size / 2.0;
+    let plane = Plane3d::new(Vec3::new(0., 1.0, 0.), Vec2::new(half_size, half_size));
+    let material = StandardMaterial {
+        base_color: Color::srgb(0.5, 0.5, 0.5),
+        ..Default::default()
+    };
+
+    commands.insert_resource(GroundAssets {
+        mesh: meshes.add(plane),
+        material: materials.add(material),
+    });
+}
+
+/// Generates chunks as the player approaches them and despawns the ones left behind.
+pub fn update_chunks(
+    mut commands: Commands,
+    mut loaded_chunks: ResMut<LoadedChunks>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    mut progress: ResMut<GenerationProgress>,
+    settings: Res<ChunkSettings>,
+    budget: Res<GenerationBudget>,
+    world_seed: Res<WorldSeed>,
+    biome_map: Res<BiomeMap>,
+    island_shapes: Res<IslandShapes>,
+    vertical_settings: Res<VerticalSettings>,
+    movement_limits: Option<Res<MovementLimits>>,
+    ground_assets: Res<GroundAssets>,
+    block_assets: BlockAssets,
+    player_query: Query<&Transform, With<LogicalPlayer>>,
+    chunk_query: Query<&Chunk>,
+    island_query: Query<&Island>,
+) {
+    let _span = info_span!("update_chunks").entered();
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+    let center = settings.chunk_coord(player_transform.translation);
+
+    // unload chunks that have fallen behind, along with their islands and colliders
+    let distance = |coord: IVec2| (coord - center).abs().max_element();
+    let far: Vec<IVec2> = loaded_chunks
+        .0
+        .keys()
+        .copied()
+        .filter(|coord| distance(*coord) > settings.unload_radius)
+        .collect();
+    for coord in far {
+        let chunk_entity = loaded_chunks.0.remove(&coord).unwrap();
+        if let Ok(chunk) = chunk_query.get(chunk_entity) {
+            for island_entity in &chunk.islands {
+                if let Ok(island) = island_query.get(*island_entity) {
+                    for block in &island.blocks {
+                        spatial_index.remove(block.entity);
+                        commands.entity(block.entity).despawn_recursive();
+                    }
+                }
+                commands.entity(*island_entity).despawn_recursive();
+            }
+        }
+        commands.entity(chunk_entity).despawn_recursive();
+    }
+
+    // nearest chunks first so the ground under the player is there soonest
+    let radius = settings.load_radius;
+    let mut missing: Vec<IVec2> = (-radius..=radius)
+        .flat_map(|x| (-radius..=radius).map(move |z| center + IVec2::new(x, z)))
+        .filter(|coord| !loaded_chunks.0.contains_key(coord))
+        .collect();
+    missing.sort_by_key(|coord| (*coord - center).length_squared());
+
+    progress.chunks_pending = missing.len();
+    for coord in missing.into_iter().take(budget.chunks_per_frame) {
+        let chunk_entity = spawn_chunk(
+            &mut commands,
+            &mut spatial_index,
+            &settings,
+            &world_seed,
+            &biome_map,
+            &island_shapes,
+            &vertical_settings,
+            movement_limits.as_deref(),
+            &ground_assets,
+            &block_assets,
+            coord,
+        );
+        loaded_chunks.0.insert(coord, chunk_entity);
+        progress.chunks_pending -= 1;
+    }
+}
+
+fn spawn_chunk(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    settings: &ChunkSettings,
+    world_seed: &WorldSeed,
+    biome_map: &BiomeMap,
+    island_shapes: &IslandShapes,
+    vertical_settings: &VerticalSettings,
+    movement_limits: Option<&MovementLimits>,
+    ground_assets: &GroundAssets,
+    block_assets: &BlockAssets,
+    coord: IVec2,
+) -> Entity {
+    let _span = info_span!("spawn_chunk", x = coord.x, z = coord.y).entered();
+    let mut rng = StdRng::seed_from_u64(chunk_seed(world_seed.0, coord));
+
+    let origin = settings.chunk_origin(coord);
+    let half_size = settings.chunk_size / 2.0;
+    let ground_transform =
+        Transform::from_translation(origin + Vec3::new(half_size, -0.1, half_size));
+
+    let chunk_entity = commands
+        .spawn((
+            PbrBundle {
+                mesh: ground_assets.mesh.clone(),
+                material: ground_assets.material.clone(),
+                transform: ground_transform,
+                ..Default::default()
+            },
+            Collider::cuboid(half_size, 0.1, half_size),
+            RigidBody::Fixed,
+        ))
+        .id();
+
+    let mut islands = Vec::new();
+    let cell_size = settings.chunk_size / settings.cells as f32;
+    for cx in 0..settings.cells {
+        for cz in 0..settings.cells {
+            // always draw the same numbers per cell, so a cell's island doesn't depend on its neighbours
+            let has_island = rng.gen_bool(settings.island_chance);
+            let jitter = Vec2::new(rng.gen_range(-0.25..0.25), rng.gen_range(-0.25..0.25));
+            let island_size = rng.gen_range(settings.island_size.clone());
+            let island_seed: u64 = rng.gen();
+            if !has_island {
+                continue;
+            }
+
+            let cell_min = origin.xz() + Vec2::new(cx as f32, cz as f32) * cell_size;
+            let cell_center = cell_min + Vec2::splat(cell_size / 2.0) + jitter * cell_size;
+            let bounds = Rect::from_corners(cell_min, cell_min + Vec2::splat(cell_size));
+
+            // the island's layout draws from its own stream, so the cell's numbers above stay the same
+            let mut layout_rng = StdRng::seed_from_u64(island_seed ^ LAYOUT_SEED);
+            let shape = island_shapes.pick(&mut layout_rng);
+            let seed_position = shape.seed_position(&bounds, cell_center, &mut layout_rng);
+            let vertical = vertical_settings.rules_for(&mut layout_rng, movement_limits);
+            let xform = Transform::from_xyz(seed_position.x, 0.0, seed_position.y);
+
+            if let Some(island) = spawn_island(
+                commands,
+                spatial_index,
+                biome_map,
+                block_assets,
+                StdRng::seed_from_u64(island_seed),
+                xform,
+                island_size,
+                bounds,
+                shape,
+                vertical,
+            ) {
+                islands.push(island);
+            }
+        }
+    }
+
+    commands
+        .entity(chunk_entity)
+        .insert(Chunk { coord, islands });
+    chunk_entity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunk_seed_is_deterministic() {
+        let coord = IVec2::new(3, -7);
+        assert_eq!(chunk_seed(42, coord), chunk_seed(42, coord));
+        // pinned so a change to the mixing, which would change every saved world, is noticed
+        assert_eq!(chunk_seed(42, coord), 0x32fe_8b75_c29c_7816);
+    }
+
+    #[test]
+    fn chunk_seed_differs_between_chunks_and_worlds() {
+        let seeds: Vec<u64> = [(0, 0), (1, 0), (0, 1), (-1, 0), (0, -1), (1, 1)]
+            .into_iter()
+            .map(|(x, y)| chunk_seed(42, IVec2::new(x, y)))
+            .collect();
+        for (i, a) in seeds.iter().enumerate() {
+            for b in &seeds[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        assert_ne!(chunk_seed(42, IVec2::ZERO), chunk_seed(43, IVec2::ZERO));
+        assert_ne!(
+            chunk_seed(42, IVec2::new(2, 5)),
+            chunk_seed(42, IVec2::new(5, 2))
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::f32::consts::FRAC_PI_2;
+
+use bevy::prelude::*;
+use bevy_fps_controller::controller::RenderPlayer;
+
+use super::biome::BiomeMap;
+use super::markov::{step_island, BlockAssets, GrowthStep, Island};
+use super::obb::Obb;
+use super::progress::GenerationProgress;
+use super::spatial::BlockSpatialIndex;
+
+const HELP: &str = "N step  hold Shift+N to keep stepping  F2 close";
+
+/// Pauses generation so it can be advanced one placement at a time. F2 toggles it, N steps
+/// the island closest to the player and the last attempt is drawn over the world.
+#[derive(Resource, Default)]
+pub struct GenerationDebugger {
+    pub active: bool,
+    /// the island being stepped, kept until it has finished growing
+    pub island: Option<Entity>,
+    pub last_step: Option<GrowthStep>,
+    /// whether the last step placed a block
+    pub placed: bool,
+}
+
+#[derive(Component)]
+pub struct DebuggerPanel;
+
+/// Run condition keeping the normal generation out of the way while stepping.
+pub fn generation_debugger_inactive(debugger: Res<GenerationDebugger>) -> bool {
+    !debugger.active
+}
+
+pub fn toggle_generation_debugger(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut debugger: ResMut<GenerationDebugger>,
+    panel_query: Query<Entity, With<DebuggerPanel>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F2) {
+        return;
+    }
+    debugger.active = !debugger.active;
+    info!(
+        "Generation debugger {}",
+        if debugger.active { "on" } else { "off" }
+    );
+
+    if debugger.active {
+        commands.spawn((
+            TextBundle::from_section(
+                HELP,
+                TextStyle {
+                    font_size: 16.0,
+                    color: Color::srgb(0.1, 0.1, 0.1),
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(8.0),
+                right: Val::Px(8.0),
+                ..default()
+            }),
+            DebuggerPanel,
+        ));
+    } else {
+        for entity in panel_query.iter() {
+            commands.entity(entity).despawn_recursive();
+        }
+        debugger.island = None;
+        debugger.last_step = None;
+    }
+}
+
+pub fn step_generation(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut debugger: ResMut<GenerationDebugger>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    mut progress: ResMut<GenerationProgress>,
+    biome_map: Res<BiomeMap>,
+    block_assets: BlockAssets,
+    camera_query: Query<&GlobalTransform, With<RenderPlayer>>,
+    mut island_query: Query<(Entity, &mut Island, &Transform)>,
+) {
+    let shift = keyboard_input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let stepping = keyboard_input.just_pressed(KeyCode::KeyN)
+        || (shift && keyboard_input.pressed(KeyCode::KeyN));
+    if !debugger.active || !stepping {
+        return;
+    }
+
+    let growing = |island: &Island| {
+        block_assets
+            .collection(&island.pack)
+            .is_some_and(|collection| !island.is_complete(collection))
+    };
+
+    // stay on the same island until it's done, then move on to the closest one still growing
+    let current = debugger.island.filter(|entity| {
+        island_query
+            .get(*entity)
+            .is_ok_and(|(_, island, _)| growing(island))
+    });
+    let target = current.or_else(|| {
+        let player = camera_query
+            .get_single()
+            .map_or(Vec3::ZERO, |camera| camera.translation());
+        island_query
+            .iter()
+            .filter(|(_, island, _)| growing(island))
+            .min_by(|(_, _, a), (_, _, b)| {
+                let distance = |transform: &Transform| transform.translation.distance(player);
+                distance(a).total_cmp(&distance(b))
+            })
+            .map(|(entity, _, _)| entity)
+    });
+    let Some(target) = target else {
+        debugger.last_step = None;
+        return;
+    };
+    let Ok((island_entity, mut island, _)) = island_query.get_mut(target) else {
+        return;
+    };
+
+    let placed_before = island.blocks.len();
+    let mut step = GrowthStep::default();
+    step_island(
+        &mut commands,
+        &mut spatial_index,
+        &mut progress,
+        &biome_map,
+        &block_assets,
+        island_entity,
+        &mut island,
+        Some(&mut step),
+    );
+
+    debugger.placed = island.blocks.len() > placed_before;
+    debugger.island = Some(island_entity);
+    debugger.last_step = Some(step);
+}
+
+pub fn draw_generation_debugger(
+    mut gizmos: Gizmos,
+    debugger: Res<GenerationDebugger>,
+    block_assets: BlockAssets,
+    island_query: Query<&Island>,
+) {
+    if !debugger.active {
+        return;
+    }
+    let Some(island) = debugger
+        .island
+        .and_then(|entity| island_query.get(entity).ok())
+    else {
+        return;
+    };
+    let Some(step) = &debugger.last_step else {
+        return;
+    };
+
+    let box_transform = |obb: &Obb| Transform {
+        translation: obb.center,
+        rotation: obb.rotation,
+        scale: obb.half_extents * 2.0,
+    };
+
+    // the island's bounds, at the height of its seed
+    let height = island
+        .blocks
+        .first()
+        .map_or(0.0, |seed| seed.transform.translation.y);
+    let center = island.bounds.center();
+    gizmos.rect(
+        Vec3::new(center.x, height, center.y),
+        Quat::from_rotation_x(FRAC_PI_2),
+        island.bounds.size(),
+        Color::srgb(0.9, 0.8, 0.1),
+    );
+
+    let Some((parent_id, parent_transform)) = &step.parent else {
+        return;
+    };
+    for obb in block_assets.footprint(parent_id, parent_transform) {
+        gizmos.cuboid(box_transform(&obb), Color::srgb(0.2, 0.4, 1.0));
+    }
+
+    for (i, candidate) in step.candidates.iter().enumerate() {
+        let color = match candidate.rejected {
+            None => Color::srgb(0.2, 0.9, 0.3),
+            Some(_) => Color::srgb(0.9, 0.2, 0.2),
+        };
+        for obb in &candidate.footprint {
+            gizmos.cuboid(box_transform(obb), color);
+        }
+        if step.chosen == Some(i) {
+            gizmos.arrow(
+                parent_transform.translation,
+                candidate.transform.translation,
+                Color::WHITE,
+            );
+        }
+    }
+}
+
+pub fn update_debugger_panel(
+    debugger: Res<GenerationDebugger>,
+    island_query: Query<&Island>,
+    mut panel_query: Query<&mut Text, With<DebuggerPanel>>,
+) {
+    if !debugger.active {
+        return;
+    }
+
+    let mut lines = vec![String::from("Generation debugger")];
+    let island = debugger
+        .island
+        .and_then(|entity| Some((entity, island_query.get(entity).ok()?)));
+    match (island, &debugger.last_step) {
+        (Some((entity, island)), Some(step)) => {
+            lines.push(format!(
+                "island {}: {}/{} blocks, {} open slots",
+                entity,
+                island.blocks.len(),
+                island.size,
+                island.frontier.len()
+            ));
+            match &step.parent {
+                Some((id, transform)) => {
+                    let at = transform.translation;
+                    lines.push(format!(
+                        "parent {} at ({:.1}, {:.1}, {:.1})",
+                        id, at.x, at.y, at.z
+                    ));
+                }
+                None => lines.push(String::from(
+                    "nothing tried: no open slots, or the island backtracked",
+                )),
+            }
+            for (i, candidate) in step.candidates.iter().enumerate() {
+                let chosen = step.chosen == Some(i);
+                let outcome = match (candidate.rejected, chosen) {
+                    (None, true) if debugger.placed => String::from("placed"),
+                    (None, _) => String::from("fits"),
+                    (Some(reason), _) => format!("{:?}", reason),
+                };
+                lines.push(format!(
+                    "{} {}  weight {:.2}  {}",
+                    if chosen { ">" } else { " " },
+                    candidate.to,
+                    candidate.weight,
+                    outcome
+                ));
+            }
+        }
+        _ => lines.push(String::from("no island growing nearby")),
+    }
+    lines.push(HELP.to_string());
+
+    let text = lines.join("\n");
+    for mut panel in panel_query.iter_mut() {
+        panel.sections[0].value.clone_from(&text);
+    }
+}
@@ -1,54 +1,261 @@
-use bevy::{
-    gltf::{Gltf, GltfMesh, GltfNode},
-    prelude::*,
-    reflect::TypePath,
-    utils::HashMap,
-};
-use rand::Rng;
+use std::fmt;
+
+use bevy::{gltf::Gltf, prelude::*, reflect::TypePath, utils::HashMap};
 use serde::{Deserialize, Serialize};
 
-use super::exports;
-use super::{exports::EXPORTED_FILES, markov};
+use super::biome::BiomeMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The pack loaded from `exports/data.json` itself.
+pub const DEFAULT_PACK: &str = "default";
+
+/// Directory of a pack's catalog and GLBs, relative to the assets folder.
+pub fn pack_dir(pack: &str) -> String {
+    if pack == DEFAULT_PACK {
+        String::from("exports")
+    } else {
+        format!("exports/{}", pack)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub struct TransformMatrix(pub [[f32; 4]; 4]);
 
+impl TransformMatrix {
+    pub fn from_transform(transform: &Transform) -> Self {
+        TransformMatrix(transform.compute_matrix().to_cols_array_2d())
+    }
+
+    pub fn to_transform(self) -> Transform {
+        Transform::from_matrix(Mat4::from_cols_array_2d(&self.0))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub struct AABB {
     pub center: [f32; 3],
     pub half_extents: [f32; 3],
 }
 
+/// Per-block generation rules, all optional in `data.json`. Rules left at their defaults
+/// aren't written back out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BlockRules {
+    /// relative chance of this block being picked as an island seed, 0 to never seed
+    #[serde(skip_serializing_if = "is_one")]
+    pub seed_weight: f32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub min_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// a landmark appears exactly once on every island
+    #[serde(skip_serializing_if = "is_false")]
+    pub landmark: bool,
+    /// laid end to end as the bridges and ramps between islands
+    #[serde(skip_serializing_if = "is_false")]
+    pub connector: bool,
+}
+
+fn is_one(value: &f32) -> bool {
+    *value == 1.0
+}
+
+fn is_zero(value: &usize) -> bool {
+    *value == 0
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl Default for BlockRules {
+    fn default() -> Self {
+        BlockRules {
+            seed_weight: 1.0,
+            min_count: 0,
+            max_count: None,
+            landmark: false,
+            connector: false,
+        }
+    }
+}
+
+impl BlockRules {
+    pub fn is_default(&self) -> bool {
+        *self == BlockRules::default()
+    }
+
+    pub fn min(&self) -> usize {
+        if self.landmark {
+            1
+        } else {
+            self.min_count
+        }
+    }
+
+    pub fn max(&self) -> Option<usize> {
+        if self.landmark {
+            Some(1)
+        } else {
+            self.max_count
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CollectionData {
-    file: String,
+    /// empty for prefabs, which have no GLB of their own
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub file: String,
     pub aabb: AABB,
     pub transforms: Vec<(String, TransformMatrix)>,
+    /// relative chance of each entry in `transforms` being picked, missing entries weigh 1
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transition_weights: Vec<f32>,
+    #[serde(default, skip_serializing_if = "BlockRules::is_default")]
+    pub rules: BlockRules,
+    /// blocks of the same pack making up a prefab, relative to the prefab's origin
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<(String, TransformMatrix)>,
+}
+
+impl CollectionData {
+    pub fn is_prefab(&self) -> bool {
+        !self.parts.is_empty()
+    }
+
+    pub fn transition_weight(&self, index: usize) -> f32 {
+        self.transition_weights.get(index).copied().unwrap_or(1.0)
+    }
+
+    pub fn set_transition_weight(&mut self, index: usize, weight: f32) {
+        if self.transition_weights.len() < self.transforms.len() {
+            self.transition_weights.resize(self.transforms.len(), 1.0);
+        }
+        self.transition_weights[index] = weight;
+    }
+
+    pub fn add_transition(&mut self, name: &str, matrix: TransformMatrix) {
+        self.transforms.push((name.to_string(), matrix));
+        if !self.transition_weights.is_empty() {
+            self.transition_weights.push(1.0);
+        }
+    }
+
+    pub fn remove_transition(&mut self, index: usize) {
+        self.transforms.remove(index);
+        if index < self.transition_weights.len() {
+            self.transition_weights.remove(index);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Asset, TypePath)]
 pub struct GlbCollections(pub HashMap<String, CollectionData>);
 
-#[derive(Resource)]
-pub struct MarkovCollection {
+/// Transitions between packs, keyed by `pack/block` on both sides.
+#[derive(Serialize, Deserialize, Debug, Asset, TypePath)]
+pub struct BridgeTable(pub HashMap<String, Vec<(String, TransformMatrix)>>);
+
+/// A block in a specific pack.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlockId {
+    pub pack: String,
+    pub name: String,
+}
+
+impl BlockId {
+    pub fn new(pack: &str, name: &str) -> Self {
+        BlockId {
+            pack: pack.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    /// Parses `pack/block`, a bare `block` is in the default pack.
+    pub fn parse(id: &str) -> Self {
+        match id.split_once('/') {
+            Some((pack, name)) => BlockId::new(pack, name),
+            None => BlockId::new(DEFAULT_PACK, id),
+        }
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.pack, self.name)
+    }
+}
+
+pub struct BlockPack {
     pub collections_handle: Handle<GlbCollections>,
     pub glb_assets: HashMap<String, Handle<Gltf>>,
+    /// set once the catalog has loaded and its GLBs were asked for, a pack of prefabs has none
+    pub glbs_requested: bool,
 }
 
-pub fn setup_markov(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let collections: Handle<GlbCollections> = asset_server.load("exports/data.json");
-    let mut glb_assets: HashMap<String, Handle<Gltf>> = HashMap::new();
+/// Empty until the biome config says which packs to load.
+#[derive(Resource, Default)]
+pub struct MarkovCollection {
+    pub packs: HashMap<String, BlockPack>,
+    pub bridges_handle: Option<Handle<BridgeTable>>,
+}
+
+/// Starts loading the catalogs of the packs the biome config lists.
+pub fn setup_markov(
+    asset_server: Res<AssetServer>,
+    biome_map: Res<BiomeMap>,
+    mut markov_collection: ResMut<MarkovCollection>,
+) {
+    let mut packs: HashMap<String, BlockPack> = HashMap::new();
+
+    for pack in &biome_map.packs {
+        let path_name = format!("{}/data.json", pack_dir(pack));
+        info!("Loading {}", path_name);
+        packs.insert(
+            pack.clone(),
+            BlockPack {
+                collections_handle: asset_server.load(path_name),
+                glb_assets: HashMap::new(),
+                glbs_requested: false,
+            },
+        );
+    }
+
+    let bridges_handle = biome_map
+        .bridges
+        .as_ref()
+        .map(|path| asset_server.load(path.clone()));
+
+    *markov_collection = MarkovCollection {
+        packs,
+        bridges_handle,
+    };
+}
 
-    EXPORTED_FILES.iter().for_each(|file| {
-        let path_name = format!("exports/{}", file);
-        let path_name = format!("{}.glb", path_name);
-        println!("Loading {}", path_name);
-        let glb_handle: Handle<Gltf> = asset_server.load(path_name);
-        glb_assets.insert(file.to_string(), glb_handle);
-    });
+/// Loads the GLBs a pack's catalog refers to once the catalog itself has loaded.
+pub fn load_pack_glbs(
+    asset_server: Res<AssetServer>,
+    collections: Res<Assets<GlbCollections>>,
+    mut markov_collection: ResMut<MarkovCollection>,
+) {
+    for (pack, block_pack) in markov_collection.packs.iter_mut() {
+        if block_pack.glbs_requested {
+            continue;
+        }
+        let Some(collection) = collections.get(&block_pack.collections_handle) else {
+            continue;
+        };
+        block_pack.glbs_requested = true;
 
-    commands.insert_resource(MarkovCollection {
-        collections_handle: collections,
-        glb_assets,
-    });
+        for (name, data) in collection.0.iter() {
+            if data.file.is_empty() {
+                continue;
+            }
+            let path_name = format!("{}/{}", pack_dir(pack), data.file);
+            debug!("Loading {}", path_name);
+            let glb_handle: Handle<Gltf> = asset_server.load(path_name);
+            block_pack.glb_assets.insert(name.clone(), glb_handle);
+        }
+    }
 }
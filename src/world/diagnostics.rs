@@ -0,0 +1,113 @@
+use std::{collections::BTreeMap, fs};
+
+use bevy::diagnostic::{Diagnostic, DiagnosticPath, Diagnostics, RegisterDiagnostic};
+use bevy::prelude::*;
+use bevy_rapier3d::plugin::RapierContext;
+use serde::Serialize;
+
+use super::chunks::WorldSeed;
+use super::markov::RejectReason;
+use super::progress::GenerationProgress;
+use crate::loading::AppState;
+
+pub const BLOCKS_PLACED: DiagnosticPath = DiagnosticPath::const_new("generation/blocks_placed");
+pub const PLACEMENTS_REJECTED: DiagnosticPath =
+    DiagnosticPath::const_new("generation/placements_rejected");
+pub const COLLIDERS: DiagnosticPath = DiagnosticPath::const_new("generation/colliders");
+pub const GROW_TIME: DiagnosticPath = DiagnosticPath::const_new("generation/grow_time");
+
+/// Rejections are counted per reason, under `generation/rejected/<reason>`.
+pub fn rejection_path(reason: RejectReason) -> DiagnosticPath {
+    DiagnosticPath::new(format!("generation/rejected/{}", reason.name()))
+}
+
+/// Where to write a summary once the world has generated, set with `--summary <path>`.
+#[derive(Resource, Default)]
+pub struct GenerationSummary {
+    pub path: Option<String>,
+}
+
+/// Generation numbers as Bevy diagnostics, so generator changes can be compared run to run.
+pub struct GenerationDiagnosticsPlugin;
+
+impl Plugin for GenerationDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_diagnostic(Diagnostic::new(BLOCKS_PLACED))
+            .register_diagnostic(Diagnostic::new(PLACEMENTS_REJECTED))
+            .register_diagnostic(Diagnostic::new(COLLIDERS))
+            .register_diagnostic(Diagnostic::new(GROW_TIME).with_suffix("ms"));
+        for reason in RejectReason::ALL {
+            app.register_diagnostic(Diagnostic::new(rejection_path(reason)));
+        }
+
+        app.init_resource::<GenerationSummary>()
+            .add_systems(Update, measure_generation)
+            .add_systems(OnExit(AppState::Generating), write_generation_summary);
+    }
+}
+
+fn measure_generation(
+    mut diagnostics: Diagnostics,
+    progress: Res<GenerationProgress>,
+    rapier_context: Res<RapierContext>,
+) {
+    diagnostics.add_measurement(&BLOCKS_PLACED, || progress.blocks_placed as f64);
+    diagnostics.add_measurement(&PLACEMENTS_REJECTED, || progress.placements_rejected as f64);
+    diagnostics.add_measurement(&COLLIDERS, || rapier_context.colliders.len() as f64);
+    diagnostics.add_measurement(&GROW_TIME, || progress.grow_time.as_secs_f64() * 1000.0);
+    for reason in RejectReason::ALL {
+        diagnostics.add_measurement(&rejection_path(reason), || progress.rejected(reason) as f64);
+    }
+}
+
+#[derive(Serialize)]
+struct Summary {
+    seed: u64,
+    chunks: usize,
+    islands: usize,
+    blocks_placed: usize,
+    blocks_target: usize,
+    placements_rejected: usize,
+    rejections: BTreeMap<&'static str, usize>,
+    colliders: usize,
+    generation_seconds: f32,
+}
+
+fn write_generation_summary(
+    summary: Res<GenerationSummary>,
+    progress: Res<GenerationProgress>,
+    world_seed: Res<WorldSeed>,
+    rapier_context: Res<RapierContext>,
+) {
+    let colliders = rapier_context.colliders.len();
+    info!(
+        "{} placements rejected, {} colliders",
+        progress.placements_rejected, colliders
+    );
+
+    let Some(path) = &summary.path else {
+        return;
+    };
+    let summary = Summary {
+        seed: world_seed.0,
+        chunks: progress.chunks_loaded,
+        islands: progress.islands_total,
+        blocks_placed: progress.blocks_placed,
+        blocks_target: progress.blocks_target,
+        placements_rejected: progress.placements_rejected,
+        rejections: RejectReason::ALL
+            .iter()
+            .map(|reason| (reason.name(), progress.rejected(*reason)))
+            .collect(),
+        colliders,
+        generation_seconds: progress.generation_time.as_secs_f32(),
+    };
+
+    let written = serde_json::to_string_pretty(&summary)
+        .map_err(|err| err.to_string())
+        .and_then(|json| fs::write(path, json).map_err(|err| err.to_string()));
+    match written {
+        Ok(()) => info!("Wrote generation summary to {}", path),
+        Err(err) => error!("Could not write {}: {}", path, err),
+    }
+}
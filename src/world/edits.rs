@@ -0,0 +1,296 @@
+use std::fs;
+
+use bevy::{prelude::*, utils::HashMap};
+use serde::{Deserialize, Serialize};
+
+use super::bake::KeepUnbaked;
+use super::checkpoints::spawn_checkpoint;
+use super::chunks::{Chunk, ChunkSettings, SeedOverride, WorldSeed};
+use super::deserialize::{BlockId, TransformMatrix};
+use super::markov::{BlockAssets, Island};
+use super::routes::Connected;
+use super::spatial::BlockSpatialIndex;
+use super::support::{resting_on, DestroyBlock};
+
+const SAVE_DIR: &str = "saves";
+const SAVE_PATH: &str = "saves/world.json";
+// blocks this close to an edit's position are the block it refers to
+const MATCH_DISTANCE: f32 = 0.01;
+
+/// A change the player made to a generated chunk.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum BlockEdit {
+    Placed {
+        block: String,
+        transform: TransformMatrix,
+    },
+    Removed {
+        block: String,
+        transform: TransformMatrix,
+    },
+    /// where the player stands at the checkpoint
+    Checkpoint { position: [f32; 3] },
+}
+
+impl BlockEdit {
+    fn refers_to(&self, id: &BlockId, transform: &Transform) -> bool {
+        let (block, matrix) = match self {
+            BlockEdit::Placed {
+                block,
+                transform: matrix,
+            }
+            | BlockEdit::Removed {
+                block,
+                transform: matrix,
+            } => (block, matrix),
+            BlockEdit::Checkpoint { .. } => return false,
+        };
+        BlockId::parse(block) == *id
+            && matrix
+                .to_transform()
+                .translation
+                .distance(transform.translation)
+                < MATCH_DISTANCE
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChunkEdits {
+    coord: [i32; 2],
+    edits: Vec<BlockEdit>,
+}
+
+/// The save file: the seed regenerates the world, the edits are replayed on top of it.
+#[derive(Serialize, Deserialize)]
+struct WorldSave {
+    seed: u64,
+    chunks: Vec<ChunkEdits>,
+}
+
+#[derive(Resource, Default)]
+pub struct WorldEdits {
+    pub chunks: HashMap<IVec2, Vec<BlockEdit>>,
+}
+
+impl WorldEdits {
+    pub fn record_placed(&mut self, coord: IVec2, id: &BlockId, transform: &Transform) {
+        self.chunks
+            .entry(coord)
+            .or_default()
+            .push(BlockEdit::Placed {
+                block: id.to_string(),
+                transform: TransformMatrix::from_transform(transform),
+            });
+    }
+
+    pub fn record_checkpoint(&mut self, coord: IVec2, position: Vec3) {
+        self.chunks
+            .entry(coord)
+            .or_default()
+            .push(BlockEdit::Checkpoint {
+                position: position.to_array(),
+            });
+    }
+
+    /// Records a removal, or forgets the placement if the player placed the block themselves.
+    pub fn record_removed(&mut self, coord: IVec2, id: &BlockId, transform: &Transform) {
+        let edits = self.chunks.entry(coord).or_default();
+        let placed = edits.iter().position(|edit| {
+            matches!(edit, BlockEdit::Placed { .. }) && edit.refers_to(id, transform)
+        });
+        match placed {
+            Some(index) => {
+                edits.remove(index);
+            }
+            None => edits.push(BlockEdit::Removed {
+                block: id.to_string(),
+                transform: TransformMatrix::from_transform(transform),
+            }),
+        }
+    }
+
+    pub fn save(&self, seed: u64) {
+        let mut chunks: Vec<ChunkEdits> = self
+            .chunks
+            .iter()
+            .filter(|(_, edits)| !edits.is_empty())
+            .map(|(coord, edits)| ChunkEdits {
+                coord: coord.to_array(),
+                edits: edits.clone(),
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.coord);
+
+        let json = match serde_json::to_string_pretty(&WorldSave { seed, chunks }) {
+            Ok(json) => json,
+            Err(err) => {
+                error!("Could not serialize world edits: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = fs::create_dir_all(SAVE_DIR).and_then(|_| fs::write(SAVE_PATH, json)) {
+            error!("Could not save {}: {}", SAVE_PATH, err);
+        }
+    }
+}
+
+/// The chunk an island belongs to, edits are stored per chunk.
+pub fn island_chunk(settings: &ChunkSettings, island: &Island) -> IVec2 {
+    let center = island.bounds.center();
+    settings.chunk_coord(Vec3::new(center.x, 0.0, center.y))
+}
+
+/// Loads the saved world, if there is one, so it regenerates with the same seed. A seed given on
+/// the command line wins, the save's edits are only replayed if it was made with that seed.
+pub fn load_world_edits(
+    seed_override: Res<SeedOverride>,
+    mut world_seed: ResMut<WorldSeed>,
+    mut world_edits: ResMut<WorldEdits>,
+) {
+    if let Some(seed) = seed_override.0 {
+        world_seed.0 = seed;
+    }
+    let Ok(json) = fs::read_to_string(SAVE_PATH) else {
+        return;
+    };
+    let save: WorldSave = match serde_json::from_str(&json) {
+        Ok(save) => save,
+        Err(err) => {
+            warn!("Could not parse {}: {}", SAVE_PATH, err);
+            return;
+        }
+    };
+
+    if seed_override.0.is_some_and(|seed| seed != save.seed) {
+        warn!(
+            "{} was saved with seed {}, leaving its edits out",
+            SAVE_PATH, save.seed
+        );
+        return;
+    }
+    info!(
+        "Loaded {} with edits in {} chunks",
+        SAVE_PATH,
+        save.chunks.len()
+    );
+    world_seed.0 = save.seed;
+    world_edits.chunks = save
+        .chunks
+        .into_iter()
+        .map(|chunk| (IVec2::from_array(chunk.coord), chunk.edits))
+        .collect();
+}
+
+/// Placed blocks and checkpoints join the chunk's island closest to them.
+fn nearest_island(chunk: &Chunk, island_query: &Query<&mut Island>, point: Vec2) -> Option<Entity> {
+    chunk
+        .islands
+        .iter()
+        .min_by(|a, b| {
+            let distance = |entity: &Entity| {
+                island_query.get(*entity).map_or(f32::MAX, |island| {
+                    point.distance(point.clamp(island.bounds.min, island.bounds.max))
+                })
+            };
+            distance(a).total_cmp(&distance(b))
+        })
+        .copied()
+}
+
+/// Marks a chunk whose saved edits have been replayed.
+#[derive(Component)]
+pub struct EditsApplied;
+
+/// Replays the saved edits of a chunk once its islands have finished growing.
+pub fn apply_world_edits(
+    mut commands: Commands,
+    world_edits: Res<WorldEdits>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    chunk_query: Query<(Entity, &Chunk), Without<EditsApplied>>,
+    mut island_query: Query<&mut Island>,
+    connected_query: Query<(), With<Connected>>,
+    mut destroy_events: EventWriter<DestroyBlock>,
+) {
+    for (chunk_entity, chunk) in chunk_query.iter() {
+        let Some(edits) = world_edits.chunks.get(&chunk.coord) else {
+            commands.entity(chunk_entity).insert(EditsApplied);
+            continue;
+        };
+
+        // removed blocks may not have grown yet, placed ones may rest on a bridge still to come
+        let grown = chunk.islands.iter().all(|island_entity| {
+            island_query.get(*island_entity).map_or(true, |island| {
+                block_assets
+                    .collection(&island.pack)
+                    .is_none_or(|collection| island.is_complete(collection))
+                    && connected_query.contains(*island_entity)
+            })
+        });
+        if !grown {
+            continue;
+        }
+        let _span = info_span!("apply_world_edits", x = chunk.coord.x, z = chunk.coord.y).entered();
+
+        for edit in edits {
+            match edit {
+                BlockEdit::Placed { block, transform } => {
+                    let id = BlockId::parse(block);
+                    let xform = transform.to_transform();
+                    if block_assets.block(&id).is_none() {
+                        warn!("Saved block {} is no longer in the catalog", id);
+                        continue;
+                    }
+
+                    let Some(island_entity) =
+                        nearest_island(chunk, &island_query, xform.translation.xz())
+                    else {
+                        continue;
+                    };
+                    let Ok(mut island) = island_query.get_mut(island_entity) else {
+                        continue;
+                    };
+
+                    // the block it was placed from isn't saved, it hangs from what it rests on
+                    let parent = resting_on(&spatial_index, &block_assets.footprint(&id, &xform));
+                    let entity = block_assets.spawn_block(
+                        &mut commands,
+                        &mut spatial_index,
+                        &id,
+                        xform,
+                        island_entity,
+                    );
+                    if let Some(parent) = parent {
+                        spatial_index.set_parent(entity, parent);
+                    }
+                    island.add_block(&id, entity, xform);
+                    commands.entity(island_entity).insert(KeepUnbaked);
+                }
+                BlockEdit::Removed { .. } => {
+                    for island_entity in &chunk.islands {
+                        let Ok(island) = island_query.get(*island_entity) else {
+                            continue;
+                        };
+                        let removed = island
+                            .blocks
+                            .iter()
+                            .find(|placed| edit.refers_to(&placed.id, &placed.transform));
+                        if let Some(placed) = removed {
+                            destroy_events.send(DestroyBlock(placed.entity));
+                            commands.entity(*island_entity).insert(KeepUnbaked);
+                        }
+                    }
+                }
+                BlockEdit::Checkpoint { position } => {
+                    let position = Vec3::from_array(*position);
+                    if let Some(island_entity) = nearest_island(chunk, &island_query, position.xz())
+                    {
+                        spawn_checkpoint(&mut commands, island_entity, position);
+                    }
+                }
+            }
+        }
+
+        commands.entity(chunk_entity).insert(EditsApplied);
+    }
+}
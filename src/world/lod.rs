@@ -0,0 +1,321 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_asset::RenderAssetUsages, render_resource::PrimitiveTopology},
+};
+use bevy_fps_controller::controller::LogicalPlayer;
+use bevy_rapier3d::prelude::*;
+
+use super::bake::{set_baked_lod, Baked};
+use super::deserialize::BlockId;
+use super::markov::{BlockAssets, Island};
+use super::obb::Obb;
+
+#[derive(Resource)]
+pub struct LodSettings {
+    /// islands further than this from the player are drawn as proxies
+    pub proxy_distance: f32,
+    /// how far past `proxy_distance` the player has to move before an island switches, so it doesn't flicker
+    pub hysteresis: f32,
+}
+
+impl Default for LodSettings {
+    fn default() -> Self {
+        LodSettings {
+            proxy_distance: 120.0,
+            hysteresis: 12.0,
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IslandLod {
+    #[default]
+    Full,
+    Proxy,
+}
+
+/// The box mesh standing in for a far island, with the blocks it was built from.
+#[derive(Component)]
+pub struct IslandProxy {
+    pub entity: Entity,
+    /// hidden while the proxy stands in for them
+    pub blocks: Vec<Entity>,
+}
+
+#[derive(Resource)]
+pub struct ProxyMaterial(pub Handle<StandardMaterial>);
+
+pub fn setup_proxy_material(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // the box colours come from the mesh's vertex colours
+    let material = StandardMaterial {
+        base_color: Color::WHITE,
+        perceptual_roughness: 1.0,
+        ..Default::default()
+    };
+    commands.insert_resource(ProxyMaterial(materials.add(material)));
+}
+
+/// Distance from a point to the island's XZ bounds, zero inside them.
+fn distance_to_island(island: &Island, point: Vec3) -> f32 {
+    let p = point.xz();
+    p.distance(p.clamp(island.bounds.min, island.bounds.max))
+}
+
+/// Swaps islands between their full blocks and a box proxy depending on their distance to the player.
+pub fn update_island_lod(
+    mut commands: Commands,
+    // the block assets hold the mesh assets too, so they can't be borrowed at the same time
+    mut assets: ParamSet<(BlockAssets, ResMut<Assets<Mesh>>)>,
+    settings: Res<LodSettings>,
+    proxy_material: Res<ProxyMaterial>,
+    player_query: Query<&Transform, With<LogicalPlayer>>,
+    mut island_query: Query<(
+        Entity,
+        &Island,
+        &Transform,
+        Option<&mut IslandLod>,
+        Option<&mut IslandProxy>,
+        Option<&Baked>,
+    )>,
+    children_query: Query<&Children>,
+    collider_query: Query<(), With<Collider>>,
+) {
+    let Ok(player_transform) = player_query.get_single() else {
+        return;
+    };
+
+    for (island_entity, island, island_transform, lod, proxy, baked) in island_query.iter_mut() {
+        let distance = distance_to_island(island, player_transform.translation);
+        let current = lod.as_deref().copied().unwrap_or_default();
+        let wanted = match current {
+            IslandLod::Full if distance > settings.proxy_distance + settings.hysteresis => {
+                IslandLod::Proxy
+            }
+            IslandLod::Proxy if distance < settings.proxy_distance - settings.hysteresis => {
+                IslandLod::Full
+            }
+            _ => current,
+        };
+
+        if wanted == IslandLod::Full {
+            if current == IslandLod::Proxy {
+                match baked {
+                    Some(baked) => set_baked_lod(&mut commands, island_entity, baked, true),
+                    None => {
+                        for block in &island.blocks {
+                            set_block_lod(
+                                &mut commands,
+                                block.entity,
+                                true,
+                                &children_query,
+                                &collider_query,
+                            );
+                        }
+                    }
+                }
+                if let Some(proxy) = proxy {
+                    commands.entity(proxy.entity).insert(Visibility::Hidden);
+                }
+            }
+            if let Some(mut lod) = lod {
+                *lod = IslandLod::Full;
+            }
+            continue;
+        }
+
+        // a far island may still be growing or backtracking, so rebuild its proxy whenever its
+        // blocks change
+        let blocks: Vec<Entity> = island.blocks.iter().map(|block| block.entity).collect();
+        let uncovered = match proxy.as_ref().filter(|_| current == IslandLod::Proxy) {
+            Some(proxy) => match uncovered_blocks(&proxy.blocks, &blocks) {
+                Some(uncovered) => uncovered,
+                None => continue,
+            },
+            None => blocks.clone(),
+        };
+
+        match baked {
+            // a baked island's blocks are hidden already
+            Some(baked) => set_baked_lod(&mut commands, island_entity, baked, false),
+            None => {
+                for entity in uncovered {
+                    set_block_lod(
+                        &mut commands,
+                        entity,
+                        false,
+                        &children_query,
+                        &collider_query,
+                    );
+                }
+            }
+        }
+
+        let mesh = proxy_mesh(&assets.p0(), island, island_transform);
+        let mesh = assets.p1().add(mesh);
+        match proxy {
+            Some(mut proxy) => {
+                commands
+                    .entity(proxy.entity)
+                    .insert((mesh, Visibility::Inherited));
+                proxy.blocks = blocks;
+            }
+            None => {
+                let proxy_entity = commands
+                    .spawn(PbrBundle {
+                        mesh,
+                        material: proxy_material.0.clone(),
+                        ..Default::default()
+                    })
+                    .id();
+                commands
+                    .entity(island_entity)
+                    .add_child(proxy_entity)
+                    .insert(IslandProxy {
+                        entity: proxy_entity,
+                        blocks,
+                    });
+            }
+        }
+
+        match lod {
+            Some(mut lod) => *lod = IslandLod::Proxy,
+            None => {
+                commands.entity(island_entity).insert(IslandLod::Proxy);
+            }
+        }
+    }
+}
+
+/// The blocks a proxy built over `covered` leaves showing, None if it still stands for exactly
+/// these blocks and doesn't need rebuilding.
+fn uncovered_blocks(covered: &[Entity], blocks: &[Entity]) -> Option<Vec<Entity>> {
+    if covered == blocks {
+        return None;
+    }
+    Some(
+        blocks
+            .iter()
+            .filter(|entity| !covered.contains(entity))
+            .copied()
+            .collect(),
+    )
+}
+
+/// Shows or hides a block and enables or disables every collider under it.
+pub fn set_block_lod(
+    commands: &mut Commands,
+    entity: Entity,
+    full: bool,
+    children_query: &Query<&Children>,
+    collider_query: &Query<(), With<Collider>>,
+) {
+    let visibility = if full {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    commands.entity(entity).insert(visibility);
+
+    for descendant in std::iter::once(entity).chain(children_query.iter_descendants(entity)) {
+        if !collider_query.contains(descendant) {
+            continue;
+        }
+        if full {
+            commands.entity(descendant).remove::<ColliderDisabled>();
+        } else {
+            commands.entity(descendant).insert(ColliderDisabled);
+        }
+    }
+}
+
+/// One mesh of every block's catalog box, in the island's local space.
+fn proxy_mesh(block_assets: &BlockAssets, island: &Island, island_transform: &Transform) -> Mesh {
+    let to_local = island_transform.compute_matrix().inverse();
+    let mut builder = BoxMeshBuilder::default();
+
+    for block in &island.blocks {
+        let color = block_color(&block.id);
+        for obb in block_assets.footprint(&block.id, &block.transform) {
+            builder.push(&obb, to_local, color);
+        }
+    }
+    builder.build()
+}
+
+/// A stable colour per block so proxies still read as the island they stand in for.
+fn block_color(id: &BlockId) -> [f32; 4] {
+    let hash = id
+        .to_string()
+        .bytes()
+        .fold(2166136261u32, |h, b| (h ^ b as u32).wrapping_mul(16777619));
+    let hue = (hash % 360) as f32;
+    let color = Color::hsl(hue, 0.35, 0.6).to_linear();
+    [color.red, color.green, color.blue, 1.0]
+}
+
+#[derive(Default)]
+struct BoxMeshBuilder {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl BoxMeshBuilder {
+    fn push(&mut self, obb: &Obb, to_local: Mat4, color: [f32; 4]) {
+        let rotation = to_local.to_scale_rotation_translation().1 * obb.rotation;
+        let center = to_local.transform_point3(obb.center);
+
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            for sign in [1.0, -1.0] {
+                let normal = axis * sign;
+                // two axes spanning the face, ordered so the winding faces outwards
+                let u = Vec3::new(normal.y, normal.z, normal.x);
+                let v = normal.cross(u);
+
+                let base = self.positions.len() as u32;
+                for (du, dv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                    let corner = (normal + u * du + v * dv) * obb.half_extents;
+                    self.positions.push((center + rotation * corner).to_array());
+                    self.normals.push((rotation * normal).to_array());
+                    self.colors.push(color);
+                }
+                self.indices
+                    .extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+            }
+        }
+    }
+
+    fn build(self) -> Mesh {
+        Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::RENDER_WORLD,
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, self.colors)
+        .with_inserted_indices(Indices::U32(self.indices))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regrown_blocks_are_hidden() {
+        let [seed, a, b, c, d] = [1, 2, 3, 4, 5].map(Entity::from_raw);
+        let covered = vec![seed, a, b];
+        assert_eq!(uncovered_blocks(&covered, &covered), None);
+
+        // backtracked under the proxy, nothing left to hide but the mesh is stale
+        assert_eq!(uncovered_blocks(&covered, &[seed]), Some(vec![]));
+        // and regrown back to the same count with new blocks
+        assert_eq!(uncovered_blocks(&covered, &[seed, c, d]), Some(vec![c, d]));
+        // grown on past it
+        assert_eq!(uncovered_blocks(&covered, &[seed, a, b, c]), Some(vec![c]));
+    }
+}
@@ -1,135 +1,598 @@
 use bevy::{
+    ecs::system::SystemParam,
     gltf::{Gltf, GltfMesh, GltfNode},
     prelude::*,
+    utils::{HashMap, Instant},
 };
-use bevy_rapier3d::{
-    plugin::RapierContext,
-    prelude::{Collider, QueryFilter, RigidBody},
-};
+use bevy_rapier3d::prelude::RigidBody;
 use rand::prelude::SliceRandom;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 use crate::world::spawn_collider_from_gltf_node;
 
-use super::deserialize::{GlbCollections, MarkovCollection};
+use super::biome::BiomeMap;
+use super::deserialize::{
+    BlockId, BridgeTable, CollectionData, GlbCollections, MarkovCollection, TransformMatrix,
+};
+use super::obb::Obb;
+use super::progress::{GenerationBudget, GenerationProgress};
+use super::shape::IslandShape;
+use super::spatial::BlockSpatialIndex;
+use super::support::is_supported;
+use super::vertical::VerticalRules;
+
+// consecutive failed placements before an island counts as stalled
+const MAX_FAILED_ATTEMPTS: usize = 200;
+// a stalled island takes back its newest blocks, a few more each time, before it's regrown
+const BACKTRACK_BLOCKS: usize = 3;
+const MAX_BACKTRACKS: usize = 8;
+// an island that still can't be finished after this many regrowths is removed
+const MAX_REGENERATIONS: usize = 5;
+// open slots this far from the island's centre weigh a half, keeping islands compact
+const COMPACT_RADIUS: f32 = 10.0;
 
-pub fn post_generate(rapier_context: Res<RapierContext>) {
-    println!("length of colliders: {:?}", rapier_context.colliders.len());
+/// The asset collections needed to spawn catalog blocks.
+#[derive(SystemParam)]
+pub struct BlockAssets<'w> {
+    pub markov_collection: Res<'w, MarkovCollection>,
+    pub collections: Res<'w, Assets<GlbCollections>>,
+    pub bridges: Res<'w, Assets<BridgeTable>>,
+    pub gltf_assets: Res<'w, Assets<Gltf>>,
+    pub gltf_mesh_assets: Res<'w, Assets<GltfMesh>>,
+    pub gltf_node_assets: Res<'w, Assets<GltfNode>>,
+    pub mesh_assets: Res<'w, Assets<Mesh>>,
 }
 
-pub fn add_island(
-    mut commands: Commands,
-    mut world_state: ResMut<NextState<WorldState>>,
-    mut world_random: ResMut<WorldRandom>,
-    collections: Res<Assets<GlbCollections>>,
-    markov_collection: Res<MarkovCollection>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    mesh_assets: Res<Assets<Mesh>>,
-) {
-    if gltf_assets.len() == 0 {
-        return;
-    }
-    if let Some(_) = collections.get(&markov_collection.collections_handle) {
-        println!("Generating world");
-
-        let count = 40;
-        for i in 0..count {
-            println!("Generating island number: {:?}", i);
-
-            // start by randomly picking a glb and spawning at 0,0
-            let random_index = world_random
-                .0
-                .gen_range(0..markov_collection.glb_assets.len());
-            let random_instance = markov_collection
-                .glb_assets
-                .keys()
-                .nth(random_index)
-                .unwrap();
-
-            let size = 200.0;
-            let xform = Transform::from_xyz(
-                world_random.0.gen_range(-size..size),
-                0.0,
-                world_random.0.gen_range(-size..size),
-            );
+impl BlockAssets<'_> {
+    pub fn collection(&self, pack: &str) -> Option<&GlbCollections> {
+        let pack = self.markov_collection.packs.get(pack)?;
+        self.collections.get(&pack.collections_handle)
+    }
 
-            let gltf_handle = markov_collection.glb_assets.get(random_instance).unwrap();
-            println!(
-                "random instance: {:?}, handle {:?}",
-                random_instance, gltf_handle
-            );
-            let gltf = gltf_assets.get(gltf_handle).unwrap();
-
-            let scene = gltf.scenes.first().unwrap().clone();
-            commands
-                .spawn((
-                    SceneBundle {
-                        scene,
-                        transform: xform.clone(),
-                        ..Default::default()
-                    },
-                    BlockInstance {
-                        name: random_instance.clone(),
-                    },
-                    RigidBody::Fixed,
-                    BlockInstanceCollider,
-                ))
-                .with_children(|children| {
-                    for node in &gltf.nodes {
-                        let node = gltf_node_assets.get(node).unwrap();
-                        for bundle in
-                            spawn_collider_from_gltf_node(node, &gltf_mesh_assets, &mesh_assets)
-                        {
-                            children.spawn(bundle);
-                        }
-                    }
-                });
+    pub fn block(&self, id: &BlockId) -> Option<&CollectionData> {
+        self.collection(&id.pack)?.0.get(&id.name)
+    }
 
-            let island_size = world_random.0.gen_range(20..80) as usize;
-            commands.spawn((Island::new(island_size), xform));
+    pub fn gltf(&self, id: &BlockId) -> Option<&Gltf> {
+        let pack = self.markov_collection.packs.get(&id.pack)?;
+        self.gltf_assets.get(pack.glb_assets.get(&id.name)?)
+    }
 
-            world_state.set(WorldState::Generated);
+    /// Whether every pack's catalog and GLBs have loaded.
+    pub fn is_loaded(&self) -> bool {
+        self.markov_collection.packs.values().all(|pack| {
+            self.collections.get(&pack.collections_handle).is_some()
+                && pack.glbs_requested
+                && pack
+                    .glb_assets
+                    .values()
+                    .all(|handle| self.gltf_assets.get(handle).is_some())
+        })
+    }
+
+    /// Every transition out of a block, including bridges into other packs.
+    pub fn transitions(&self, id: &BlockId) -> Vec<Transition<'_>> {
+        let mut transitions: Vec<Transition> = self
+            .block(id)
+            .map(|data| {
+                data.transforms
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, matrix))| Transition {
+                        to: BlockId::new(&id.pack, name),
+                        matrix,
+                        weight: data.transition_weight(i),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let bridges = self
+            .markov_collection
+            .bridges_handle
+            .as_ref()
+            .and_then(|handle| self.bridges.get(handle));
+        if let Some(bridges) = bridges.and_then(|bridges| bridges.0.get(&id.to_string())) {
+            transitions.extend(bridges.iter().map(|(target, matrix)| Transition {
+                to: BlockId::parse(target),
+                matrix,
+                weight: 1.0,
+            }));
         }
-    } else {
-        println!("No collections found");
+
+        transitions
+    }
+
+    /// The plain blocks making up a block placed at `xform`: the block itself, or a prefab's parts.
+    pub fn leaf_blocks(&self, id: &BlockId, xform: &Transform) -> Vec<(BlockId, Transform)> {
+        let Some(data) = self.block(id) else {
+            return Vec::new();
+        };
+        if !data.is_prefab() {
+            return vec![(id.clone(), *xform)];
+        }
+
+        data.parts
+            .iter()
+            .flat_map(|(name, matrix)| {
+                self.leaf_blocks(
+                    &BlockId::new(&id.pack, name),
+                    &(*xform * matrix.to_transform()),
+                )
+            })
+            .collect()
+    }
+
+    /// Oriented boxes covering a block placed at `xform`, one per part for prefabs.
+    pub fn footprint(&self, id: &BlockId, xform: &Transform) -> Vec<Obb> {
+        self.leaf_blocks(id, xform)
+            .iter()
+            .filter_map(|(id, xform)| Some(Obb::from_aabb(&self.block(id)?.aabb, xform)))
+            .collect()
+    }
+
+    /// Spawns a block, or a prefab and all of its parts as children, and adds it and its parts
+    /// to the spatial index straight away. Returns the root entity.
+    pub fn spawn_block(
+        &self,
+        commands: &mut Commands,
+        spatial_index: &mut BlockSpatialIndex,
+        id: &BlockId,
+        xform: Transform,
+        island: Entity,
+    ) -> Entity {
+        let mut leaves = Vec::new();
+        let entity = self.spawn_block_entity(commands, id, xform, island, &mut leaves);
+        let footprint = self.footprint(id, &xform);
+        if leaves == [entity] {
+            spatial_index.insert(entity, footprint);
+        } else {
+            spatial_index.insert_prefab(entity, footprint, leaves);
+        }
+        entity
     }
+
+    /// Spawns a block and pushes the plain blocks it's made of to `leaves`, in the order of
+    /// `leaf_blocks`.
+    fn spawn_block_entity(
+        &self,
+        commands: &mut Commands,
+        id: &BlockId,
+        xform: Transform,
+        island: Entity,
+        leaves: &mut Vec<Entity>,
+    ) -> Entity {
+        let block_instance = BlockInstance {
+            name: id.name.clone(),
+            pack: id.pack.clone(),
+            island,
+        };
+
+        let data = self.block(id).unwrap();
+        if data.is_prefab() {
+            let root = commands
+                .spawn((SpatialBundle::from_transform(xform), block_instance, Prefab))
+                .id();
+            for (name, matrix) in &data.parts {
+                let part = self.spawn_block_entity(
+                    commands,
+                    &BlockId::new(&id.pack, name),
+                    matrix.to_transform(),
+                    island,
+                    leaves,
+                );
+                commands.entity(root).add_child(part);
+            }
+            return root;
+        }
+
+        let gltf = self.gltf(id).unwrap();
+        let scene = gltf.scenes.first().unwrap().clone();
+
+        let entity = commands
+            .spawn((
+                SceneBundle {
+                    scene,
+                    transform: xform,
+                    ..Default::default()
+                },
+                block_instance,
+                RigidBody::Fixed,
+                BlockInstanceCollider,
+            ))
+            .with_children(|children| {
+                for node in &gltf.nodes {
+                    let node = self.gltf_node_assets.get(node).unwrap();
+                    for bundle in spawn_collider_from_gltf_node(
+                        node,
+                        &self.gltf_mesh_assets,
+                        &self.mesh_assets,
+                    ) {
+                        children.spawn(bundle);
+                    }
+                }
+            })
+            .id();
+        leaves.push(entity);
+        entity
+    }
+}
+
+/// A way to grow from one block to another.
+pub struct Transition<'a> {
+    pub to: BlockId,
+    pub matrix: &'a TransformMatrix,
+    pub weight: f32,
+}
+
+/// Spawns an island's seed block, the island then grows inside `bounds` over the next frames.
+pub fn spawn_island(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    biome_map: &BiomeMap,
+    block_assets: &BlockAssets,
+    mut rng: StdRng,
+    xform: Transform,
+    size: usize,
+    bounds: Rect,
+    shape: IslandShape,
+    vertical: VerticalRules,
+) -> Option<Entity> {
+    // the biome under the island decides which pack it grows from
+    let pack = biome_map.pack_at(xform.translation);
+    let Some(collection) = block_assets.collection(pack) else {
+        warn!("No collections found for pack {}", pack);
+        return None;
+    };
+
+    let Some(seed_id) = pick_seed(collection, pack, &mut rng) else {
+        warn!("No seed blocks in pack {}", pack);
+        return None;
+    };
+
+    debug!("Seeding island with {} at {}", seed_id, xform.translation);
+
+    let island_entity = commands.spawn_empty().id();
+    let seed = block_assets.spawn_block(commands, spatial_index, &seed_id, xform, island_entity);
+
+    let mut island = Island::new(size, seed_id.clone(), rng, bounds, shape, vertical);
+    island.add_block(&seed_id, seed, xform);
+    island.open_slots(block_assets, 0);
+    commands
+        .entity(island_entity)
+        .insert((island, SpatialBundle::from_transform(xform)));
+
+    Some(island_entity)
 }
 
-#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash, States)]
-pub enum WorldState {
-    #[default]
-    Generating,
-    Generated,
+/// Picks a block to start an island from, weighted by how often each should start one.
+fn pick_seed(collection: &GlbCollections, pack: &str, rng: &mut StdRng) -> Option<BlockId> {
+    let mut seeds: Vec<(&String, &CollectionData)> = collection
+        .0
+        .iter()
+        .filter(|(_, data)| data.rules.seed_weight > 0.0)
+        .collect();
+    // hash map order changes between runs, the seed pick must not
+    seeds.sort_by(|a, b| a.0.cmp(b.0));
+
+    let (name, _) = seeds
+        .choose_weighted(rng, |(_, data)| data.rules.seed_weight)
+        .ok()?;
+    Some(BlockId::new(pack, name))
 }
 
 #[derive(Component)]
 pub struct BlockInstance {
     pub name: String,
+    pub pack: String,
+    pub island: Entity,
 }
 
-#[derive(Resource)]
-pub struct WorldRandom(pub StdRng);
-
-impl Default for WorldRandom {
-    fn default() -> Self {
-        WorldRandom(StdRng::from_entropy())
+impl BlockInstance {
+    pub fn id(&self) -> BlockId {
+        BlockId::new(&self.pack, &self.name)
     }
 }
 
+/// A block placed on an island, kept on the island so growth doesn't have to wait
+/// for the spawn commands to be applied.
+pub struct PlacedBlock {
+    pub entity: Entity,
+    pub id: BlockId,
+    pub transform: Transform,
+}
+
+/// A transition out of a placed block that hasn't been tried yet.
+pub struct Slot {
+    pub parent: Entity,
+    /// index into the parent's `BlockAssets::transitions`
+    pub transition: usize,
+    pub to: BlockId,
+    pub position: Vec3,
+    /// the transition's weight scaled by the island's shape there
+    pub weight: f32,
+}
+
 #[derive(Component)]
 pub struct Island {
-    pub children: Vec<Entity>,
+    pub blocks: Vec<PlacedBlock>,
     pub size: usize,
+    /// the pack the island was seeded from, its landmarks come from this pack
+    pub pack: String,
+    pub counts: HashMap<BlockId, usize>,
+    /// XZ area the island's blocks must stay inside
+    pub bounds: Rect,
+    /// outline laid over `bounds` that steers where the island grows
+    pub shape: IslandShape,
+    pub vertical: VerticalRules,
+    /// storeys the island's blocks reach, the seed's counts as the first
+    pub storeys: usize,
+    /// open slots the island can grow into next
+    pub frontier: Vec<Slot>,
+    /// each island grows from its own random stream, so it comes out the same however the frames fall
+    rng: StdRng,
+    failed_attempts: usize,
+    backtracks: usize,
+    regenerations: usize,
+    /// set once the island has grown, blocks destroyed or placed after that don't restart growth
+    finished: bool,
+    /// set when the island couldn't meet its rules and is being despawned
+    abandoned: bool,
 }
 
 impl Island {
-    fn new(size: usize) -> Self {
+    fn new(
+        size: usize,
+        seed: BlockId,
+        rng: StdRng,
+        bounds: Rect,
+        shape: IslandShape,
+        vertical: VerticalRules,
+    ) -> Self {
         Island {
-            children: Vec::new(),
+            blocks: Vec::new(),
             size,
+            pack: seed.pack,
+            counts: HashMap::new(),
+            bounds,
+            shape,
+            vertical,
+            storeys: 1,
+            frontier: Vec::new(),
+            rng,
+            failed_attempts: 0,
+            backtracks: 0,
+            regenerations: 0,
+            finished: false,
+            abandoned: false,
+        }
+    }
+
+    /// Whether a box lies inside the island's bounds.
+    pub fn contains(&self, obb: &Obb) -> bool {
+        let (min, max) = (obb.min().xz(), obb.max().xz());
+        self.bounds.contains(min) && self.bounds.contains(max)
+    }
+
+    /// How readily the island grows at `position`, zero outside its shape.
+    pub fn shape_at(&self, position: Vec3) -> f32 {
+        self.shape.value(&self.bounds, position)
+    }
+
+    pub fn add_block(&mut self, id: &BlockId, entity: Entity, transform: Transform) {
+        self.blocks.push(PlacedBlock {
+            entity,
+            id: id.clone(),
+            transform,
+        });
+        *self.counts.entry(id.clone()).or_default() += 1;
+        self.failed_attempts = 0;
+    }
+
+    /// Adds the transitions out of `self.blocks[index]` to the frontier, leaving out
+    /// the ones that can never be picked.
+    pub fn open_slots(&mut self, block_assets: &BlockAssets, index: usize) {
+        let block = &self.blocks[index];
+        let slots: Vec<Slot> = block_assets
+            .transitions(&block.id)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, transition)| {
+                let position = (block.transform * transition.matrix.to_transform()).translation;
+                let weight = transition.weight * self.shape_at(position);
+                (weight > 0.0).then_some(Slot {
+                    parent: block.entity,
+                    transition: i,
+                    to: transition.to,
+                    position,
+                    weight,
+                })
+            })
+            .collect();
+        self.frontier.extend(slots);
+    }
+
+    /// Mean position of the island's blocks.
+    pub fn centroid(&self) -> Vec3 {
+        let sum: Vec3 = self
+            .blocks
+            .iter()
+            .map(|block| block.transform.translation)
+            .sum();
+        sum / self.blocks.len().max(1) as f32
+    }
+
+    /// Takes a block off the island, e.g. when it's destroyed.
+    pub fn remove_block(&mut self, entity: Entity) -> Option<PlacedBlock> {
+        let index = self
+            .blocks
+            .iter()
+            .position(|block| block.entity == entity)?;
+        let block = self.blocks.remove(index);
+        self.frontier.retain(|slot| slot.parent != entity);
+        if let Some(count) = self.counts.get_mut(&block.id) {
+            *count = count.saturating_sub(1);
+        }
+        Some(block)
+    }
+
+    /// Updates where a block is, e.g. once it has fallen and come to rest.
+    pub fn move_block(&mut self, entity: Entity, transform: Transform) {
+        if let Some(block) = self.blocks.iter_mut().find(|block| block.entity == entity) {
+            block.transform = transform;
+        }
+    }
+
+    pub fn count(&self, id: &BlockId) -> usize {
+        self.counts.get(id).copied().unwrap_or(0)
+    }
+
+    /// Blocks the island still needs to satisfy its pack's min counts.
+    pub fn missing_required(&self, collection: &GlbCollections) -> Vec<BlockId> {
+        collection
+            .0
+            .iter()
+            .map(|(name, data)| (BlockId::new(&self.pack, name), data))
+            .filter(|(id, data)| self.count(id) < data.rules.min())
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Whether another `id` block may be added to this island.
+    pub fn allows(&self, id: &BlockId, data: &CollectionData) -> bool {
+        if let Some(max) = data.rules.max() {
+            if self.count(id) >= max {
+                return false;
+            }
+        }
+
+        // once the island is full, only grow towards blocks it still needs, or upwards
+        if self.blocks.len() >= self.size {
+            return (id.pack == self.pack && self.count(id) < data.rules.min())
+                || self.storeys < self.vertical.min_storeys;
+        }
+        true
+    }
+
+    pub fn is_complete(&self, collection: &GlbCollections) -> bool {
+        if self.finished {
+            return true;
+        }
+        self.blocks.len() >= self.size
+            && self.missing_required(collection).is_empty()
+            && self.storeys >= self.vertical.min_storeys
+    }
+
+    /// Fraction of the island's target size that has been placed.
+    pub fn progress(&self) -> f32 {
+        (self.blocks.len() as f32 / self.size.max(1) as f32).min(1.0)
+    }
+
+    /// Takes the blocks from `keep` on off the island, and out of its counts.
+    fn take_blocks(&mut self, keep: usize) -> Vec<PlacedBlock> {
+        let keep = keep.min(self.blocks.len());
+        let removed: Vec<PlacedBlock> = self.blocks.drain(keep..).collect();
+        for block in &removed {
+            if let Some(placed) = self.counts.get_mut(&block.id) {
+                *placed = placed.saturating_sub(1);
+            }
         }
+        removed
+    }
+
+    /// Takes the `count` newest blocks off the island, never the seed.
+    fn take_newest(&mut self, count: usize) -> Vec<PlacedBlock> {
+        self.take_blocks(self.blocks.len().saturating_sub(count).max(1))
+    }
+
+    /// Reopens every slot of the blocks left and works out the storeys they reach again.
+    fn reopen(&mut self, block_assets: &BlockAssets) {
+        self.frontier.clear();
+        for index in 0..self.blocks.len() {
+            self.open_slots(block_assets, index);
+        }
+        self.storeys = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let footprint = block_assets.footprint(&block.id, &block.transform);
+                self.vertical.storey(&footprint) + 1
+            })
+            .max()
+            .unwrap_or(1);
+        self.failed_attempts = 0;
+    }
+
+    /// Despawns the `count` newest blocks, never the seed, and reopens every slot of the
+    /// blocks left so growth can take another route. Returns how many blocks went.
+    fn backtrack(
+        &mut self,
+        commands: &mut Commands,
+        spatial_index: &mut BlockSpatialIndex,
+        block_assets: &BlockAssets,
+        count: usize,
+    ) -> usize {
+        let removed = self.take_newest(count);
+        despawn_blocks(commands, spatial_index, &removed);
+        self.reopen(block_assets);
+        self.backtracks += 1;
+        removed.len()
+    }
+
+    /// Despawns every block, the seed too, and grows the island again from a freshly picked
+    /// seed in the same spot, a seed the island can't be finished from would stall every
+    /// regrowth. Returns how many blocks went besides the seed.
+    fn regenerate(
+        &mut self,
+        commands: &mut Commands,
+        spatial_index: &mut BlockSpatialIndex,
+        block_assets: &BlockAssets,
+        island_entity: Entity,
+    ) -> usize {
+        let Some(seed_transform) = self.blocks.first().map(|block| block.transform) else {
+            return 0;
+        };
+        let removed = self.take_blocks(0);
+        despawn_blocks(commands, spatial_index, &removed);
+
+        let seed_id = block_assets
+            .collection(&self.pack)
+            .and_then(|collection| pick_seed(collection, &self.pack, &mut self.rng));
+        if let Some(seed_id) = seed_id {
+            let seed = block_assets.spawn_block(
+                commands,
+                spatial_index,
+                &seed_id,
+                seed_transform,
+                island_entity,
+            );
+            self.add_block(&seed_id, seed, seed_transform);
+        }
+        self.reopen(block_assets);
+        self.backtracks = 0;
+        self.regenerations += 1;
+        removed.len().saturating_sub(1)
+    }
+
+    /// Despawns every block so the island can be dropped. Returns how many blocks went besides
+    /// the seed.
+    fn abandon(&mut self, commands: &mut Commands, spatial_index: &mut BlockSpatialIndex) -> usize {
+        let removed = self.take_blocks(0);
+        despawn_blocks(commands, spatial_index, &removed);
+        self.frontier.clear();
+        self.abandoned = true;
+        removed.len().saturating_sub(1)
+    }
+}
+
+fn despawn_blocks(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    blocks: &[PlacedBlock],
+) {
+    for block in blocks {
+        spatial_index.remove(block.entity);
+        commands.entity(block.entity).despawn_recursive();
     }
 }
 
@@ -139,106 +602,493 @@ impl Island {
 //     }
 // }
 
+/// Grows the islands round robin, one placement attempt per island per pass,
+/// until the frame's generation budget is used up.
 pub fn add_blocks_to_island(
     mut commands: Commands,
-    block_instance_query: Query<(&BlockInstance, &Transform)>,
-    mut random: ResMut<WorldRandom>,
-    markov_collection: Res<MarkovCollection>,
-    collections: Res<Assets<GlbCollections>>,
-    gltf_assets: Res<Assets<Gltf>>,
-    gltf_mesh_assets: Res<Assets<GltfMesh>>,
-    gltf_node_assets: Res<Assets<GltfNode>>,
-    mesh_assets: Res<Assets<Mesh>>,
-    rapier_context: Res<RapierContext>,
-    mut island_query: Query<&mut Island>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    mut progress: ResMut<GenerationProgress>,
+    budget: Res<GenerationBudget>,
+    biome_map: Res<BiomeMap>,
+    block_assets: BlockAssets,
+    mut island_query: Query<(Entity, &mut Island)>,
 ) {
-    for mut island in island_query.iter_mut() {
-        if island.children.len() > island.size {
-            return;
+    let _span = info_span!("grow_islands").entered();
+    let start = Instant::now();
+    let mut attempts = 0;
+
+    'grow: loop {
+        let mut growing = false;
+
+        for (island_entity, mut island) in island_query.iter_mut() {
+            if attempts >= budget.attempts_per_frame || start.elapsed() > budget.max_frame_time {
+                break 'grow;
+            }
+            // left alone so only real edits mark a finished island as changed
+            if island.finished {
+                continue;
+            }
+
+            let attempted = step_island(
+                &mut commands,
+                &mut spatial_index,
+                &mut progress,
+                &biome_map,
+                &block_assets,
+                island_entity,
+                &mut island,
+                None,
+            );
+            if attempted {
+                growing = true;
+                attempts += 1;
+            }
         }
 
-        let collection = collections
-            .get(&markov_collection.collections_handle)
-            .unwrap();
-        let mut transforms: Vec<(&BlockInstance, &Transform)> =
-            block_instance_query.iter().collect();
+        if !growing {
+            break;
+        }
+    }
+    progress.grow_time = start.elapsed();
+}
 
-        if let Some((block_instance, transform)) = transforms.choose_mut(&mut random.0) {
-            // given the transform and the block_instance, what is likely to be the next block_instance?
+/// Makes one placement attempt on an island that is still growing. A stalled island first
+/// takes back its newest blocks, is regrown from a new seed once that stops helping, and is
+/// despawned if it still can't be finished. Returns false if the island is done growing.
+pub fn step_island(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    progress: &mut GenerationProgress,
+    biome_map: &BiomeMap,
+    block_assets: &BlockAssets,
+    island_entity: Entity,
+    island: &mut Island,
+    step: Option<&mut GrowthStep>,
+) -> bool {
+    if island.abandoned {
+        return false;
+    }
+    let Some(collection) = block_assets.collection(&island.pack) else {
+        return false;
+    };
+    if island.is_complete(collection) {
+        island.finished = true;
+        return false;
+    }
 
-            let next_transforms = &collection.0.get(&block_instance.name).unwrap().transforms;
-            let next_transform = next_transforms.choose(&mut random.0).unwrap();
+    if island.failed_attempts > MAX_FAILED_ATTEMPTS {
+        let removed = if island.backtracks < MAX_BACKTRACKS {
+            let count = BACKTRACK_BLOCKS * (island.backtracks + 1);
+            debug!(
+                "Island stalled at {}/{} blocks, backtracking {}",
+                island.blocks.len(),
+                island.size,
+                count
+            );
+            island.backtrack(commands, spatial_index, block_assets, count)
+        } else if island.regenerations >= MAX_REGENERATIONS {
+            // an island without its landmarks or required blocks isn't one, drop it
+            warn!(
+                "Island at {} still missing {:?} after {} regrowths, removing it",
+                island.bounds.center(),
+                island.missing_required(collection),
+                island.regenerations
+            );
+            let removed = island.abandon(commands, spatial_index);
+            commands.entity(island_entity).despawn_recursive();
+            progress.blocks_placed = progress.blocks_placed.saturating_sub(removed);
+            return false;
+        } else {
+            info!(
+                "Island stalled at {}/{} blocks missing {:?}, regenerating (attempt {})",
+                island.blocks.len(),
+                island.size,
+                island.missing_required(collection),
+                island.regenerations + 1
+            );
+            island.regenerate(commands, spatial_index, block_assets, island_entity)
+        };
+        progress.blocks_placed = progress.blocks_placed.saturating_sub(removed);
+        return true;
+    }
+
+    let grown = grow_island(
+        commands,
+        spatial_index,
+        biome_map,
+        block_assets,
+        island_entity,
+        island,
+        step,
+    );
+    match grown {
+        Ok(()) => progress.blocks_placed += 1,
+        Err(Some(reason)) => {
+            progress.record_rejection(reason);
+            island.failed_attempts += 1;
+        }
+        // no open slot left to try, the island is stuck as it is
+        Err(None) => island.failed_attempts = MAX_FAILED_ATTEMPTS + 1,
+    }
+    true
+}
+
+/// Why a transition couldn't be placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// the block's min/max counts, or the island being full, rule it out
+    RuleLimit,
+    /// a bridge into a pack whose biome isn't under the block
+    BiomeMismatch,
+    /// the block would land outside the island's shape
+    OutsideShape,
+    OutOfBounds,
+    /// above the island's height or storey limit
+    TooHigh,
+    Overlap,
+    /// nothing underneath to rest on
+    Unsupported,
+    /// out of the player's jumping reach
+    Unreachable,
+    /// the slot's parent block or transition has gone since it was opened
+    StaleSlot,
+}
 
-            let xform = Transform::from_matrix(Mat4::from_cols_array_2d(&next_transform.1 .0));
-            let xform = transform.clone() * xform;
+impl RejectReason {
+    pub const ALL: [RejectReason; 9] = [
+        RejectReason::RuleLimit,
+        RejectReason::BiomeMismatch,
+        RejectReason::OutsideShape,
+        RejectReason::OutOfBounds,
+        RejectReason::TooHigh,
+        RejectReason::Overlap,
+        RejectReason::Unsupported,
+        RejectReason::Unreachable,
+        RejectReason::StaleSlot,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RejectReason::RuleLimit => "rule_limit",
+            RejectReason::BiomeMismatch => "biome_mismatch",
+            RejectReason::OutsideShape => "outside_shape",
+            RejectReason::OutOfBounds => "out_of_bounds",
+            RejectReason::TooHigh => "too_high",
+            RejectReason::Overlap => "overlap",
+            RejectReason::Unsupported => "unsupported",
+            RejectReason::Unreachable => "unreachable",
+            RejectReason::StaleSlot => "stale_slot",
+        }
+    }
+}
 
-            let gltf_handle = markov_collection.glb_assets.get(&next_transform.0).unwrap();
-            let gltf = gltf_assets.get(gltf_handle).unwrap();
-            let scene = gltf.scenes.first().unwrap().clone();
+/// A transition out of the parent block and whether it would fit.
+pub struct Candidate {
+    pub to: BlockId,
+    pub transform: Transform,
+    pub footprint: Vec<Obb>,
+    pub weight: f32,
+    pub rejected: Option<RejectReason>,
+}
 
-            let can_place = {
-                let aabb = collection.0.get(&next_transform.0).unwrap().aabb;
+/// What a single placement attempt looked at, filled in for the generation debugger.
+#[derive(Default)]
+pub struct GrowthStep {
+    pub parent: Option<(BlockId, Transform)>,
+    pub candidates: Vec<Candidate>,
+    /// index into `candidates` of the transition that was picked
+    pub chosen: Option<usize>,
+}
 
-                let mut shape_pos = xform.translation.clone();
+/// Makes one attempt at placing a block in an open slot of the island. Fails with the reason
+/// the slot didn't fit, or with `None` when no open slot is left.
+fn grow_island(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    biome_map: &BiomeMap,
+    block_assets: &BlockAssets,
+    island_entity: Entity,
+    island: &mut Island,
+    step: Option<&mut GrowthStep>,
+) -> Result<(), Option<RejectReason>> {
+    // slots the rules allow, favouring the ones close to the island's centre
+    let centroid = island.centroid().xz();
+    let open: Vec<usize> = (0..island.frontier.len())
+        .filter(|i| {
+            let to = &island.frontier[*i].to;
+            block_assets
+                .block(to)
+                .is_some_and(|data| island.allows(to, data))
+        })
+        .collect();
+    let weights: Vec<f32> = open
+        .iter()
+        .map(|i| {
+            let slot = &island.frontier[*i];
+            let distance = slot.position.xz().distance(centroid) / COMPACT_RADIUS;
+            slot.weight / (1.0 + distance * distance)
+        })
+        .collect();
+    let Ok(choice) = (0..open.len())
+        .collect::<Vec<usize>>()
+        .choose_weighted(&mut island.rng, |i| weights[*i])
+        .copied()
+    else {
+        return Err(None);
+    };
+    // a tried slot is closed whether it fits or not, backtracking reopens it
+    let slot = island.frontier.swap_remove(open[choice]);
 
-                shape_pos += Vec3::new(aabb.center[0], aabb.center[1], aabb.center[2]);
+    let Some(parent) = island
+        .blocks
+        .iter()
+        .find(|block| block.entity == slot.parent)
+    else {
+        return Err(Some(RejectReason::StaleSlot));
+    };
+    let (parent_id, parent_transform) = (parent.id.clone(), parent.transform);
 
-                let shape_rot = xform.rotation.clone();
+    let transitions = block_assets.transitions(&parent_id);
+    // the catalog may have been edited since the slot was opened
+    let Some(transition) = transitions
+        .get(slot.transition)
+        .filter(|transition| transition.to == slot.to)
+    else {
+        return Err(Some(RejectReason::StaleSlot));
+    };
 
-                let tolerance = 0.05;
-                let hx = aabb.half_extents[0] - tolerance;
-                let hy = aabb.half_extents[1] - tolerance; // y and z are swapped
-                let hz = aabb.half_extents[2] - tolerance;
+    let check = |transition: &Transition| {
+        check_transition(
+            spatial_index,
+            biome_map,
+            block_assets,
+            island,
+            &parent_id,
+            &parent_transform,
+            transition,
+        )
+    };
+    // the debugger sees every transition out of the parent, not just the slot's
+    if let Some(step) = step {
+        step.parent = Some((parent_id.clone(), parent_transform));
+        step.candidates = transitions.iter().map(check).collect();
+        step.chosen = Some(slot.transition);
+    }
+    let next = check(transition);
+    if let Some(reason) = next.rejected {
+        return Err(Some(reason));
+    }
 
-                let shape = Collider::cuboid(hx, hy, hz);
-                let filter = QueryFilter::default();
+    let entity = block_assets.spawn_block(
+        commands,
+        spatial_index,
+        &next.to,
+        next.transform,
+        island_entity,
+    );
+    spatial_index.set_parent(entity, slot.parent);
+    island.add_block(&next.to, entity, next.transform);
+    island.storeys = island
+        .storeys
+        .max(island.vertical.storey(&next.footprint) + 1);
+    island.open_slots(block_assets, island.blocks.len() - 1);
+    Ok(())
+}
 
-                let mut can_place = true;
-                rapier_context.intersections_with_shape(
-                    shape_pos,
-                    shape_rot,
-                    &shape,
-                    filter,
-                    |entity| {
-                        // println!("The entity {:?} intersects our shape.", entity);
-                        can_place = false;
-                        false
-                    },
-                );
+/// Runs a transition out of a placed block through the island's rules, the checks every block
+/// the generator or the player places has to pass.
+pub fn check_transition(
+    spatial_index: &BlockSpatialIndex,
+    biome_map: &BiomeMap,
+    block_assets: &BlockAssets,
+    island: &Island,
+    parent_id: &BlockId,
+    parent_transform: &Transform,
+    transition: &Transition,
+) -> Candidate {
+    let next_id = &transition.to;
+    let transform = *parent_transform * transition.matrix.to_transform();
+    let footprint = block_assets.footprint(next_id, &transform);
+    let shape = island.shape_at(transform.translation);
 
-                can_place
-            };
-
-            if can_place {
-                let mut entity = commands.spawn((
-                    SceneBundle {
-                        scene,
-                        transform: xform.clone(),
-                        ..Default::default()
-                    },
-                    BlockInstance {
-                        name: next_transform.0.clone(),
-                    },
-                    RigidBody::Fixed,
-                    BlockInstanceCollider,
-                ));
-
-                entity.with_children(|children| {
-                    for node in &gltf.nodes {
-                        let node = gltf_node_assets.get(node).unwrap();
-                        for bundle in
-                            spawn_collider_from_gltf_node(node, &gltf_mesh_assets, &mesh_assets)
-                        {
-                            children.spawn(bundle);
-                        }
-                    }
-                });
+    let rejected = if !block_assets
+        .block(next_id)
+        .is_some_and(|data| island.allows(next_id, data))
+    {
+        Some(RejectReason::RuleLimit)
+    } else if next_id.pack != parent_id.pack
+        && biome_map.pack_at(transform.translation) != next_id.pack
+    {
+        // bridges into another pack are only taken where the biome map hands over to that pack
+        Some(RejectReason::BiomeMismatch)
+    } else if shape <= 0.0 {
+        Some(RejectReason::OutsideShape)
+    } else if !footprint.iter().all(|obb| island.contains(obb)) {
+        Some(RejectReason::OutOfBounds)
+    } else if !island.vertical.fits(&footprint) {
+        Some(RejectReason::TooHigh)
+    } else if !can_place(spatial_index, &footprint) {
+        Some(RejectReason::Overlap)
+    } else if island.vertical.must_be_supported && !is_supported(spatial_index, &footprint) {
+        Some(RejectReason::Unsupported)
+    } else if island
+        .vertical
+        .reach
+        .is_some_and(|reach| !reach.can_reach(spatial_index, &footprint))
+    {
+        Some(RejectReason::Unreachable)
+    } else {
+        None
+    };
 
-                island.children.push(entity.id());
-            }
-        }
+    Candidate {
+        to: next_id.clone(),
+        transform,
+        footprint,
+        weight: transition.weight * shape,
+        rejected,
     }
 }
 
+/// Whether a block with this footprint would stay clear of every placed block.
+pub fn can_place(spatial_index: &BlockSpatialIndex, footprint: &[Obb]) -> bool {
+    // shrink the boxes a little so blocks that only touch their neighbours still fit
+    let tolerance = 0.05;
+    footprint
+        .iter()
+        .all(|obb| !spatial_index.any_overlapping(&obb.shrunk(tolerance)))
+}
+
 #[derive(Component)]
 pub struct BlockInstanceCollider;
+
+/// Marks the root of a placed prefab, its parts are child `BlockInstance`s.
+#[derive(Component)]
+pub struct Prefab;
+
+/// The plain blocks under a placed prefab, in the order of `BlockAssets::leaf_blocks`.
+pub fn prefab_leaves(
+    entity: Entity,
+    children_query: &Query<&Children>,
+    prefab_query: &Query<(), With<Prefab>>,
+) -> Vec<Entity> {
+    if !prefab_query.contains(entity) {
+        return vec![entity];
+    }
+    children_query
+        .get(entity)
+        .map(|children| {
+            children
+                .iter()
+                .flat_map(|child| prefab_leaves(*child, children_query, prefab_query))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::world::deserialize::DEFAULT_PACK;
+
+    // blocks A, B and C, `rules` gives each one's rules as JSON
+    fn collection(rules: [&str; 3]) -> GlbCollections {
+        let block = |rules: &str| {
+            format!(
+                r#"{{"file": "x.glb", "aabb": {{"center": [0,0,0], "half_extents": [1,1,1]}}, "transforms": [], "rules": {}}}"#,
+                rules
+            )
+        };
+        serde_json::from_str(&format!(
+            r#"{{"A": {}, "B": {}, "C": {}}}"#,
+            block(rules[0]),
+            block(rules[1]),
+            block(rules[2]),
+        ))
+        .unwrap()
+    }
+
+    fn id(name: &str) -> BlockId {
+        BlockId::new(DEFAULT_PACK, name)
+    }
+
+    fn island(size: usize) -> Island {
+        Island::new(
+            size,
+            id("A"),
+            StdRng::seed_from_u64(0),
+            Rect::new(-10.0, -10.0, 10.0, 10.0),
+            IslandShape::Free,
+            VerticalRules {
+                max_height: 30.0,
+                storey_height: 6.0,
+                min_storeys: 1,
+                max_storeys: 3,
+                must_be_supported: false,
+                reach: None,
+            },
+        )
+    }
+
+    fn add(island: &mut Island, name: &str) {
+        let entity = Entity::from_raw(island.blocks.len() as u32);
+        island.add_block(&id(name), entity, Transform::IDENTITY);
+    }
+
+    #[test]
+    fn max_count_stops_a_block() {
+        let collection = collection([r#"{}"#, r#"{"max_count": 2}"#, r#"{}"#]);
+        let b = &collection.0["B"];
+        let mut island = island(10);
+        add(&mut island, "A");
+
+        assert!(island.allows(&id("B"), b));
+        add(&mut island, "B");
+        assert!(island.allows(&id("B"), b));
+        add(&mut island, "B");
+        assert!(!island.allows(&id("B"), b));
+        assert!(island.allows(&id("C"), &collection.0["C"]));
+    }
+
+    #[test]
+    fn landmarks_are_required_once() {
+        let collection = collection([r#"{}"#, r#"{"landmark": true}"#, r#"{"min_count": 2}"#]);
+        let mut island = island(2);
+        add(&mut island, "A");
+        let mut missing = island.missing_required(&collection);
+        missing.sort_by_key(|id| id.name.clone());
+        assert_eq!(missing, vec![id("B"), id("C")]);
+
+        add(&mut island, "B");
+        assert_eq!(island.missing_required(&collection), vec![id("C")]);
+        assert!(!island.allows(&id("B"), &collection.0["B"]));
+        assert!(!island.is_complete(&collection));
+
+        // full, but still allowed to grow towards the blocks it needs
+        assert!(!island.allows(&id("A"), &collection.0["A"]));
+        assert!(island.allows(&id("C"), &collection.0["C"]));
+        add(&mut island, "C");
+        add(&mut island, "C");
+        assert!(!island.allows(&id("C"), &collection.0["C"]));
+        assert!(island.is_complete(&collection));
+    }
+
+    #[test]
+    fn taking_the_newest_blocks_keeps_the_seed() {
+        let mut island = island(10);
+        add(&mut island, "A");
+        add(&mut island, "B");
+        add(&mut island, "B");
+
+        let removed = island.take_newest(2);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(island.count(&id("B")), 0);
+
+        assert!(island.take_newest(5).is_empty());
+        assert_eq!(island.blocks.len(), 1);
+        assert_eq!(island.blocks[0].id, id("A"));
+        assert_eq!(island.count(&id("A")), 1);
+    }
+}
@@ -1,15 +1,67 @@
-mod deserialize;
+pub mod analysis;
+pub mod bake;
+pub mod biome;
+pub mod building;
+pub mod checkpoints;
+pub mod chunks;
+pub mod debugger;
+pub mod deserialize;
+pub mod diagnostics;
+pub mod edits;
 mod exports;
+pub mod lod;
 pub mod markov;
+pub mod navmesh;
+mod noise;
+pub mod obb;
+pub mod progress;
+pub mod reachability;
+pub mod routes;
+pub mod shape;
+pub mod spatial;
+pub mod spawn;
+pub mod support;
+pub mod vertical;
+pub mod voxels;
 
+use bake::{bake_islands, unbake_islands, BakeSettings, UnbakeIsland};
 use bevy::{
     gltf::{Gltf, GltfMesh, GltfNode},
     prelude::*,
 };
 use bevy_common_assets::json::JsonAssetPlugin;
 use bevy_rapier3d::prelude::*;
-use deserialize::{setup_markov, GlbCollections};
-use markov::{add_blocks_to_island, add_island, post_generate, WorldRandom, WorldState};
+use biome::{apply_biome_map, biome_map_applied, load_biome_map, BiomeMap};
+use building::{setup_ghost_assets, toggle_build_mode, update_build_mode, BuildMode};
+use checkpoints::{
+    activate_checkpoints, despawn_orphan_checkpoints, draw_checkpoints, place_checkpoints,
+    ActiveCheckpoint,
+};
+use chunks::{setup_ground, update_chunks, ChunkSettings, LoadedChunks, SeedOverride, WorldSeed};
+use debugger::{
+    draw_generation_debugger, generation_debugger_inactive, step_generation,
+    toggle_generation_debugger, update_debugger_panel, GenerationDebugger,
+};
+use deserialize::{load_pack_glbs, setup_markov, BridgeTable, GlbCollections, MarkovCollection};
+use diagnostics::GenerationDiagnosticsPlugin;
+use edits::{apply_world_edits, load_world_edits, WorldEdits};
+use lod::{setup_proxy_material, update_island_lod, LodSettings};
+use markov::add_blocks_to_island;
+use navmesh::{update_navmesh, NavMesh, NavMeshSettings};
+use progress::{track_generation_progress, GenerationBudget, GenerationProgress};
+use reachability::{
+    check_reachability, draw_reachability, measure_movement_limits, toggle_reachability_overlay,
+    Reachability,
+};
+use routes::{connect_islands, remove_stranded_bridges, IslandGraph};
+use shape::{add_loaded_shape_masks, load_shape_masks, IslandShapes, ShapeMaskHandles};
+use spatial::{prune_spatial_index, BlockSpatialIndex};
+use spawn::{choose_spawn_point, SpawnPoint};
+use support::{destroy_blocks, destroy_targeted_block, settle_debris, DestroyBlock};
+use vertical::VerticalSettings;
+use voxels::{update_voxel_grid, write_voxel_grid, VoxelExport, VoxelGrid, VoxelSettings};
+
+use crate::loading::AppState;
 
 pub struct WorldPlugin;
 
@@ -18,14 +70,164 @@ impl Plugin for WorldPlugin {
         app.add_plugins(JsonAssetPlugin::<GlbCollections>::new(&[
             "exports/data.json",
         ]))
-        .init_state::<WorldState>()
-        .init_resource::<WorldRandom>()
+        .add_plugins(JsonAssetPlugin::<BridgeTable>::new(&["bridges.json"]))
+        .add_plugins(JsonAssetPlugin::<BiomeMap>::new(&["biomes.json"]))
+        .add_plugins(GenerationDiagnosticsPlugin)
+        .init_resource::<WorldSeed>()
+        .init_resource::<SeedOverride>()
+        .init_resource::<ChunkSettings>()
+        .init_resource::<LoadedChunks>()
+        .init_resource::<BiomeMap>()
+        .init_resource::<MarkovCollection>()
+        .init_resource::<IslandShapes>()
+        .init_resource::<VerticalSettings>()
+        .init_resource::<ShapeMaskHandles>()
+        .init_resource::<BlockSpatialIndex>()
+        .init_resource::<GenerationBudget>()
+        .init_resource::<GenerationProgress>()
+        .init_resource::<LodSettings>()
+        .init_resource::<BakeSettings>()
+        .init_resource::<WorldEdits>()
+        .init_resource::<BuildMode>()
+        .init_resource::<GenerationDebugger>()
+        .init_resource::<IslandGraph>()
+        .init_resource::<Reachability>()
+        .init_resource::<NavMeshSettings>()
+        .init_resource::<NavMesh>()
+        .init_resource::<VoxelSettings>()
+        .init_resource::<VoxelGrid>()
+        .init_resource::<VoxelExport>()
+        .init_resource::<SpawnPoint>()
+        .init_resource::<ActiveCheckpoint>()
+        .add_event::<UnbakeIsland>()
+        .add_event::<DestroyBlock>()
         // .add_systems(Startup, load_scene)
-        .add_systems(Startup, setup_markov)
-        .add_systems(Startup, setup_ground)
-        .add_systems(Update, add_island.run_if(in_state(WorldState::Generating)))
-        .add_systems(Update, add_blocks_to_island);
-        // .add_systems(OnExit(WorldState::Generating), post_generate)
+        .add_systems(Startup, load_biome_map)
+        .add_systems(
+            Update,
+            (apply_biome_map, setup_markov.run_if(biome_map_applied))
+                .chain()
+                .run_if(in_state(AppState::Loading)),
+        )
+        .add_systems(
+            Startup,
+            (
+                load_world_edits.before(setup_ground),
+                setup_ground,
+                setup_proxy_material,
+                setup_ghost_assets,
+            ),
+        )
+        .add_systems(Startup, load_shape_masks)
+        .add_systems(Update, (load_pack_glbs, add_loaded_shape_masks))
+        .add_systems(
+            Update,
+            update_chunks
+                .before(add_blocks_to_island)
+                .run_if(not(in_state(AppState::Loading))),
+        )
+        .add_systems(Update, remove_stranded_bridges.after(update_chunks))
+        .add_systems(
+            Update,
+            add_blocks_to_island.run_if(generation_debugger_inactive),
+        )
+        .add_systems(
+            Update,
+            (
+                toggle_generation_debugger,
+                step_generation,
+                draw_generation_debugger,
+                update_debugger_panel,
+            )
+                .chain()
+                .after(add_blocks_to_island)
+                .before(bake_islands),
+        )
+        .add_systems(
+            Update,
+            connect_islands
+                .after(update_debugger_panel)
+                .before(bake_islands)
+                .before(apply_world_edits)
+                .before(track_generation_progress),
+        )
+        .add_systems(
+            Update,
+            track_generation_progress.after(add_blocks_to_island),
+        )
+        .add_systems(
+            Update,
+            (
+                place_checkpoints.before(connect_islands),
+                despawn_orphan_checkpoints,
+            ),
+        )
+        .add_systems(
+            Update,
+            (activate_checkpoints, draw_checkpoints)
+                .chain()
+                .run_if(in_state(AppState::Playing)),
+        )
+        .add_systems(
+            Update,
+            (bake_islands, unbake_islands)
+                .chain()
+                .after(add_blocks_to_island),
+        )
+        .add_systems(
+            Update,
+            apply_world_edits
+                .after(add_blocks_to_island)
+                .before(bake_islands)
+                .before(destroy_blocks),
+        )
+        .add_systems(
+            Update,
+            (toggle_build_mode, update_build_mode, destroy_targeted_block)
+                .chain()
+                .before(destroy_blocks)
+                .run_if(in_state(AppState::Playing)),
+        )
+        .add_systems(
+            Update,
+            (destroy_blocks, settle_debris)
+                .chain()
+                .before(unbake_islands),
+        )
+        .add_systems(
+            Update,
+            update_navmesh.after(connect_islands).after(settle_debris),
+        )
+        .add_systems(
+            Update,
+            update_voxel_grid
+                .after(connect_islands)
+                .after(apply_world_edits)
+                .after(settle_debris),
+        )
+        .add_systems(
+            OnExit(AppState::Generating),
+            (write_voxel_grid, choose_spawn_point),
+        )
+        .add_systems(Update, measure_movement_limits.before(update_chunks))
+        .add_systems(
+            Update,
+            (
+                toggle_reachability_overlay,
+                check_reachability,
+                draw_reachability,
+            )
+                .chain()
+                .after(connect_islands)
+                .run_if(in_state(AppState::Playing)),
+        )
+        .add_systems(
+            Update,
+            update_island_lod
+                .after(add_blocks_to_island)
+                .run_if(in_state(AppState::Playing)),
+        )
+        .add_systems(Last, prune_spatial_index);
         // .add_systems(Update, scene_colliders);
     }
 }
@@ -43,36 +245,6 @@ fn load_scene(mut commands: Commands, assets: Res<AssetServer>) {
     });
 }
 
-fn setup_ground(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) {
-    let ground_size = 500.0;
-    let ground_transform = Transform::from_xyz(0.0, -0.1, 0.0);
-    commands.spawn((
-        ground_transform.clone(), // Position the ground slightly below the origin
-        GlobalTransform::IDENTITY,
-        Collider::cuboid(ground_size, 0.1, ground_size), // Create a large plane collider
-        RigidBody::Fixed, // Make the collider fixed so it doesn't move
-    ));
-
-    // spawn in a mesh for the ground
-    let plane = Plane3d::new(Vec3::new(0., 1.0, 0.), Vec2::new(ground_size, ground_size));
-    let material = StandardMaterial {
-        base_color: Color::rgb(0.5, 0.5, 0.5),
-        ..Default::default()
-    };
-    // let mesh = Mesh::from(plane);
-    // let mesh_
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(plane),
-        material: materials.add(material),
-        transform: ground_transform,
-        ..Default::default()
-    });
-}
-
 fn scene_colliders(
     mut commands: Commands,
     mut main_scene: ResMut<MainScene>,
@@ -106,6 +278,20 @@ pub fn spawn_collider_from_gltf_node(
     gltf_node: &GltfNode,
     gltf_mesh_assets: &Assets<GltfMesh>,
     mesh_assets: &Assets<Mesh>,
+) -> Vec<Collider> {
+    colliders_from_gltf_node(
+        gltf_node,
+        gltf_mesh_assets,
+        mesh_assets,
+        &ComputedColliderShape::TriMesh,
+    )
+}
+
+pub fn colliders_from_gltf_node(
+    gltf_node: &GltfNode,
+    gltf_mesh_assets: &Assets<GltfMesh>,
+    mesh_assets: &Assets<Mesh>,
+    shape: &ComputedColliderShape,
 ) -> Vec<Collider> {
     let mut bundles: Vec<Collider> = Vec::new();
     if let Some(gltf_mesh) = gltf_node.mesh.clone() {
@@ -113,7 +299,9 @@ pub fn spawn_collider_from_gltf_node(
         for mesh_primitive in gltf_mesh.primitives.iter() {
             let mesh = mesh_assets.get(&mesh_primitive.mesh).unwrap();
 
-            bundles.push(Collider::from_bevy_mesh(mesh, &ComputedColliderShape::TriMesh).unwrap());
+            if let Some(collider) = Collider::from_bevy_mesh(mesh, shape) {
+                bundles.push(collider);
+            }
         }
     }
     bundles
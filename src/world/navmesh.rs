@@ -0,0 +1,850 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{
+    prelude::*,
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        render_resource::PrimitiveTopology,
+    },
+    utils::{HashMap, HashSet},
+};
+
+use super::bake::gltf_mesh_nodes;
+use super::chunks::{Chunk, ChunkSettings};
+use super::deserialize::BlockId;
+use super::markov::{BlockAssets, Island, PlacedBlock};
+use super::obb::Obb;
+use super::routes::{Bridge, Connected};
+use super::spatial::BlockSpatialIndex;
+
+// side of the cells triangles are looked up by
+const INDEX_CELL: f32 = 4.0;
+// edges of neighbouring triangles this far apart across are still joined, e.g. between blocks
+const LINK_GAP: f32 = 0.3;
+// shortest shared stretch of edge a path may cross through
+const MIN_PORTAL: f32 = 0.2;
+// a surface this close under a downward face has something resting on it
+const RESTING: f32 = 0.05;
+// tiles rebuilt per frame, the first build after generation touches them all
+const TILES_PER_FRAME: usize = 2;
+// triangles a single path query may look at
+const MAX_PATH_NODES: usize = 20_000;
+
+#[derive(Resource)]
+pub struct NavMeshSettings {
+    /// walkable triangles are split until no edge is longer, so their headroom is checked finely
+    pub max_edge: f32,
+    /// the ground is laid in squares this wide, halved around blocks down to `min_ground_cell`
+    pub ground_cell: f32,
+    pub min_ground_cell: f32,
+    /// free height needed above a surface to stand on it
+    pub agent_height: f32,
+    /// highest step walked up without jumping
+    pub step_height: f32,
+    /// steepest surface still walked on, in radians
+    pub max_slope: f32,
+}
+
+impl Default for NavMeshSettings {
+    fn default() -> Self {
+        NavMeshSettings {
+            max_edge: 1.0,
+            ground_cell: 16.0,
+            min_ground_cell: 0.25,
+            agent_height: 2.0,
+            step_height: 0.5,
+            max_slope: 40f32.to_radians(),
+        }
+    }
+}
+
+/// A triangle of the navmesh: the tile it belongs to and its index there.
+type PolyRef = (Entity, usize);
+
+/// Where a path can cross from one triangle into another.
+struct Link {
+    to: PolyRef,
+    /// the stretch of this triangle's edge facing the other one
+    portal: [Vec3; 2],
+}
+
+/// The walkable triangles of an island, a bridge or a chunk's ground, and how they join up.
+struct NavTile {
+    triangles: Vec<[Vec3; 3]>,
+    links: Vec<Vec<Link>>,
+}
+
+/// A navigation mesh over the world, made of the walkable top surfaces of the blocks and of the
+/// ground of the loaded chunks. Each island, bridge and chunk has its own tile, rebuilt when it
+/// changes, and tiles are joined where their edges meet within a step.
+#[derive(Resource, Default)]
+pub struct NavMesh {
+    tiles: HashMap<Entity, NavTile>,
+    /// the triangles reaching into each cell
+    cells: HashMap<IVec2, Vec<PolyRef>>,
+    /// islands and bridges whose tiles are out of date
+    pending: HashSet<Entity>,
+    /// chunks whose ground is out of date
+    pending_ground: HashSet<IVec2>,
+}
+
+impl NavMesh {
+    /// Adds a tile, replacing the one `entity` had, and joins it to its neighbours.
+    fn insert_tile(
+        &mut self,
+        settings: &NavMeshSettings,
+        entity: Entity,
+        triangles: Vec<[Vec3; 3]>,
+    ) {
+        self.remove_tile(entity);
+
+        let mut own_cells: HashMap<IVec2, Vec<usize>> = HashMap::new();
+        for (index, triangle) in triangles.iter().enumerate() {
+            for cell in index_cells(triangle, 0.0) {
+                own_cells.entry(cell).or_default().push(index);
+            }
+        }
+
+        let mut links: Vec<Vec<Link>> = triangles.iter().map(|_| Vec::new()).collect();
+        let mut back_links: Vec<(PolyRef, Link)> = Vec::new();
+        for (index, triangle) in triangles.iter().enumerate() {
+            let cells: Vec<IVec2> = index_cells(triangle, LINK_GAP).collect();
+            let mut candidates: Vec<PolyRef> = cells
+                .iter()
+                .filter_map(|cell| self.cells.get(cell))
+                .flatten()
+                .copied()
+                .chain(
+                    cells
+                        .iter()
+                        .filter_map(|cell| own_cells.get(cell))
+                        .flatten()
+                        .map(|other| (entity, *other)),
+                )
+                .collect();
+            candidates.sort();
+            candidates.dedup();
+
+            for to in candidates {
+                if to == (entity, index) {
+                    continue;
+                }
+                let other = match self.tiles.get(&to.0) {
+                    Some(tile) => &tile.triangles[to.1],
+                    None => &triangles[to.1],
+                };
+                if let Some(portal) = portal(triangle, other, settings.step_height) {
+                    links[index].push(Link { to, portal });
+                }
+                // the other tile is already in, so its side of the link is added here too
+                if to.0 != entity {
+                    if let Some(portal) = portal(other, triangle, settings.step_height) {
+                        back_links.push((
+                            to,
+                            Link {
+                                to: (entity, index),
+                                portal,
+                            },
+                        ));
+                    }
+                }
+            }
+        }
+
+        for (from, link) in back_links {
+            self.tiles.get_mut(&from.0).unwrap().links[from.1].push(link);
+        }
+        for (cell, indices) in own_cells {
+            let polys = self.cells.entry(cell).or_default();
+            polys.extend(indices.into_iter().map(|index| (entity, index)));
+        }
+        self.tiles.insert(entity, NavTile { triangles, links });
+    }
+
+    /// Drops `entity`'s tile and the links into it, returning the tile.
+    fn remove_tile(&mut self, entity: Entity) -> Option<NavTile> {
+        let tile = self.tiles.remove(&entity)?;
+        let neighbours: HashSet<Entity> = tile
+            .links
+            .iter()
+            .flatten()
+            .map(|link| link.to.0)
+            .filter(|other| *other != entity)
+            .collect();
+        for other in neighbours {
+            if let Some(other) = self.tiles.get_mut(&other) {
+                for links in &mut other.links {
+                    links.retain(|link| link.to.0 != entity);
+                }
+            }
+        }
+        for triangle in &tile.triangles {
+            for cell in index_cells(triangle, 0.0) {
+                if let Some(polys) = self.cells.get_mut(&cell) {
+                    polys.retain(|(other, _)| *other != entity);
+                    if polys.is_empty() {
+                        self.cells.remove(&cell);
+                    }
+                }
+            }
+        }
+        Some(tile)
+    }
+
+    fn triangle(&self, poly: PolyRef) -> &[Vec3; 3] {
+        &self.tiles[&poly.0].triangles[poly.1]
+    }
+
+    /// The triangle the player would stand on at `point`, the highest no more than a step above
+    /// it, and the point on it. None where nothing walkable is known, e.g. in unloaded chunks.
+    fn locate(&self, settings: &NavMeshSettings, point: Vec3) -> Option<(PolyRef, Vec3)> {
+        let cell = (point.xz() / INDEX_CELL).floor().as_ivec2();
+        self.cells
+            .get(&cell)?
+            .iter()
+            .filter_map(|poly| {
+                let height = height_at(self.triangle(*poly), point.xz())?;
+                (height <= point.y + settings.step_height)
+                    .then_some((*poly, Vec3::new(point.x, height, point.z)))
+            })
+            .max_by(|a, b| a.1.y.total_cmp(&b.1.y))
+    }
+
+    /// Where the player would stand at `point`, None if there's nothing walkable under it.
+    pub fn surface_at(&self, settings: &NavMeshSettings, point: Vec3) -> Option<Vec3> {
+        self.locate(settings, point).map(|(_, surface)| surface)
+    }
+
+    /// A walkable route from `start` to `goal` as a list of waypoints, None if there is none.
+    /// It only crosses between triangles that meet within a step, and never leaves the surfaces
+    /// gentle enough to walk on.
+    pub fn find_path(
+        &self,
+        settings: &NavMeshSettings,
+        start: Vec3,
+        goal: Vec3,
+    ) -> Option<Vec<Vec3>> {
+        let (start_poly, start) = self.locate(settings, start)?;
+        let (goal_poly, goal) = self.locate(settings, goal)?;
+
+        struct PathNode {
+            poly: PolyRef,
+            point: Vec3,
+            cost: f32,
+            /// the node this one was reached from, and the portal crossed
+            parent: Option<(usize, [Vec3; 2])>,
+        }
+        let mut nodes = vec![PathNode {
+            poly: start_poly,
+            point: start,
+            cost: 0.0,
+            parent: None,
+        }];
+        let mut best: HashMap<PolyRef, f32> = HashMap::new();
+        best.insert(start_poly, 0.0);
+        // costs are kept in centimetres so the heap can order them
+        let mut open = BinaryHeap::new();
+        open.push(Reverse(((start.distance(goal) * 100.0) as u32, 0)));
+
+        while let Some(Reverse((_, current))) = open.pop() {
+            let (poly, point, cost) = (
+                nodes[current].poly,
+                nodes[current].point,
+                nodes[current].cost,
+            );
+            if best.get(&poly).is_some_and(|known| *known < cost) {
+                continue;
+            }
+            if poly == goal_poly {
+                let mut portals = Vec::new();
+                let mut node = current;
+                while let Some((parent, portal)) = nodes[node].parent {
+                    let from = centroid(self.triangle(nodes[parent].poly));
+                    let to = centroid(self.triangle(nodes[node].poly));
+                    portals.push(left_and_right(portal, from, to));
+                    node = parent;
+                }
+                portals.reverse();
+                return Some(string_pull(start, goal, &portals));
+            }
+            if nodes.len() > MAX_PATH_NODES {
+                break;
+            }
+
+            for link in &self.tiles[&poly.0].links[poly.1] {
+                let next = crossing(link.portal, point, goal);
+                let next_cost = cost + point.distance(next);
+                if best.get(&link.to).is_some_and(|known| *known <= next_cost) {
+                    continue;
+                }
+                best.insert(link.to, next_cost);
+                nodes.push(PathNode {
+                    poly: link.to,
+                    point: next,
+                    cost: next_cost,
+                    parent: Some((current, link.portal)),
+                });
+                let estimate = next_cost + next.distance(goal);
+                open.push(Reverse(((estimate * 100.0) as u32, nodes.len() - 1)));
+            }
+        }
+        None
+    }
+}
+
+/// The index cells a triangle's box reaches into, grown by `margin`.
+fn index_cells(triangle: &[Vec3; 3], margin: f32) -> impl Iterator<Item = IVec2> {
+    let min = triangle[0].xz().min(triangle[1].xz()).min(triangle[2].xz()) - Vec2::splat(margin);
+    let max = triangle[0].xz().max(triangle[1].xz()).max(triangle[2].xz()) + Vec2::splat(margin);
+    let (min, max) = (
+        (min / INDEX_CELL).floor().as_ivec2(),
+        (max / INDEX_CELL).floor().as_ivec2(),
+    );
+    (min.x..=max.x).flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+}
+
+fn centroid(triangle: &[Vec3; 3]) -> Vec3 {
+    (triangle[0] + triangle[1] + triangle[2]) / 3.0
+}
+
+fn normal(triangle: &[Vec3; 3]) -> Vec3 {
+    (triangle[1] - triangle[0])
+        .cross(triangle[2] - triangle[0])
+        .normalize_or_zero()
+}
+
+/// Height of a triangle above `point`, None if the point isn't over it.
+fn height_at(triangle: &[Vec3; 3], point: Vec2) -> Option<f32> {
+    let [a, b, c] = triangle.map(|vertex| vertex.xz());
+    let area = (b - a).perp_dot(c - a);
+    if area.abs() < f32::EPSILON {
+        return None;
+    }
+    let u = (c - b).perp_dot(point - b) / area;
+    let v = (a - c).perp_dot(point - c) / area;
+    let w = 1.0 - u - v;
+    (u >= 0.0 && v >= 0.0 && w >= 0.0)
+        .then(|| triangle[0].y * u + triangle[1].y * v + triangle[2].y * w)
+}
+
+/// Where a path can cross from triangle `a` into `b`: the stretch of an edge of `a` lying along
+/// an edge of `b`, no more than `LINK_GAP` across and a step up or down from it.
+fn portal(a: &[Vec3; 3], b: &[Vec3; 3], step_height: f32) -> Option<[Vec3; 2]> {
+    for i in 0..3 {
+        let (a0, a1) = (a[i], a[(i + 1) % 3]);
+        let length = a0.xz().distance(a1.xz());
+        if length < MIN_PORTAL {
+            continue;
+        }
+        let along = (a1.xz() - a0.xz()) / length;
+        let height_on_a = |t: f32| a0.y + (a1.y - a0.y) * t / length;
+
+        for j in 0..3 {
+            let (b0, b1) = (b[j], b[(j + 1) % 3]);
+            let offset = |point: Vec3| point.xz() - a0.xz();
+            if along.perp_dot(offset(b0)).abs() > LINK_GAP
+                || along.perp_dot(offset(b1)).abs() > LINK_GAP
+            {
+                continue;
+            }
+            let (t0, t1) = (along.dot(offset(b0)), along.dot(offset(b1)));
+            let (low, high) = (t0.min(t1).max(0.0), t0.max(t1).min(length));
+            if high - low < MIN_PORTAL {
+                continue;
+            }
+            let height_on_b = |t: f32| b0.y + (b1.y - b0.y) * (t - t0) / (t1 - t0);
+            if (height_on_a(low) - height_on_b(low)).abs() > step_height
+                || (height_on_a(high) - height_on_b(high)).abs() > step_height
+            {
+                continue;
+            }
+            return Some([a0.lerp(a1, low / length), a0.lerp(a1, high / length)]);
+        }
+    }
+    None
+}
+
+/// Where a portal is crossed heading from `from` straight for `to`, or its nearer end if that
+/// line misses it.
+fn crossing(portal: [Vec3; 2], from: Vec3, to: Vec3) -> Vec3 {
+    let side = |point: Vec3| (to - from).xz().perp_dot((point - from).xz());
+    let (side0, side1) = (side(portal[0]), side(portal[1]));
+    let t = if side0 == side1 {
+        0.5
+    } else {
+        (side0 / (side0 - side1)).clamp(0.0, 1.0)
+    };
+    portal[0].lerp(portal[1], t)
+}
+
+/// A portal's ends ordered as (left, right) for a path going from `from` to `to`.
+fn left_and_right(portal: [Vec3; 2], from: Vec3, to: Vec3) -> (Vec3, Vec3) {
+    let middle = (portal[0] + portal[1]) / 2.0;
+    if (to - from).xz().perp_dot((portal[0] - middle).xz()) > 0.0 {
+        (portal[0], portal[1])
+    } else {
+        (portal[1], portal[0])
+    }
+}
+
+/// Pulls a path taut through the portals it crosses, keeping only the corners it bends round.
+fn string_pull(start: Vec3, goal: Vec3, portals: &[(Vec3, Vec3)]) -> Vec<Vec3> {
+    // positive when `c` lies to the left of the line from `a` through `b`
+    let side = |a: Vec3, b: Vec3, c: Vec3| (b - a).xz().perp_dot((c - a).xz());
+
+    let mut portals = portals.to_vec();
+    portals.insert(0, (start, start));
+    portals.push((goal, goal));
+
+    let mut path = vec![start];
+    let (mut apex, mut left, mut right) = (start, start, start);
+    let (mut left_index, mut right_index) = (0, 0);
+    let mut i = 1;
+    while i < portals.len() {
+        let (next_left, next_right) = portals[i];
+
+        // narrow the funnel from the right, unless that crosses its left side
+        if side(apex, right, next_right) >= 0.0 {
+            if apex == right || side(apex, left, next_right) <= 0.0 {
+                right = next_right;
+                right_index = i;
+            } else {
+                if path.last() != Some(&left) {
+                    path.push(left);
+                }
+                apex = left;
+                right = apex;
+                right_index = left_index;
+                i = left_index + 1;
+                continue;
+            }
+        }
+
+        if side(apex, left, next_left) <= 0.0 {
+            if apex == left || side(apex, right, next_left) >= 0.0 {
+                left = next_left;
+                left_index = i;
+            } else {
+                if path.last() != Some(&right) {
+                    path.push(right);
+                }
+                apex = right;
+                left = apex;
+                left_index = right_index;
+                i = right_index + 1;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    if path.last() != Some(&goal) {
+        path.push(goal);
+    }
+    path
+}
+
+/// Splits a triangle in four until none of its edges is longer than `max_edge`.
+fn subdivide(triangle: [Vec3; 3], max_edge: f32, out: &mut Vec<[Vec3; 3]>) {
+    let [a, b, c] = triangle;
+    let longest = a.distance(b).max(b.distance(c)).max(c.distance(a));
+    if longest <= max_edge {
+        out.push(triangle);
+        return;
+    }
+    let (ab, bc, ca) = ((a + b) / 2.0, (b + c) / 2.0, (c + a) / 2.0);
+    for part in [[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]] {
+        subdivide(part, max_edge, out);
+    }
+}
+
+/// The walkable top surfaces among a tile's triangles: gentle enough to stand on, with nothing
+/// else of the tile lower than the agent above them.
+fn walkable_triangles(settings: &NavMeshSettings, triangles: &[[Vec3; 3]]) -> Vec<[Vec3; 3]> {
+    let mut cells: HashMap<IVec2, Vec<usize>> = HashMap::new();
+    for (index, triangle) in triangles.iter().enumerate() {
+        for cell in index_cells(triangle, 0.0) {
+            cells.entry(cell).or_default().push(index);
+        }
+    }
+
+    let min_normal_y = settings.max_slope.cos();
+    let mut walkable = Vec::new();
+    for triangle in triangles {
+        if normal(triangle).y >= min_normal_y {
+            subdivide(*triangle, settings.max_edge, &mut walkable);
+        }
+    }
+
+    walkable.retain(|triangle| {
+        let center = centroid(triangle);
+        let cell = (center.xz() / INDEX_CELL).floor().as_ivec2();
+        cells.get(&cell).is_none_or(|others| {
+            others.iter().all(|other| {
+                let other = &triangles[*other];
+                let Some(height) = height_at(other, center.xz()) else {
+                    return true;
+                };
+                let above = height - center.y;
+                let resting = above.abs() <= RESTING && normal(other).y < 0.0;
+                !resting && (above <= RESTING || above >= settings.agent_height)
+            })
+        })
+    });
+    walkable
+}
+
+/// Triangles of a square of ground at y = 0.
+fn ground_square(corner: Vec2, side: f32) -> [[Vec3; 3]; 2] {
+    let point = |x: f32, z: f32| Vec3::new(corner.x + x, 0.0, corner.y + z);
+    [
+        [point(0.0, 0.0), point(0.0, side), point(side, 0.0)],
+        [point(side, side), point(side, 0.0), point(0.0, side)],
+    ]
+}
+
+/// The ground of a chunk where no block stands in the way, in squares halved down to
+/// `min_ground_cell` around blocks so it reaches close up to them.
+fn ground_triangles(
+    settings: &NavMeshSettings,
+    spatial_index: &BlockSpatialIndex,
+    corner: Vec2,
+    size: f32,
+) -> Vec<[Vec3; 3]> {
+    let cells = (size / settings.ground_cell).round().max(1.0) as i32;
+    let side = size / cells as f32;
+    let mut squares: Vec<(Vec2, f32)> = (0..cells)
+        .flat_map(|x| (0..cells).map(move |z| IVec2::new(x, z)))
+        .map(|cell| (corner + cell.as_vec2() * side, side))
+        .collect();
+
+    let mut triangles = Vec::new();
+    while let Some((corner, side)) = squares.pop() {
+        let room = Obb {
+            center: Vec3::new(
+                corner.x + side / 2.0,
+                (RESTING + settings.agent_height) / 2.0,
+                corner.y + side / 2.0,
+            ),
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::new(
+                side / 2.0,
+                (settings.agent_height - RESTING) / 2.0,
+                side / 2.0,
+            ),
+        };
+        if !spatial_index.any_overlapping(&room) {
+            triangles.extend(ground_square(corner, side));
+        } else if side / 2.0 >= settings.min_ground_cell {
+            let half = side / 2.0;
+            for offset in [Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE] {
+                squares.push((corner + offset * half, half));
+            }
+        }
+    }
+    triangles
+}
+
+/// Triangles of a block's meshes placed at `xform`, None while any of them is still loading.
+fn block_triangles(
+    block_assets: &BlockAssets,
+    id: &BlockId,
+    xform: &Transform,
+    triangles: &mut Vec<[Vec3; 3]>,
+) -> Option<()> {
+    for (id, xform) in block_assets.leaf_blocks(id, xform) {
+        let gltf = block_assets.gltf(&id)?;
+        let block_matrix = xform.compute_matrix();
+        for (node, node_matrix) in gltf_mesh_nodes(gltf, &block_assets.gltf_node_assets) {
+            let Some(gltf_mesh) = &node.mesh else {
+                continue;
+            };
+            let gltf_mesh = block_assets.gltf_mesh_assets.get(gltf_mesh)?;
+            let matrix = block_matrix * node_matrix;
+
+            for primitive in &gltf_mesh.primitives {
+                let mesh = block_assets.mesh_assets.get(&primitive.mesh)?;
+                if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+                    continue;
+                }
+                let Some(VertexAttributeValues::Float32x3(positions)) =
+                    mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+                else {
+                    continue;
+                };
+                let positions: Vec<Vec3> = positions
+                    .iter()
+                    .map(|position| matrix.transform_point3(Vec3::from(*position)))
+                    .collect();
+                let indices: Vec<usize> = match mesh.indices() {
+                    Some(Indices::U16(indices)) => indices.iter().map(|i| *i as usize).collect(),
+                    Some(Indices::U32(indices)) => indices.iter().map(|i| *i as usize).collect(),
+                    None => (0..positions.len()).collect(),
+                };
+                triangles.extend(
+                    indices
+                        .chunks_exact(3)
+                        .map(|i| [positions[i[0]], positions[i[1]], positions[i[2]]]),
+                );
+            }
+        }
+    }
+    Some(())
+}
+
+/// The walkable triangles of an island's or a bridge's blocks, None while any of their meshes
+/// is still loading. Blocks of other islands and bridges only count by their boxes.
+fn block_tile(
+    settings: &NavMeshSettings,
+    block_assets: &BlockAssets,
+    spatial_index: &BlockSpatialIndex,
+    blocks: &[PlacedBlock],
+) -> Option<Vec<[Vec3; 3]>> {
+    let mut triangles = Vec::new();
+    for block in blocks {
+        block_triangles(block_assets, &block.id, &block.transform, &mut triangles)?;
+    }
+
+    let own: HashSet<Entity> = blocks.iter().map(|block| block.entity).collect();
+    let mut walkable = walkable_triangles(settings, &triangles);
+    walkable.retain(|triangle| {
+        let center = centroid(triangle);
+        let room = Obb {
+            center: center + Vec3::Y * (settings.step_height + settings.agent_height) / 2.0,
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::new(
+                0.1,
+                (settings.agent_height - settings.step_height) / 2.0,
+                0.1,
+            ),
+        };
+        spatial_index
+            .overlapping(&room)
+            .iter()
+            .all(|entity| own.contains(entity))
+    });
+    Some(walkable)
+}
+
+/// Chunks whose ground a tile may stand on.
+fn tile_chunks(chunk_settings: &ChunkSettings, blocks: &[PlacedBlock]) -> HashSet<IVec2> {
+    blocks
+        .iter()
+        .map(|block| chunk_settings.chunk_coord(block.transform.translation))
+        .collect()
+}
+
+/// Builds the tiles of islands once they've been linked up and of the bridges between them,
+/// and rebuilds an island's when its blocks are placed, destroyed or fall. A chunk's ground is
+/// built once its islands have grown, and again whenever blocks on it change. Unloaded islands,
+/// bridges and chunks take their tiles with them.
+pub fn update_navmesh(
+    mut navmesh: ResMut<NavMesh>,
+    settings: Res<NavMeshSettings>,
+    chunk_settings: Res<ChunkSettings>,
+    spatial_index: Res<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    chunk_query: Query<(Entity, &Chunk)>,
+    island_query: Query<&Island, With<Connected>>,
+    growing_query: Query<(), (With<Island>, Without<Connected>)>,
+    bridge_query: Query<&Bridge>,
+    changed_query: Query<Entity, (With<Connected>, Or<(Changed<Island>, Added<Connected>)>)>,
+    added_query: Query<Entity, Or<(Added<Bridge>, Added<Chunk>)>>,
+    mut unloaded: RemovedComponents<Island>,
+    mut removed_bridges: RemovedComponents<Bridge>,
+    mut removed_chunks: RemovedComponents<Chunk>,
+) {
+    let chunks: HashMap<IVec2, (Entity, &Chunk)> = chunk_query
+        .iter()
+        .map(|(entity, chunk)| (chunk.coord, (entity, chunk)))
+        .collect();
+
+    for entity in unloaded
+        .read()
+        .chain(removed_bridges.read())
+        .chain(removed_chunks.read())
+    {
+        navmesh.pending.remove(&entity);
+        // the ground under a bridge or island that went is free again
+        if let Some(tile) = navmesh.remove_tile(entity) {
+            let touched = tile
+                .triangles
+                .iter()
+                .flatten()
+                .map(|vertex| chunk_settings.chunk_coord(*vertex));
+            navmesh.pending_ground.extend(touched);
+        }
+    }
+    let added: Vec<Entity> = changed_query.iter().chain(added_query.iter()).collect();
+    for entity in added {
+        match chunk_query.get(entity) {
+            Ok((_, chunk)) => navmesh.pending_ground.insert(chunk.coord),
+            Err(_) => navmesh.pending.insert(entity),
+        };
+    }
+
+    // in entity order, so a seed always builds its tiles in the same order
+    let mut pending: Vec<Entity> = navmesh.pending.iter().copied().collect();
+    pending.sort();
+    let mut rebuilt = 0;
+    for entity in pending {
+        if rebuilt >= TILES_PER_FRAME {
+            break;
+        }
+        let blocks = match (island_query.get(entity), bridge_query.get(entity)) {
+            (Ok(island), _) => &island.blocks,
+            (_, Ok(bridge)) => &bridge.pieces,
+            _ => {
+                navmesh.pending.remove(&entity);
+                continue;
+            }
+        };
+
+        let _span = info_span!("build_navmesh_tile").entered();
+        // blocks still loading are tried again next frame
+        if let Some(triangles) = block_tile(&settings, &block_assets, &spatial_index, blocks) {
+            navmesh.insert_tile(&settings, entity, triangles);
+            navmesh.pending.remove(&entity);
+            let touched = tile_chunks(&chunk_settings, blocks);
+            navmesh.pending_ground.extend(touched);
+            rebuilt += 1;
+        }
+    }
+
+    let mut pending: Vec<IVec2> = navmesh.pending_ground.iter().copied().collect();
+    pending.sort_by_key(|coord| coord.to_array());
+    for coord in pending {
+        if rebuilt >= TILES_PER_FRAME {
+            break;
+        }
+        let Some((entity, chunk)) = chunks.get(&coord) else {
+            navmesh.pending_ground.remove(&coord);
+            continue;
+        };
+        // growing blocks would have the ground rebuilt every frame
+        if chunk
+            .islands
+            .iter()
+            .any(|island| growing_query.contains(*island))
+        {
+            continue;
+        }
+
+        let _span = info_span!("build_navmesh_ground").entered();
+        let corner = chunk_settings.chunk_origin(coord).xz();
+        let triangles =
+            ground_triangles(&settings, &spatial_index, corner, chunk_settings.chunk_size);
+        navmesh.insert_tile(&settings, *entity, triangles);
+        navmesh.pending_ground.remove(&coord);
+        rebuilt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an upward facing square from (x0, z0) to (x1, z1) at `y`
+    fn floor(x0: f32, z0: f32, x1: f32, z1: f32, y: f32) -> Vec<[Vec3; 3]> {
+        let point = |x: f32, z: f32| Vec3::new(x, y, z);
+        vec![
+            [point(x0, z0), point(x0, z1), point(x1, z0)],
+            [point(x1, z1), point(x1, z0), point(x0, z1)],
+        ]
+    }
+
+    // the same square facing down, the underside of something
+    fn ceiling(x0: f32, z0: f32, x1: f32, z1: f32, y: f32) -> Vec<[Vec3; 3]> {
+        floor(x0, z0, x1, z1, y)
+            .into_iter()
+            .map(|[a, b, c]| [a, c, b])
+            .collect()
+    }
+
+    fn navmesh(tiles: &[Vec<[Vec3; 3]>]) -> NavMesh {
+        let settings = NavMeshSettings::default();
+        let mut navmesh = NavMesh::default();
+        for (i, triangles) in tiles.iter().enumerate() {
+            let walkable = walkable_triangles(&settings, triangles);
+            navmesh.insert_tile(&settings, Entity::from_raw(i as u32), walkable);
+        }
+        navmesh
+    }
+
+    fn path(navmesh: &NavMesh, start: Vec3, goal: Vec3) -> Option<Vec<Vec3>> {
+        navmesh.find_path(&NavMeshSettings::default(), start, goal)
+    }
+
+    #[test]
+    fn steps_are_climbed_up_to_the_step_height() {
+        let step = navmesh(&[
+            floor(0.0, 0.0, 2.0, 2.0, 0.0),
+            floor(2.0, 0.0, 4.0, 2.0, 0.4),
+        ]);
+        let waypoints = path(&step, Vec3::new(0.5, 0.0, 1.0), Vec3::new(3.5, 0.4, 1.0)).unwrap();
+        assert_eq!(waypoints.first(), Some(&Vec3::new(0.5, 0.0, 1.0)));
+        assert_eq!(waypoints.last(), Some(&Vec3::new(3.5, 0.4, 1.0)));
+
+        let wall = navmesh(&[
+            floor(0.0, 0.0, 2.0, 2.0, 0.0),
+            floor(2.0, 0.0, 4.0, 2.0, 1.0),
+        ]);
+        assert!(path(&wall, Vec3::new(0.5, 0.0, 1.0), Vec3::new(3.5, 1.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn paths_go_round_a_blocked_column() {
+        // the floor is split along the ceiling's edges
+        let mut room = floor(0.0, 0.0, 4.0, 4.0, 0.0);
+        room.extend(ceiling(1.5, 1.5, 2.5, 2.5, 1.0));
+        let navmesh = navmesh(&[room]);
+
+        let (start, goal) = (Vec3::new(0.5, 0.0, 2.0), Vec3::new(3.5, 0.0, 2.0));
+        let waypoints = path(&navmesh, start, goal).unwrap();
+        let length: f32 = waypoints.windows(2).map(|w| w[0].distance(w[1])).sum();
+        assert!(length > start.distance(goal) + 0.1);
+        for pair in waypoints.windows(2) {
+            for i in 0..=20 {
+                let point = pair[0].lerp(pair[1], i as f32 / 20.0);
+                let inside = (1.55..2.45).contains(&point.x) && (1.55..2.45).contains(&point.z);
+                assert!(!inside, "path goes under the ceiling at {}", point);
+            }
+        }
+        assert!(navmesh
+            .surface_at(&NavMeshSettings::default(), Vec3::new(2.0, 0.0, 2.0))
+            .is_none());
+    }
+
+    #[test]
+    fn no_path_across_a_gap_or_off_the_mesh() {
+        let navmesh = navmesh(&[
+            floor(0.0, 0.0, 2.0, 2.0, 0.0),
+            floor(4.0, 0.0, 6.0, 2.0, 0.0),
+        ]);
+        assert!(path(&navmesh, Vec3::new(1.0, 0.0, 1.0), Vec3::new(5.0, 0.0, 1.0)).is_none());
+        // nothing is known out here, so it isn't walkable
+        assert!(path(
+            &navmesh,
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(10.0, 0.0, 1.0)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn removing_a_tile_cuts_the_paths_through_it() {
+        let settings = NavMeshSettings::default();
+        let mut navmesh = navmesh(&[
+            floor(0.0, 0.0, 2.0, 2.0, 0.0),
+            floor(2.0, 0.0, 4.0, 2.0, 0.0),
+            floor(4.0, 0.0, 6.0, 2.0, 0.0),
+        ]);
+        let (start, goal) = (Vec3::new(0.5, 0.0, 1.0), Vec3::new(5.5, 0.0, 1.0));
+        assert_eq!(path(&navmesh, start, goal), Some(vec![start, goal]));
+
+        navmesh.remove_tile(Entity::from_raw(1));
+        assert!(path(&navmesh, start, goal).is_none());
+        let walkable = walkable_triangles(&settings, &floor(2.0, 0.0, 4.0, 2.0, 0.0));
+        navmesh.insert_tile(&settings, Entity::from_raw(1), walkable);
+        assert_eq!(path(&navmesh, start, goal), Some(vec![start, goal]));
+    }
+}
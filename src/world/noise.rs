@@ -0,0 +1,48 @@
+use bevy::math::Vec2;
+
+fn hash(seed: u32, x: i32, y: i32) -> f32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4_eb2d) ^ (y as u32).wrapping_mul(0x1656_67b1);
+    h = (h ^ (h >> 15)).wrapping_mul(0x2c1b_3c6d);
+    h = (h ^ (h >> 12)).wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    h as f32 / u32::MAX as f32
+}
+
+/// Smoothly interpolated value noise in `0..1`, with features roughly one unit apart.
+pub fn value_noise(seed: u32, p: Vec2) -> f32 {
+    let cell = p.floor();
+    let (x, y) = (cell.x as i32, cell.y as i32);
+
+    let f = p - cell;
+    let u = f * f * (Vec2::splat(3.0) - 2.0 * f);
+
+    let a = hash(seed, x, y);
+    let b = hash(seed, x + 1, y);
+    let c = hash(seed, x, y + 1);
+    let d = hash(seed, x + 1, y + 1);
+
+    let bottom = a + (b - a) * u.x;
+    let top = c + (d - c) * u.x;
+    bottom + (top - bottom) * u.y
+}
+
+/// Several octaves of value noise, each at double the frequency and half the weight.
+pub fn fbm(seed: u32, p: Vec2, octaves: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut weight = 0.5;
+    let mut total = 0.0;
+    let mut p = p;
+
+    for octave in 0..octaves {
+        sum += value_noise(seed.wrapping_add(octave), p) * weight;
+        total += weight;
+        weight *= 0.5;
+        p *= 2.0;
+    }
+
+    if total > 0.0 {
+        sum / total
+    } else {
+        0.0
+    }
+}
@@ -0,0 +1,158 @@
+use bevy::prelude::*;
+
+use super::deserialize::AABB;
+
+/// An oriented box in world space.
+#[derive(Debug, Clone, Copy)]
+pub struct Obb {
+    pub center: Vec3,
+    pub rotation: Quat,
+    pub half_extents: Vec3,
+}
+
+impl Obb {
+    /// A block's catalog AABB placed at `xform`.
+    pub fn from_aabb(aabb: &AABB, xform: &Transform) -> Self {
+        Obb {
+            center: xform.transform_point(Vec3::from(aabb.center)),
+            rotation: xform.rotation,
+            half_extents: Vec3::from(aabb.half_extents) * xform.scale.abs(),
+        }
+    }
+
+    /// The box shrunk by `amount` on every side, so touching boxes don't count as overlapping.
+    pub fn shrunk(&self, amount: f32) -> Self {
+        Obb {
+            half_extents: (self.half_extents - Vec3::splat(amount)).max(Vec3::ZERO),
+            ..*self
+        }
+    }
+
+    /// The box grown by `amount` on every side, so touching boxes count as overlapping.
+    pub fn grown(&self, amount: f32) -> Self {
+        Obb {
+            half_extents: self.half_extents + Vec3::splat(amount),
+            ..*self
+        }
+    }
+}
+
+impl Obb {
+    fn axes(&self) -> [Vec3; 3] {
+        [
+            self.rotation * Vec3::X,
+            self.rotation * Vec3::Y,
+            self.rotation * Vec3::Z,
+        ]
+    }
+
+    /// Half extents of the world aligned box around this one.
+    pub fn world_half_extents(&self) -> Vec3 {
+        let [x, y, z] = self.axes();
+        x.abs() * self.half_extents.x
+            + y.abs() * self.half_extents.y
+            + z.abs() * self.half_extents.z
+    }
+
+    pub fn min(&self) -> Vec3 {
+        self.center - self.world_half_extents()
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.center + self.world_half_extents()
+    }
+
+    /// Separating axis test between two boxes.
+    pub fn intersects(&self, other: &Obb) -> bool {
+        let a = self.axes();
+        let b = other.axes();
+        let t = other.center - self.center;
+
+        let mut axes: Vec<Vec3> = a.iter().chain(b.iter()).copied().collect();
+        for a_axis in a {
+            for b_axis in b {
+                let axis = a_axis.cross(b_axis);
+                // parallel edges give no new axis
+                if axis.length_squared() > 1e-6 {
+                    axes.push(axis.normalize());
+                }
+            }
+        }
+
+        axes.iter().all(|axis| {
+            let ra = a
+                .iter()
+                .zip(self.half_extents.to_array())
+                .map(|(a_axis, h)| h * a_axis.dot(*axis).abs())
+                .sum::<f32>();
+            let rb = b
+                .iter()
+                .zip(other.half_extents.to_array())
+                .map(|(b_axis, h)| h * b_axis.dot(*axis).abs())
+                .sum::<f32>();
+            t.dot(*axis).abs() <= ra + rb
+        })
+    }
+
+    /// Closest point on or inside the box to `point`.
+    pub fn closest_point(&self, point: Vec3) -> Vec3 {
+        let local = self.rotation.inverse() * (point - self.center);
+        let clamped = local.clamp(-self.half_extents, self.half_extents);
+        self.center + self.rotation * clamped
+    }
+
+    pub fn distance_to(&self, point: Vec3) -> f32 {
+        self.closest_point(point).distance(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_4;
+
+    use super::*;
+
+    fn unit_box(center: Vec3, rotation: Quat) -> Obb {
+        Obb {
+            center,
+            rotation,
+            half_extents: Vec3::splat(0.5),
+        }
+    }
+
+    #[test]
+    fn aligned_boxes() {
+        let a = unit_box(Vec3::ZERO, Quat::IDENTITY);
+        assert!(a.intersects(&unit_box(Vec3::new(0.9, 0.0, 0.0), Quat::IDENTITY)));
+        assert!(!a.intersects(&unit_box(Vec3::new(1.1, 0.0, 0.0), Quat::IDENTITY)));
+        assert!(!a.intersects(&unit_box(Vec3::new(0.0, 0.0, -1.1), Quat::IDENTITY)));
+    }
+
+    #[test]
+    fn touching_boxes_only_overlap_until_shrunk() {
+        let a = unit_box(Vec3::ZERO, Quat::IDENTITY);
+        let b = unit_box(Vec3::X, Quat::IDENTITY);
+        assert!(a.intersects(&b));
+        assert!(!a.shrunk(0.05).intersects(&b));
+    }
+
+    #[test]
+    fn rotated_boxes() {
+        let a = unit_box(Vec3::ZERO, Quat::IDENTITY);
+        let turned = Quat::from_rotation_y(FRAC_PI_4);
+        // a corner of the turned box reaches sqrt(0.5) out along x
+        assert!(a.intersects(&unit_box(Vec3::new(1.15, 0.0, 0.0), turned)));
+        assert!(!a.intersects(&unit_box(Vec3::new(1.25, 0.0, 0.0), turned)));
+        // the aligned boxes around them would overlap, the boxes themselves don't
+        let diagonal = unit_box(Vec3::new(1.0, 0.0, 1.0), turned);
+        assert!(!a.intersects(&diagonal));
+        assert!(a.max().x > diagonal.min().x && a.max().z > diagonal.min().z);
+    }
+
+    #[test]
+    fn intersects_is_symmetric() {
+        let a = unit_box(Vec3::ZERO, Quat::from_rotation_x(0.3));
+        let b = unit_box(Vec3::new(0.8, 0.4, 0.1), Quat::from_rotation_z(1.1));
+        assert_eq!(a.intersects(&b), b.intersects(&a));
+    }
+}
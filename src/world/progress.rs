@@ -0,0 +1,114 @@
+use bevy::{
+    prelude::*,
+    utils::{Duration, HashMap},
+};
+
+use super::chunks::LoadedChunks;
+use super::markov::{BlockAssets, Island, RejectReason};
+use crate::loading::AppState;
+
+/// How much generation work is done per frame, so growing the world doesn't hitch.
+#[derive(Resource)]
+pub struct GenerationBudget {
+    pub chunks_per_frame: usize,
+    pub attempts_per_frame: usize,
+    pub max_frame_time: Duration,
+}
+
+impl Default for GenerationBudget {
+    fn default() -> Self {
+        GenerationBudget {
+            chunks_per_frame: 1,
+            attempts_per_frame: 200,
+            max_frame_time: Duration::from_millis(4),
+        }
+    }
+}
+
+/// Progress of the chunks around the player, the loading screen waits on it before play starts.
+#[derive(Resource, Default)]
+pub struct GenerationProgress {
+    pub chunks_loaded: usize,
+    pub chunks_pending: usize,
+    pub islands_total: usize,
+    pub islands_done: usize,
+    pub blocks_placed: usize,
+    pub blocks_target: usize,
+    pub percent: f32,
+    pub placements_rejected: usize,
+    pub rejections: HashMap<RejectReason, usize>,
+    /// time spent growing islands in the last frame
+    pub grow_time: Duration,
+    /// time from the start of generation until play could start
+    pub generation_time: Duration,
+}
+
+impl GenerationProgress {
+    pub fn record_rejection(&mut self, reason: RejectReason) {
+        self.placements_rejected += 1;
+        *self.rejections.entry(reason).or_default() += 1;
+    }
+
+    pub fn rejected(&self, reason: RejectReason) -> usize {
+        self.rejections.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.chunks_loaded > 0
+            && self.chunks_pending == 0
+            && self.islands_done >= self.islands_total
+    }
+}
+
+pub fn track_generation_progress(
+    mut progress: ResMut<GenerationProgress>,
+    mut next_state: ResMut<NextState<AppState>>,
+    state: Res<State<AppState>>,
+    time: Res<Time>,
+    block_assets: BlockAssets,
+    loaded_chunks: Res<LoadedChunks>,
+    island_query: Query<&Island>,
+) {
+    let mut islands_done = 0;
+    let mut blocks_target = 0;
+    let mut grown = 0.0;
+
+    for island in island_query.iter() {
+        blocks_target += island.size;
+        let complete = block_assets
+            .collection(&island.pack)
+            .is_none_or(|collection| island.is_complete(collection));
+        if complete {
+            islands_done += 1;
+            grown += 1.0;
+        } else {
+            grown += island.progress();
+        }
+    }
+
+    progress.chunks_loaded = loaded_chunks.0.len();
+    progress.islands_total = island_query.iter().len();
+    progress.islands_done = islands_done;
+    progress.blocks_target = blocks_target;
+
+    // pending chunks count as islands that haven't started growing
+    let chunks_total = progress.chunks_loaded + progress.chunks_pending;
+    let islands_per_chunk = progress.islands_total as f32 / progress.chunks_loaded.max(1) as f32;
+    let expected_islands = islands_per_chunk * chunks_total as f32;
+    progress.percent = 100.0 * grown / expected_islands.max(1.0);
+
+    if *state.get() != AppState::Generating {
+        return;
+    }
+    progress.generation_time += time.delta();
+    if progress.is_done() {
+        info!(
+            "Generated {} islands, {} blocks in {:.1}s",
+            progress.islands_done,
+            progress.blocks_placed,
+            progress.generation_time.as_secs_f32()
+        );
+        progress.percent = 100.0;
+        next_state.set(AppState::Playing);
+    }
+}
@@ -0,0 +1,447 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, utils::HashMap};
+use bevy_fps_controller::controller::{FpsController, LogicalPlayer};
+
+use super::markov::Island;
+use super::obb::Obb;
+use super::routes::{Bridge, Connected};
+use super::spatial::BlockSpatialIndex;
+
+// jumps are only counted at this fraction of what the controller can just about make
+const REACH_MARGIN: f32 = 0.85;
+// a top with less room than this above it can't be stood on
+const STANDING_ROOM: f32 = 2.0;
+
+/// How far the player can jump, worked out from the controller's movement parameters.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct MovementLimits {
+    pub gravity: f32,
+    pub jump_speed: f32,
+    /// horizontal speed a jump is taken at
+    pub speed: f32,
+    /// how fast holding a direction in the air speeds the jump up
+    pub air_acceleration: f32,
+    /// air acceleration stops adding speed past this
+    pub air_speed: f32,
+}
+
+impl MovementLimits {
+    /// The controller only speeds up an airborne player while their speed along the held
+    /// direction is under its air speed cap, at `air_acceleration` times the capped speed.
+    pub fn from_controller(controller: &FpsController) -> Self {
+        MovementLimits {
+            gravity: controller.gravity,
+            jump_speed: controller.jump_speed,
+            speed: controller.run_speed.max(controller.walk_speed),
+            air_acceleration: controller.air_acceleration * controller.air_speed_cap,
+            air_speed: controller.air_speed_cap,
+        }
+    }
+
+    /// Horizontal distance covered in `time` seconds of a jump.
+    fn air_distance(&self, time: f32) -> f32 {
+        let top_speed = self.air_speed.max(self.speed);
+        let acceleration = self.air_acceleration.max(f32::EPSILON);
+        let speeding_up = ((top_speed - self.speed) / acceleration).min(time);
+        self.speed * speeding_up
+            + 0.5 * acceleration * speeding_up * speeding_up
+            + top_speed * (time - speeding_up)
+    }
+
+    /// Highest ledge a jump gets the player's feet onto.
+    pub fn max_step(&self) -> f32 {
+        REACH_MARGIN * self.jump_speed * self.jump_speed / (2.0 * self.gravity.max(f32::EPSILON))
+    }
+
+    /// Widest gap a jump crosses onto a ledge `rise` higher, negative for lower. None if
+    /// the ledge is out of reach.
+    pub fn gap(&self, rise: f32) -> Option<f32> {
+        if rise > self.max_step() {
+            return None;
+        }
+        // time until the jump comes back down to the ledge
+        let gravity = self.gravity.max(f32::EPSILON);
+        let rise = rise / REACH_MARGIN;
+        let discriminant = (self.jump_speed * self.jump_speed - 2.0 * gravity * rise).max(0.0);
+        let time = (self.jump_speed + discriminant.sqrt()) / gravity;
+        Some(REACH_MARGIN * self.air_distance(time))
+    }
+
+    pub fn max_gap(&self) -> f32 {
+        self.gap(0.0).unwrap_or(0.0)
+    }
+
+    /// Whether a block placed with this footprint could be climbed onto from the ground or
+    /// from a block already placed near it.
+    pub fn can_reach(&self, spatial_index: &BlockSpatialIndex, footprint: &[Obb]) -> bool {
+        let height = top(footprint);
+        if height <= self.max_step() {
+            return true;
+        }
+        let rect = footprint_rect(footprint);
+        let center = rect.center();
+        let radius = rect.half_size().length() + self.gap(-height).unwrap_or(0.0);
+
+        spatial_index
+            .within_radius(Vec3::new(center.x, height, center.y), radius)
+            .iter()
+            .filter_map(|(entity, _)| spatial_index.boxes(*entity))
+            .any(|boxes| {
+                self.gap(height - top(boxes))
+                    .is_some_and(|gap| rect_distance(&rect, &footprint_rect(boxes)) <= gap)
+            })
+    }
+}
+
+fn top(boxes: &[Obb]) -> f32 {
+    boxes.iter().map(|obb| obb.max().y).fold(f32::MIN, f32::max)
+}
+
+/// XZ area covered by boxes.
+fn footprint_rect(boxes: &[Obb]) -> Rect {
+    boxes.iter().fold(Rect::default(), |rect, obb| {
+        let obb_rect = Rect::from_corners(obb.min().xz(), obb.max().xz());
+        if rect.is_empty() {
+            obb_rect
+        } else {
+            rect.union(obb_rect)
+        }
+    })
+}
+
+/// Gap between two areas, zero where they overlap.
+fn rect_distance(a: &Rect, b: &Rect) -> f32 {
+    let gap = (a.min - b.max).max(b.min - a.max).max(Vec2::ZERO);
+    gap.length()
+}
+
+/// Takes the movement limits from the player's controller once it has been spawned.
+pub fn measure_movement_limits(
+    mut commands: Commands,
+    controller_query: Query<&FpsController, Added<FpsController>>,
+) {
+    let Ok(controller) = controller_query.get_single() else {
+        return;
+    };
+    let limits = MovementLimits::from_controller(controller);
+    info!(
+        "Player reach: {:.2}m up, {:.1}m across",
+        limits.max_step(),
+        limits.max_gap()
+    );
+    commands.insert_resource(limits);
+}
+
+/// The top of a placed block the player could stand on.
+pub struct Platform {
+    pub island: Entity,
+    pub rect: Rect,
+    pub top: f32,
+}
+
+/// What could not be reached from where the player stood when the world was checked.
+#[derive(Default)]
+pub struct ReachabilityReport {
+    pub platforms: Vec<Platform>,
+    /// indices into `platforms`
+    pub unreachable: Vec<usize>,
+    /// islands only reachable by dropping to the ground and climbing back up
+    pub ground_only: Vec<Entity>,
+    /// islands with no platform the player can get onto at all
+    pub unreachable_islands: Vec<Entity>,
+}
+
+/// Checks the generated world against the player's reach once it has generated, again each
+/// time islands finish or unload, and with F3, which also toggles an overlay of what can't be
+/// reached.
+#[derive(Resource, Default)]
+pub struct Reachability {
+    pub show: bool,
+    pub report: Option<ReachabilityReport>,
+}
+
+pub fn toggle_reachability_overlay(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut reachability: ResMut<Reachability>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        reachability.show = !reachability.show;
+        // checked again so the overlay shows the world as it is now
+        reachability.report = None;
+    }
+}
+
+pub fn check_reachability(
+    mut reachability: ResMut<Reachability>,
+    limits: Option<Res<MovementLimits>>,
+    spatial_index: Res<BlockSpatialIndex>,
+    player_query: Query<&Transform, With<LogicalPlayer>>,
+    island_query: Query<(Entity, &Island)>,
+    bridge_query: Query<&Bridge>,
+    finished_query: Query<(), (With<Island>, Added<Connected>)>,
+    mut unloaded: RemovedComponents<Island>,
+) {
+    // islands are only linked up once they've finished growing
+    let islands_changed = unloaded.read().count() > 0 || !finished_query.is_empty();
+    if reachability.report.is_some() && !islands_changed {
+        return;
+    }
+    let (Some(limits), Ok(player)) = (limits, player_query.get_single()) else {
+        return;
+    };
+    let _span = info_span!("check_reachability").entered();
+
+    // a bridge's deck counts as part of the island it starts from
+    let blocks = island_query
+        .iter()
+        .flat_map(|(entity, island)| island.blocks.iter().map(move |block| (entity, block)))
+        .chain(
+            bridge_query
+                .iter()
+                .flat_map(|bridge| bridge.pieces.iter().map(|piece| (bridge.ends.0, piece))),
+        );
+    let mut platforms = Vec::new();
+    for (island_entity, block) in blocks {
+        let Some(boxes) = spatial_index.boxes(block.entity) else {
+            continue;
+        };
+        for obb in boxes {
+            // only tops facing up with room to stand on them
+            if (obb.rotation * Vec3::Y).y < 0.7 {
+                continue;
+            }
+            let top = obb.max().y;
+            let room = Obb {
+                center: Vec3::new(obb.center.x, top + STANDING_ROOM / 2.0 + 0.05, obb.center.z),
+                rotation: Quat::IDENTITY,
+                half_extents: Vec3::new(0.25, STANDING_ROOM / 2.0, 0.25),
+            };
+            if spatial_index.any_overlapping(&room) {
+                continue;
+            }
+            platforms.push(Platform {
+                island: island_entity,
+                rect: Rect::from_corners(obb.min().xz(), obb.max().xz()),
+                top,
+            });
+        }
+    }
+
+    // the player lands on the highest platform under them, or on the ground
+    let feet = player.translation;
+    let start = platforms
+        .iter()
+        .enumerate()
+        .filter(|(_, platform)| platform.rect.contains(feet.xz()) && platform.top <= feet.y)
+        .max_by(|a, b| a.1.top.total_cmp(&b.1.top))
+        .map(|(i, _)| i);
+
+    let with_ground = reachable(&platforms, &limits, start, true);
+    let without_ground = reachable(&platforms, &limits, start, false);
+
+    let mut report = ReachabilityReport {
+        unreachable: (0..platforms.len()).filter(|i| !with_ground[*i]).collect(),
+        ..default()
+    };
+    let mut islands: HashMap<Entity, (bool, bool)> = HashMap::new();
+    for (i, platform) in platforms.iter().enumerate() {
+        let island = islands.entry(platform.island).or_default();
+        island.0 |= with_ground[i];
+        island.1 |= without_ground[i];
+    }
+    for (entity, (any, without)) in islands {
+        if !any {
+            report.unreachable_islands.push(entity);
+        } else if !without {
+            report.ground_only.push(entity);
+        }
+    }
+    report.platforms = platforms;
+
+    info!(
+        "Reachability: {} of {} platforms out of reach, {} islands unreachable, {} only from the ground",
+        report.unreachable.len(),
+        report.platforms.len(),
+        report.unreachable_islands.len(),
+        report.ground_only.len()
+    );
+    for platform in report.unreachable.iter().map(|i| &report.platforms[*i]) {
+        let center = platform.rect.center();
+        debug!(
+            "Unreachable platform on island {} at ({:.1}, {:.1}, {:.1})",
+            platform.island, center.x, platform.top, center.y
+        );
+    }
+    reachability.report = Some(report);
+}
+
+/// Platforms the player can get to from `start`, the ground if that's None. Without the
+/// ground the player never drops down to it, so islands have to be linked by jumps or bridges.
+fn reachable(
+    platforms: &[Platform],
+    limits: &MovementLimits,
+    start: Option<usize>,
+    use_ground: bool,
+) -> Vec<bool> {
+    let mut reached = vec![false; platforms.len()];
+    let mut queue = VecDeque::new();
+
+    let reach_from_ground = |reached: &mut Vec<bool>, queue: &mut VecDeque<usize>| {
+        for (i, platform) in platforms.iter().enumerate() {
+            if !reached[i] && platform.top <= limits.max_step() {
+                reached[i] = true;
+                queue.push_back(i);
+            }
+        }
+    };
+    let mut ground_reached = false;
+    match start {
+        Some(start) => {
+            reached[start] = true;
+            queue.push_back(start);
+        }
+        None => {
+            ground_reached = true;
+            reach_from_ground(&mut reached, &mut queue);
+        }
+    }
+
+    // platforms are bucketed by area so each one only looks at its neighbours
+    let cell_size = limits.max_gap().max(1.0) * 2.0;
+    let mut cells: HashMap<IVec2, Vec<usize>> = HashMap::new();
+    for (i, platform) in platforms.iter().enumerate() {
+        let min = (platform.rect.min / cell_size).floor().as_ivec2();
+        let max = (platform.rect.max / cell_size).floor().as_ivec2();
+        for x in min.x..=max.x {
+            for z in min.y..=max.y {
+                cells.entry(IVec2::new(x, z)).or_default().push(i);
+            }
+        }
+    }
+
+    while let Some(current) = queue.pop_front() {
+        let platform = &platforms[current];
+        // anything can drop to the ground
+        if use_ground && !ground_reached {
+            ground_reached = true;
+            reach_from_ground(&mut reached, &mut queue);
+        }
+
+        let reach = limits.gap(-platform.top).unwrap_or(0.0);
+        let min = ((platform.rect.min - reach) / cell_size).floor().as_ivec2();
+        let max = ((platform.rect.max + reach) / cell_size).floor().as_ivec2();
+        for x in min.x..=max.x {
+            for z in min.y..=max.y {
+                let Some(nearby) = cells.get(&IVec2::new(x, z)) else {
+                    continue;
+                };
+                for next in nearby {
+                    if reached[*next] {
+                        continue;
+                    }
+                    let other = &platforms[*next];
+                    let crossable = limits
+                        .gap(other.top - platform.top)
+                        .is_some_and(|gap| rect_distance(&platform.rect, &other.rect) <= gap);
+                    if crossable {
+                        reached[*next] = true;
+                        queue.push_back(*next);
+                    }
+                }
+            }
+        }
+    }
+    reached
+}
+
+pub fn draw_reachability(
+    mut gizmos: Gizmos,
+    reachability: Res<Reachability>,
+    island_query: Query<&Island>,
+) {
+    if !reachability.show {
+        return;
+    }
+    let Some(report) = &reachability.report else {
+        return;
+    };
+
+    let flat = Quat::from_rotation_x(std::f32::consts::FRAC_PI_2);
+    for platform in report.unreachable.iter().map(|i| &report.platforms[*i]) {
+        let center = platform.rect.center();
+        gizmos.rect(
+            Vec3::new(center.x, platform.top + 0.05, center.y),
+            flat,
+            platform.rect.size(),
+            Color::srgb(0.9, 0.2, 0.2),
+        );
+    }
+
+    // islands are outlined by their bounds at the height of their seed
+    let outline = |entity: &Entity, color: Color, gizmos: &mut Gizmos| {
+        let Ok(island) = island_query.get(*entity) else {
+            return;
+        };
+        let height = island
+            .blocks
+            .first()
+            .map_or(0.0, |seed| seed.transform.translation.y);
+        let center = island.bounds.center();
+        gizmos.rect(
+            Vec3::new(center.x, height, center.y),
+            flat,
+            island.bounds.size(),
+            color,
+        );
+    };
+    for entity in &report.unreachable_islands {
+        outline(entity, Color::srgb(0.9, 0.2, 0.2), &mut gizmos);
+    }
+    for entity in &report.ground_only {
+        outline(entity, Color::srgb(0.95, 0.6, 0.1), &mut gizmos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(speed: f32, air_speed: f32) -> MovementLimits {
+        MovementLimits {
+            gravity: 20.0,
+            jump_speed: 8.0,
+            speed,
+            air_acceleration: 10.0,
+            air_speed,
+        }
+    }
+
+    #[test]
+    fn flat_gap_is_speed_times_airtime() {
+        let limits = limits(6.0, 2.0);
+        let airtime = 2.0 * 8.0 / 20.0;
+        let gap = limits.gap(0.0).unwrap();
+        assert!((gap - REACH_MARGIN * 6.0 * airtime).abs() < 1e-4);
+        assert_eq!(limits.max_gap(), gap);
+    }
+
+    #[test]
+    fn gap_shrinks_with_rise() {
+        let limits = limits(6.0, 2.0);
+        let flat = limits.gap(0.0).unwrap();
+        assert!(limits.gap(-2.0).unwrap() > flat);
+        assert!(limits.gap(limits.max_step() * 0.5).unwrap() < flat);
+        assert!(limits.gap(limits.max_step()).is_some());
+        assert!(limits.gap(limits.max_step() + 0.01).is_none());
+    }
+
+    #[test]
+    fn air_acceleration_lengthens_slow_jumps() {
+        let slow = limits(1.0, 1.0).gap(0.0).unwrap();
+        let accelerated = limits(1.0, 4.0).gap(0.0).unwrap();
+        assert!(accelerated > slow);
+        // never more than crossing the whole jump at the capped speed
+        assert!(accelerated < limits(4.0, 4.0).gap(0.0).unwrap());
+    }
+}
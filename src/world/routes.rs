@@ -0,0 +1,636 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use super::deserialize::{BlockId, AABB, DEFAULT_PACK};
+use super::markov::{BlockAssets, Island, PlacedBlock};
+use super::obb::Obb;
+use super::spatial::BlockSpatialIndex;
+
+// islands further apart than this are never linked directly
+const MAX_EDGE_LENGTH: f32 = 96.0;
+// an extra edge is added where going round the graph is this much longer than the straight line
+const LOOP_FACTOR: f32 = 2.5;
+// steepest ramp a bridge may climb, rise over run
+const MAX_RAMP_SLOPE: f32 = 0.3;
+// room kept free above a bridge deck for the rider
+const HEADROOM: f32 = 2.5;
+// grid cells the router may look at for a single bridge
+const MAX_ROUTE_NODES: usize = 4000;
+// closest block pairs tried as bridge ends before an edge is given up
+const ENDPOINT_PAIRS: usize = 4;
+
+/// Links between islands that have a bridge, so the archipelago can be crossed without
+/// dropping to the ground.
+#[derive(Resource, Default)]
+pub struct IslandGraph {
+    pub edges: Vec<(Entity, Entity)>,
+}
+
+impl IslandGraph {
+    pub fn neighbours(&self, island: Entity) -> impl Iterator<Item = Entity> + '_ {
+        self.edges.iter().filter_map(move |(a, b)| {
+            if *a == island {
+                Some(*b)
+            } else if *b == island {
+                Some(*a)
+            } else {
+                None
+            }
+        })
+    }
+
+    fn linked(&self, a: Entity, b: Entity) -> bool {
+        self.neighbours(a).any(|neighbour| neighbour == b)
+    }
+}
+
+/// Marks an island that has been through the route pass, it is only baked after that.
+#[derive(Component)]
+pub struct Connected;
+
+/// A bridge of connector blocks between two islands. It is kept apart from both islands, so
+/// it doesn't count towards their rules, isn't baked or saved with them, and goes as soon as
+/// either of them unloads.
+#[derive(Component)]
+pub struct Bridge {
+    pub ends: (Entity, Entity),
+    pub pieces: Vec<PlacedBlock>,
+}
+
+struct UnionFind(Vec<usize>);
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind((0..len).collect())
+    }
+
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.0[root] != root {
+            root = self.0[root];
+        }
+        self.0[i] = root;
+        root
+    }
+
+    /// Joins the sets of `a` and `b`, false if they were joined already.
+    fn union(&mut self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        self.0[a] = b;
+        a != b
+    }
+}
+
+/// Once every loaded island has grown, links the new ones into the island graph: a minimum
+/// spanning tree over the island centres, plus an edge wherever the tree makes a long detour.
+/// Each edge is built as a bridge of connector blocks starting on the newer island.
+pub fn connect_islands(
+    mut commands: Commands,
+    mut graph: ResMut<IslandGraph>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    island_query: Query<(Entity, &Island, Has<Connected>)>,
+) {
+    let growing = island_query.iter().any(|(_, island, _)| {
+        block_assets
+            .collection(&island.pack)
+            .is_some_and(|collection| !island.is_complete(collection))
+    });
+    if growing || island_query.iter().all(|(_, _, connected)| connected) {
+        return;
+    }
+    let _span = info_span!("connect_islands").entered();
+
+    if connector(&block_assets, DEFAULT_PACK).is_none() {
+        warn!("No connector blocks in any pack, islands are left unlinked");
+        for (entity, _, _) in island_query.iter() {
+            commands.entity(entity).insert(Connected);
+        }
+        return;
+    }
+
+    // unloaded islands take their bridges with them
+    graph
+        .edges
+        .retain(|(a, b)| island_query.contains(*a) && island_query.contains(*b));
+
+    // sorted by seed position, so the same islands always link up the same way
+    let mut islands: Vec<(Entity, Vec3, bool)> = island_query
+        .iter()
+        .filter(|(_, island, _)| !island.blocks.is_empty())
+        .map(|(entity, island, connected)| (entity, island.centroid(), !connected))
+        .collect();
+    islands.sort_by(|a, b| {
+        let key = |entity: &Entity| {
+            island_query
+                .get(*entity)
+                .map_or(Vec3::ZERO, |(_, island, _)| {
+                    island.blocks[0].transform.translation
+                })
+                .to_array()
+        };
+        key(&a.0)
+            .partial_cmp(&key(&b.0))
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    let index: HashMap<Entity, usize> = islands
+        .iter()
+        .enumerate()
+        .map(|(i, (entity, _, _))| (*entity, i))
+        .collect();
+
+    let mut sets = UnionFind::new(islands.len());
+    for (a, b) in &graph.edges {
+        if let (Some(a), Some(b)) = (index.get(a), index.get(b)) {
+            sets.union(*a, *b);
+        }
+    }
+
+    // only edges touching a new island are considered, the old ones are settled
+    let mut candidates: Vec<(f32, usize, usize)> = Vec::new();
+    for i in 0..islands.len() {
+        for j in (i + 1)..islands.len() {
+            let distance = islands[i].1.xz().distance(islands[j].1.xz());
+            if (islands[i].2 || islands[j].2) && distance <= MAX_EDGE_LENGTH {
+                candidates.push((distance, i, j));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut built = 0;
+    let mut failed = 0;
+    let mut build = |graph: &mut IslandGraph, i: usize, j: usize| {
+        // the bridge hangs from the new island, an old one may have been baked already
+        let (new, old) = if islands[i].2 { (i, j) } else { (j, i) };
+        let ends = (islands[new].0, islands[old].0);
+        let Ok([(_, from, _), (_, to, _)]) = island_query.get_many([ends.0, ends.1]) else {
+            return false;
+        };
+        let Some(connector) = connector(&block_assets, &from.pack) else {
+            return false;
+        };
+        let connected = build_bridge(
+            &mut commands,
+            &mut spatial_index,
+            &block_assets,
+            &connector,
+            ends,
+            from,
+            to,
+        );
+        if connected {
+            graph.edges.push(ends);
+            built += 1;
+        } else {
+            failed += 1;
+        }
+        connected
+    };
+
+    for (_, i, j) in &candidates {
+        if sets.find(*i) != sets.find(*j) && build(&mut graph, *i, *j) {
+            sets.union(*i, *j);
+        }
+    }
+
+    // loops, so two islands side by side aren't only linked the long way round
+    for (distance, i, j) in &candidates {
+        let (a, b) = (islands[*i].0, islands[*j].0);
+        if graph.linked(a, b) {
+            continue;
+        }
+        // islands still apart here were already tried by the tree
+        let around = graph_distance(&graph, &islands, *i, *j);
+        if around < f32::MAX && around > distance * LOOP_FACTOR {
+            build(&mut graph, *i, *j);
+        }
+    }
+
+    let mut linked = 0;
+    for (entity, _, connected) in island_query.iter() {
+        if !connected {
+            commands.entity(entity).insert(Connected);
+            linked += 1;
+        }
+    }
+    info!(
+        "Linked {} islands: {} bridges built, {} routes not found",
+        linked, built, failed
+    );
+}
+
+/// Despawns the bridges that lost an island, so the one left has nothing hanging off it.
+pub fn remove_stranded_bridges(
+    mut commands: Commands,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    bridge_query: Query<(Entity, &Bridge)>,
+    island_query: Query<(), With<Island>>,
+) {
+    for (entity, bridge) in bridge_query.iter() {
+        if island_query.contains(bridge.ends.0) && island_query.contains(bridge.ends.1) {
+            continue;
+        }
+        for piece in &bridge.pieces {
+            spatial_index.remove(piece.entity);
+            // pieces that fell off have been despawned already
+            if let Some(piece) = commands.get_entity(piece.entity) {
+                piece.despawn_recursive();
+            }
+        }
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Shortest way from island `from` to island `to` along the graph's edges, measured
+/// between island centres.
+fn graph_distance(
+    graph: &IslandGraph,
+    islands: &[(Entity, Vec3, bool)],
+    from: usize,
+    to: usize,
+) -> f32 {
+    let mut best = vec![f32::MAX; islands.len()];
+    let mut done = vec![false; islands.len()];
+    best[from] = 0.0;
+
+    while let Some(current) = (0..islands.len())
+        .filter(|i| !done[*i] && best[*i] < f32::MAX)
+        .min_by(|a, b| best[*a].total_cmp(&best[*b]))
+    {
+        if current == to {
+            break;
+        }
+        done[current] = true;
+        for neighbour in graph.neighbours(islands[current].0) {
+            let Some(next) = islands
+                .iter()
+                .position(|(entity, _, _)| *entity == neighbour)
+            else {
+                continue;
+            };
+            let step = islands[current].1.distance(islands[next].1);
+            best[next] = best[next].min(best[current] + step);
+        }
+    }
+    best[to]
+}
+
+/// The connector block used for bridges out of `pack`, falling back to any pack's.
+fn connector(block_assets: &BlockAssets, pack: &str) -> Option<BlockId> {
+    let mut packs: Vec<&String> = block_assets.markov_collection.packs.keys().collect();
+    packs.sort_by_key(|other| (other.as_str() != pack, other.as_str()));
+    packs
+        .into_iter()
+        .find_map(|pack| connector_for(block_assets, pack))
+}
+
+/// The first of a pack's blocks tagged as a connector.
+fn connector_for(block_assets: &BlockAssets, pack: &str) -> Option<BlockId> {
+    let mut names: Vec<&String> = block_assets
+        .collection(pack)?
+        .0
+        .iter()
+        .filter(|(_, data)| data.rules.connector && !data.is_prefab())
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+    names.first().map(|name| BlockId::new(pack, name))
+}
+
+/// Top of a block's boxes.
+fn top(footprint: &[Obb]) -> f32 {
+    footprint
+        .iter()
+        .map(|obb| obb.max().y)
+        .fold(f32::MIN, f32::max)
+}
+
+/// A block on an island where a bridge can start or end: a point on the edge of its top,
+/// and the block itself, which the bridge may cut into.
+struct Landing {
+    entity: Entity,
+    point: Vec3,
+}
+
+/// An island's blocks with nothing standing on them, with their first box and their top.
+fn open_tops(spatial_index: &BlockSpatialIndex, island: &Island) -> Vec<(Entity, Obb, f32)> {
+    island
+        .blocks
+        .iter()
+        .filter_map(|block| {
+            let boxes = spatial_index.boxes(block.entity)?;
+            let top = top(boxes);
+            let above = Obb {
+                center: Vec3::new(boxes[0].center.x, top + HEADROOM / 2.0, boxes[0].center.z),
+                rotation: Quat::IDENTITY,
+                half_extents: Vec3::new(0.25, HEADROOM / 2.0 - 0.05, 0.25),
+            };
+            let open = spatial_index
+                .overlapping(&above)
+                .iter()
+                .all(|entity| *entity == block.entity);
+            open.then_some((block.entity, boxes[0], top))
+        })
+        .collect()
+}
+
+/// Pairs of blocks facing each other across the gap between two islands, closest first.
+fn landings(
+    spatial_index: &BlockSpatialIndex,
+    from: &Island,
+    to: &Island,
+) -> Vec<(Landing, Landing)> {
+    let targets = open_tops(spatial_index, to);
+    let mut pairs: Vec<(f32, Landing, Landing)> = Vec::new();
+    for (a_entity, a_box, a_top) in open_tops(spatial_index, from) {
+        for (b_entity, b_box, b_top) in &targets {
+            let a_point = a_box.closest_point(b_box.center).xz();
+            let b_point = b_box.closest_point(a_box.center).xz();
+            // climbing costs more than crossing
+            let cost = a_point.distance(b_point) + (a_top - b_top).abs() / MAX_RAMP_SLOPE;
+            pairs.push((
+                cost,
+                Landing {
+                    entity: a_entity,
+                    point: Vec3::new(a_point.x, a_top, a_point.y),
+                },
+                Landing {
+                    entity: *b_entity,
+                    point: Vec3::new(b_point.x, *b_top, b_point.y),
+                },
+            ));
+        }
+    }
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    pairs
+        .into_iter()
+        .take(ENDPOINT_PAIRS)
+        .map(|(_, a, b)| (a, b))
+        .collect()
+}
+
+/// Lays a bridge of `connector` blocks from `from` to `to`, the islands at `ends`, and spawns
+/// it as its own `Bridge`. Returns false if no route fits between them.
+fn build_bridge(
+    commands: &mut Commands,
+    spatial_index: &mut BlockSpatialIndex,
+    block_assets: &BlockAssets,
+    connector: &BlockId,
+    ends: (Entity, Entity),
+    from: &Island,
+    to: &Island,
+) -> bool {
+    let Some(data) = block_assets.block(connector) else {
+        return false;
+    };
+    let piece = Piece::new(&data.aabb);
+
+    for (start, end) in landings(spatial_index, from, to) {
+        let Some(deck) = route(spatial_index, &piece, &start, &end) else {
+            continue;
+        };
+        let pieces = piece.lay(&deck);
+        // the route only checked level pieces, the laid ones are tipped up the ramp
+        let clear = pieces
+            .iter()
+            .all(|transform| piece.is_clear(spatial_index, transform, &start, &end));
+        if !clear {
+            continue;
+        }
+
+        // the bridge hangs from the island it starts on, its far end only rests on the other
+        let bridge = commands.spawn_empty().id();
+        let mut parent = start.entity;
+        let pieces = pieces
+            .into_iter()
+            .map(|transform| {
+                let entity =
+                    block_assets.spawn_block(commands, spatial_index, connector, transform, bridge);
+                spatial_index.set_parent(entity, parent);
+                parent = entity;
+                PlacedBlock {
+                    entity,
+                    id: connector.clone(),
+                    transform,
+                }
+            })
+            .collect();
+        commands.entity(bridge).insert(Bridge { ends, pieces });
+        return true;
+    }
+    false
+}
+
+/// A connector's box, laid with its long horizontal side along the route.
+struct Piece {
+    aabb: AABB,
+    /// local axis laid along the route, and the one across it
+    run: Vec3,
+    across: Vec3,
+}
+
+impl Piece {
+    fn new(aabb: &AABB) -> Self {
+        let half_extents = Vec3::from(aabb.half_extents);
+        let (run, across) = if half_extents.x >= half_extents.z {
+            (Vec3::X, Vec3::Z)
+        } else {
+            (Vec3::Z, Vec3::X)
+        };
+        Piece {
+            aabb: *aabb,
+            run,
+            across,
+        }
+    }
+
+    fn length(&self) -> f32 {
+        self.run.dot(Vec3::from(self.aabb.half_extents)) * 2.0
+    }
+
+    fn width(&self) -> f32 {
+        self.across.dot(Vec3::from(self.aabb.half_extents)) * 2.0
+    }
+
+    /// The piece centred on `deck` with its top at the deck's height, running along `direction`.
+    fn transform(&self, deck: Vec3, direction: Vec3) -> Transform {
+        let heading = |v: Vec3| v.x.atan2(v.z);
+        let flat = Vec3::new(direction.x, 0.0, direction.z);
+        let yaw = if flat.length_squared() > f32::EPSILON {
+            Quat::from_rotation_y(heading(flat) - heading(self.run))
+        } else {
+            Quat::IDENTITY
+        };
+        // tip the run axis up the slope before turning it onto the route
+        let slope = direction.y.atan2(flat.length());
+        let pitch = Quat::from_axis_angle(self.run.cross(Vec3::Y), slope);
+        let rotation = yaw * pitch;
+
+        let half_height = self.aabb.half_extents[1];
+        let center = deck - Vec3::Y * half_height;
+        Transform::from_translation(center - rotation * Vec3::from(self.aabb.center))
+            .with_rotation(rotation)
+    }
+
+    /// The piece's box and the space above it that has to stay free.
+    fn boxes(&self, transform: &Transform) -> [Obb; 2] {
+        let body = Obb::from_aabb(&self.aabb, transform);
+        let up = body.rotation * Vec3::Y;
+        let headroom = Obb {
+            center: body.center + up * (body.half_extents.y + HEADROOM / 2.0),
+            half_extents: Vec3::new(body.half_extents.x, HEADROOM / 2.0, body.half_extents.z),
+            ..body
+        };
+        [body, headroom]
+    }
+
+    /// Whether the piece fits without touching blocks other than the two the bridge joins.
+    fn is_clear(
+        &self,
+        spatial_index: &BlockSpatialIndex,
+        transform: &Transform,
+        start: &Landing,
+        end: &Landing,
+    ) -> bool {
+        self.boxes(transform).iter().all(|obb| {
+            spatial_index
+                .overlapping(&obb.shrunk(0.05))
+                .iter()
+                .all(|entity| *entity == start.entity || *entity == end.entity)
+        })
+    }
+
+    /// Transforms of the pieces along a routed deck. Pieces at least twice as long as the
+    /// cells only need every other cell on a straight run along an axis.
+    fn lay(&self, deck: &[Vec3]) -> Vec<Transform> {
+        let long = self.length() >= self.width() * 2.0;
+        let mut pieces = Vec::new();
+        let mut previous: Option<(usize, Vec2)> = None;
+        for (i, point) in deck.iter().enumerate() {
+            let ahead = deck.get(i + 1).unwrap_or(point);
+            let behind = if i > 0 { &deck[i - 1] } else { point };
+            let direction = *ahead - *behind;
+            let heading = direction.xz().normalize_or_zero();
+            let straight = heading.x.abs() < 0.01 || heading.y.abs() < 0.01;
+
+            let covered = previous.is_some_and(|(last, last_heading)| {
+                long && straight
+                    && last + 1 == i
+                    && i + 1 < deck.len()
+                    && last_heading.distance(heading) < 0.01
+            });
+            if covered {
+                continue;
+            }
+            pieces.push(self.transform(*point, direction));
+            previous = Some((i, heading));
+        }
+        pieces
+    }
+}
+
+struct RouteNode {
+    cell: IVec2,
+    cost: f32,
+    parent: Option<usize>,
+}
+
+/// Finds a way for a deck from `start` to `end` across a grid one piece wide, keeping
+/// every piece and its headroom clear of blocks other than the two it joins.
+/// Returns the deck's points, their height rising evenly from one end to the other.
+fn route(
+    spatial_index: &BlockSpatialIndex,
+    piece: &Piece,
+    start: &Landing,
+    end: &Landing,
+) -> Option<Vec<Vec3>> {
+    let step = piece.width().max(0.5);
+    let direct = start.point.xz().distance(end.point.xz());
+    if (end.point.y - start.point.y).abs() > direct * MAX_RAMP_SLOPE {
+        return None;
+    }
+
+    let position = |cell: IVec2| start.point.xz() + cell.as_vec2() * step;
+    let remaining = |cell: IVec2| position(cell).distance(end.point.xz());
+    let height = |cost: f32, left: f32| {
+        start.point.y + (end.point.y - start.point.y) * cost / (cost + left).max(f32::EPSILON)
+    };
+    let free = |cell: IVec2, cost: f32, direction: Vec2| {
+        let point = position(cell);
+        let deck = Vec3::new(point.x, height(cost, remaining(cell)), point.y);
+        let transform = piece.transform(deck, Vec3::new(direction.x, 0.0, direction.y));
+        piece.is_clear(spatial_index, &transform, start, end)
+    };
+
+    let mut nodes = vec![RouteNode {
+        cell: IVec2::ZERO,
+        cost: 0.0,
+        parent: None,
+    }];
+    let mut best: HashMap<IVec2, f32> = HashMap::new();
+    best.insert(IVec2::ZERO, 0.0);
+    // costs are kept in centimetres so the heap can order them
+    let mut open = BinaryHeap::new();
+    open.push(Reverse(((remaining(IVec2::ZERO) * 100.0) as u32, 0)));
+
+    while let Some(Reverse((_, current))) = open.pop() {
+        let (cell, cost) = (nodes[current].cell, nodes[current].cost);
+        if remaining(cell) <= step {
+            let mut path = vec![current];
+            while let Some(parent) = nodes[*path.last().unwrap()].parent {
+                path.push(parent);
+            }
+            path.reverse();
+
+            // spread the climb evenly over the route that was found
+            let length = cost + remaining(cell);
+            let mut deck: Vec<Vec3> = path
+                .iter()
+                .map(|node| {
+                    let point = position(nodes[*node].cell);
+                    Vec3::new(
+                        point.x,
+                        height(nodes[*node].cost, length - nodes[*node].cost),
+                        point.y,
+                    )
+                })
+                .collect();
+            deck.push(end.point);
+            return Some(deck);
+        }
+        if nodes.len() > MAX_ROUTE_NODES || cost > direct * 2.0 + step * 4.0 {
+            continue;
+        }
+
+        for offset in [
+            IVec2::X,
+            IVec2::NEG_X,
+            IVec2::Y,
+            IVec2::NEG_Y,
+            IVec2::ONE,
+            IVec2::NEG_ONE,
+            IVec2::new(1, -1),
+            IVec2::new(-1, 1),
+        ] {
+            let next = cell + offset;
+            let next_cost = cost + offset.as_vec2().length() * step;
+            if best.get(&next).is_some_and(|known| *known <= next_cost) {
+                continue;
+            }
+            if !free(next, next_cost, offset.as_vec2()) {
+                continue;
+            }
+            best.insert(next, next_cost);
+            nodes.push(RouteNode {
+                cell: next,
+                cost: next_cost,
+                parent: Some(current),
+            });
+            let estimate = next_cost + remaining(next);
+            open.push(Reverse(((estimate * 100.0) as u32, nodes.len() - 1)));
+        }
+    }
+    None
+}
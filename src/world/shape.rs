@@ -0,0 +1,296 @@
+use std::sync::Arc;
+
+use bevy::{asset::LoadState, prelude::*};
+use rand::{prelude::SliceRandom, Rng};
+
+use super::noise::fbm;
+
+// random spots tried when looking for a seed position inside the shape
+const SEED_SAMPLES: usize = 16;
+
+/// A greyscale image, white is inside the shape and black outside.
+pub struct ShapeMask {
+    width: u32,
+    height: u32,
+    values: Vec<f32>,
+}
+
+impl ShapeMask {
+    pub fn from_image(image: &Image) -> Option<Self> {
+        let size = image.size();
+        let values = image
+            .clone()
+            .try_into_dynamic()
+            .ok()?
+            .to_luma32f()
+            .into_raw();
+        Some(ShapeMask {
+            width: size.x,
+            height: size.y,
+            values,
+        })
+    }
+
+    /// Nearest pixel at `uv`, the image covering `0..1` on both axes.
+    fn sample(&self, uv: Vec2) -> f32 {
+        if self.values.is_empty() || uv.cmplt(Vec2::ZERO).any() || uv.cmpgt(Vec2::ONE).any() {
+            return 0.0;
+        }
+        let x = ((uv.x * self.width as f32) as u32).min(self.width - 1);
+        let y = ((uv.y * self.height as f32) as u32).min(self.height - 1);
+        self.values[(y * self.width + x) as usize]
+    }
+}
+
+/// The outline an island grows into, laid over its bounds.
+#[derive(Clone)]
+pub enum IslandShape {
+    /// anywhere inside the bounds
+    Free,
+    /// strongest at `inner` and fading out towards `outer`, both relative to the bounds'
+    /// half size. A non-zero `inner` leaves a lagoon, `aspect` stretches it along x or z.
+    Radial { inner: f32, outer: f32, aspect: f32 },
+    /// a greyscale image stretched over the bounds, for crescents and other drawn shapes
+    Mask(Arc<ShapeMask>),
+    /// a noise field in world space, values below `threshold` are outside
+    Noise {
+        seed: u32,
+        scale: f32,
+        threshold: f32,
+    },
+}
+
+impl IslandShape {
+    /// How readily the island grows at `position`, in `0..1`. Zero is outside the shape.
+    pub fn value(&self, bounds: &Rect, position: Vec3) -> f32 {
+        let uv = (position.xz() - bounds.min) / bounds.size().max(Vec2::splat(f32::EPSILON));
+
+        match self {
+            IslandShape::Free => 1.0,
+            IslandShape::Radial {
+                inner,
+                outer,
+                aspect,
+            } => {
+                let radii = if *aspect >= 1.0 {
+                    Vec2::new(1.0, 1.0 / aspect)
+                } else {
+                    Vec2::new(aspect.max(f32::EPSILON), 1.0)
+                };
+                let distance = ((uv * 2.0 - Vec2::ONE) / radii).length();
+                if distance < *inner || distance > *outer {
+                    0.0
+                } else {
+                    1.0 - (distance - inner) / (outer - inner).max(f32::EPSILON)
+                }
+            }
+            IslandShape::Mask(mask) => mask.sample(uv),
+            IslandShape::Noise {
+                seed,
+                scale,
+                threshold,
+            } => {
+                let value = fbm(*seed, position.xz() * *scale, 3);
+                ((value - threshold) / (1.0 - threshold).max(f32::EPSILON)).max(0.0)
+            }
+        }
+    }
+
+    /// Where to put an island's seed block: `preferred`, unless a few random spots in the
+    /// middle of the bounds lie further inside the shape.
+    pub fn seed_position(&self, bounds: &Rect, preferred: Vec2, rng: &mut impl Rng) -> Vec2 {
+        let value = |point: Vec2| self.value(bounds, Vec3::new(point.x, 0.0, point.y));
+
+        let mut best = (preferred, value(preferred));
+        for _ in 0..SEED_SAMPLES {
+            let point = bounds.min
+                + bounds.size() * Vec2::new(rng.gen_range(0.25..0.75), rng.gen_range(0.25..0.75));
+            let point_value = value(point);
+            if point_value > best.1 {
+                best = (point, point_value);
+            }
+        }
+        best.0
+    }
+}
+
+/// The shapes islands are given, each with its relative chance.
+#[derive(Resource)]
+pub struct IslandShapes {
+    pub choices: Vec<(IslandShape, f32)>,
+    /// greyscale images relative to the assets folder, added to `choices` as masks once loaded
+    pub masks: Vec<(String, f32)>,
+}
+
+impl Default for IslandShapes {
+    fn default() -> Self {
+        IslandShapes {
+            choices: vec![
+                (IslandShape::Free, 1.0),
+                (
+                    IslandShape::Radial {
+                        inner: 0.0,
+                        outer: 1.0,
+                        aspect: 1.0,
+                    },
+                    1.0,
+                ),
+                // atoll
+                (
+                    IslandShape::Radial {
+                        inner: 0.45,
+                        outer: 1.0,
+                        aspect: 1.0,
+                    },
+                    0.5,
+                ),
+                // long spit
+                (
+                    IslandShape::Radial {
+                        inner: 0.0,
+                        outer: 1.0,
+                        aspect: 4.0,
+                    },
+                    0.5,
+                ),
+                (
+                    IslandShape::Noise {
+                        seed: 7,
+                        scale: 0.04,
+                        threshold: 0.45,
+                    },
+                    0.5,
+                ),
+            ],
+            masks: vec![(String::from("masks/crescent.png"), 0.5)],
+        }
+    }
+}
+
+impl IslandShapes {
+    pub fn pick(&self, rng: &mut impl Rng) -> IslandShape {
+        self.choices
+            .choose_weighted(rng, |(_, weight)| *weight)
+            .map_or(IslandShape::Free, |(shape, _)| shape.clone())
+    }
+}
+
+/// Mask images still loading, they join `IslandShapes` together so the choices keep their order.
+#[derive(Resource, Default)]
+pub struct ShapeMaskHandles(pub Vec<(Handle<Image>, f32)>);
+
+pub fn load_shape_masks(
+    asset_server: Res<AssetServer>,
+    island_shapes: Res<IslandShapes>,
+    mut mask_handles: ResMut<ShapeMaskHandles>,
+) {
+    mask_handles.0 = island_shapes
+        .masks
+        .iter()
+        .map(|(path, weight)| (asset_server.load(path.clone()), *weight))
+        .collect();
+}
+
+/// Adds the masks to `IslandShapes` once all of them are in, masks that failed to load are
+/// left out.
+pub fn add_loaded_shape_masks(
+    asset_server: Res<AssetServer>,
+    images: Res<Assets<Image>>,
+    mut island_shapes: ResMut<IslandShapes>,
+    mut mask_handles: ResMut<ShapeMaskHandles>,
+) {
+    if mask_handles.0.is_empty() {
+        return;
+    }
+    let failed = |handle: &Handle<Image>| {
+        matches!(
+            asset_server.get_load_state(handle.id()),
+            Some(LoadState::Failed(_))
+        )
+    };
+    if !mask_handles
+        .0
+        .iter()
+        .all(|(handle, _)| images.contains(handle) || failed(handle))
+    {
+        return;
+    }
+
+    for (handle, weight) in mask_handles.0.drain(..) {
+        if failed(&handle) {
+            warn!("Island mask {:?} could not be loaded", handle.path());
+            continue;
+        }
+        match images.get(&handle).and_then(ShapeMask::from_image) {
+            Some(mask) => island_shapes
+                .choices
+                .push((IslandShape::Mask(Arc::new(mask)), weight)),
+            None => warn!("Island mask {:?} is not a readable image", handle.path()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds() -> Rect {
+        Rect::new(0.0, 0.0, 10.0, 10.0)
+    }
+
+    fn at(x: f32, z: f32) -> Vec3 {
+        Vec3::new(x, 0.0, z)
+    }
+
+    #[test]
+    fn radial_fades_to_zero_at_its_outer_edge() {
+        let shape = IslandShape::Radial {
+            inner: 0.0,
+            outer: 1.0,
+            aspect: 1.0,
+        };
+        assert_eq!(shape.value(&bounds(), at(5.0, 5.0)), 1.0);
+        assert!((shape.value(&bounds(), at(7.5, 5.0)) - 0.5).abs() < 1e-5);
+        // just inside the edge is barely in, just outside is out
+        assert!(shape.value(&bounds(), at(9.9, 5.0)) > 0.0);
+        assert_eq!(shape.value(&bounds(), at(10.1, 5.0)), 0.0);
+        // the corners are past the circle
+        assert_eq!(shape.value(&bounds(), at(9.5, 9.5)), 0.0);
+    }
+
+    #[test]
+    fn radial_leaves_a_lagoon_and_stretches_with_aspect() {
+        let atoll = IslandShape::Radial {
+            inner: 0.5,
+            outer: 1.0,
+            aspect: 1.0,
+        };
+        assert_eq!(atoll.value(&bounds(), at(5.0, 5.0)), 0.0);
+        assert!(atoll.value(&bounds(), at(8.0, 5.0)) > 0.0);
+
+        let spit = IslandShape::Radial {
+            inner: 0.0,
+            outer: 1.0,
+            aspect: 4.0,
+        };
+        assert!(spit.value(&bounds(), at(9.0, 5.0)) > 0.0);
+        assert_eq!(spit.value(&bounds(), at(5.0, 7.0)), 0.0);
+    }
+
+    #[test]
+    fn mask_is_looked_up_by_nearest_pixel() {
+        // a 2x2 image, only the top right pixel is white
+        let mask = ShapeMask {
+            width: 2,
+            height: 2,
+            values: vec![0.0, 1.0, 0.0, 0.0],
+        };
+        let shape = IslandShape::Mask(Arc::new(mask));
+        assert_eq!(shape.value(&bounds(), at(7.5, 2.5)), 1.0);
+        assert_eq!(shape.value(&bounds(), at(2.5, 2.5)), 0.0);
+        assert_eq!(shape.value(&bounds(), at(7.5, 7.5)), 0.0);
+        // the far edge still lands on the last pixel, outside the bounds is out
+        assert_eq!(shape.value(&bounds(), at(10.0, 0.0)), 1.0);
+        assert_eq!(shape.value(&bounds(), at(11.0, 2.5)), 0.0);
+    }
+}
@@ -0,0 +1,362 @@
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use super::markov::BlockInstance;
+use super::obb::Obb;
+
+struct SpatialEntry {
+    boxes: Vec<Obb>,
+    cells: Vec<IVec3>,
+    /// a prefab's parts, `parts[i]` is covered by `boxes[i]`
+    parts: Vec<Entity>,
+}
+
+/// Uniform grid over placed blocks. Unlike Rapier's query pipeline it is updated
+/// as soon as a block is spawned, so blocks placed earlier in the same frame are seen.
+#[derive(Resource)]
+pub struct BlockSpatialIndex {
+    cell_size: f32,
+    cells: HashMap<IVec3, Vec<Entity>>,
+    entries: HashMap<Entity, SpatialEntry>,
+    /// the prefab each part belongs to and the index of the part's box
+    part_of: HashMap<Entity, (Entity, usize)>,
+    /// the block each block was grown or placed from, what holds it up
+    parents: HashMap<Entity, Entity>,
+    children: HashMap<Entity, Vec<Entity>>,
+    /// blocks added, moved or removed since the last `take_changed`
+    changed: HashSet<Entity>,
+}
+
+impl Default for BlockSpatialIndex {
+    fn default() -> Self {
+        BlockSpatialIndex::new(8.0)
+    }
+}
+
+impl BlockSpatialIndex {
+    pub fn new(cell_size: f32) -> Self {
+        BlockSpatialIndex {
+            cell_size,
+            cells: HashMap::new(),
+            entries: HashMap::new(),
+            part_of: HashMap::new(),
+            parents: HashMap::new(),
+            children: HashMap::new(),
+            changed: HashSet::new(),
+        }
+    }
+
+    fn cell_range(&self, min: Vec3, max: Vec3) -> impl Iterator<Item = IVec3> {
+        let min = (min / self.cell_size).floor().as_ivec3();
+        let max = (max / self.cell_size).floor().as_ivec3();
+        (min.x..=max.x).flat_map(move |x| {
+            (min.y..=max.y).flat_map(move |y| (min.z..=max.z).map(move |z| IVec3::new(x, y, z)))
+        })
+    }
+
+    /// Adds a block covered by `boxes`, replacing any previous entry for it.
+    pub fn insert(&mut self, entity: Entity, boxes: Vec<Obb>) {
+        self.insert_prefab(entity, boxes, Vec::new());
+    }
+
+    /// Adds a prefab covered by the boxes of its `parts`, one box per part. Queries return the
+    /// prefab, each part can still be looked up on its own.
+    pub fn insert_prefab(&mut self, entity: Entity, boxes: Vec<Obb>, parts: Vec<Entity>) {
+        self.remove_entry(entity);
+        debug_assert!(parts.is_empty() || parts.len() == boxes.len());
+        for (i, part) in parts.iter().enumerate() {
+            self.part_of.insert(*part, (entity, i));
+        }
+
+        let mut cells: Vec<IVec3> = boxes
+            .iter()
+            .flat_map(|obb| self.cell_range(obb.min(), obb.max()))
+            .collect();
+        cells.sort_by_key(|c| (c.x, c.y, c.z));
+        cells.dedup();
+
+        for cell in &cells {
+            self.cells.entry(*cell).or_default().push(entity);
+        }
+        self.entries.insert(
+            entity,
+            SpatialEntry {
+                boxes,
+                cells,
+                parts,
+            },
+        );
+        self.changed.insert(entity);
+    }
+
+    /// Removes a block, a prefab goes with all its parts. Removing a part on its own does nothing.
+    /// The blocks grown from it are left without a parent.
+    pub fn remove(&mut self, entity: Entity) {
+        if !self.remove_entry(entity) {
+            return;
+        }
+        if let Some(parent) = self.parents.remove(&entity) {
+            if let Some(siblings) = self.children.get_mut(&parent) {
+                siblings.retain(|e| *e != entity);
+            }
+        }
+        for child in self.children.remove(&entity).unwrap_or_default() {
+            self.parents.remove(&child);
+        }
+    }
+
+    /// Takes a block's boxes out of the grid, keeping its place in the support graph.
+    fn remove_entry(&mut self, entity: Entity) -> bool {
+        let Some(entry) = self.entries.remove(&entity) else {
+            return false;
+        };
+        self.changed.insert(entity);
+        for part in &entry.parts {
+            self.part_of.remove(part);
+        }
+        for cell in entry.cells {
+            if let Some(entities) = self.cells.get_mut(&cell) {
+                entities.retain(|e| *e != entity);
+                if entities.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+        true
+    }
+
+    /// Records that `child` was grown or placed from `parent`, it stands as long as `parent` does.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) {
+        if let Some(old) = self.parents.insert(child, parent) {
+            if let Some(siblings) = self.children.get_mut(&old) {
+                siblings.retain(|e| *e != child);
+            }
+        }
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        self.parents.get(&entity).copied()
+    }
+
+    /// The blocks grown or placed from `entity`.
+    pub fn children(&self, entity: Entity) -> &[Entity] {
+        self.children.get(&entity).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.entries.contains_key(&entity) || self.part_of.contains_key(&entity)
+    }
+
+    /// The block an entity is, or the prefab it's a part of.
+    pub fn block_of(&self, entity: Entity) -> Option<Entity> {
+        if self.entries.contains_key(&entity) {
+            return Some(entity);
+        }
+        self.part_of.get(&entity).map(|(prefab, _)| *prefab)
+    }
+
+    /// A block's boxes, or a part's own box.
+    pub fn boxes(&self, entity: Entity) -> Option<&[Obb]> {
+        if let Some(entry) = self.entries.get(&entity) {
+            return Some(entry.boxes.as_slice());
+        }
+        let (prefab, i) = self.part_of.get(&entity)?;
+        self.entries
+            .get(prefab)
+            .and_then(|entry| entry.boxes.get(*i..*i + 1))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Blocks that changed since the last call, for structures kept in step with the index.
+    pub fn take_changed(&mut self) -> HashSet<Entity> {
+        std::mem::take(&mut self.changed)
+    }
+
+    pub fn entities(&self) -> impl Iterator<Item = Entity> + '_ {
+        self.entries.keys().copied()
+    }
+
+    /// Entities with a cell between `min` and `max`, each once.
+    fn candidates(&self, min: Vec3, max: Vec3) -> Vec<Entity> {
+        let mut candidates: Vec<Entity> = self
+            .cell_range(min, max)
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Blocks intersecting `obb`.
+    pub fn overlapping(&self, obb: &Obb) -> Vec<Entity> {
+        self.candidates(obb.min(), obb.max())
+            .into_iter()
+            .filter(|entity| {
+                self.entries[entity]
+                    .boxes
+                    .iter()
+                    .any(|other| other.intersects(obb))
+            })
+            .collect()
+    }
+
+    pub fn any_overlapping(&self, obb: &Obb) -> bool {
+        !self.overlapping(obb).is_empty()
+    }
+
+    /// Distance from `point` to the closest of an entity's boxes.
+    pub fn distance_to(&self, entity: Entity, point: Vec3) -> Option<f32> {
+        let entry = self.entries.get(&entity)?;
+        entry
+            .boxes
+            .iter()
+            .map(|obb| obb.distance_to(point))
+            .min_by(|a, b| a.total_cmp(b))
+    }
+
+    /// Blocks within `radius` of `point`, nearest first.
+    pub fn within_radius(&self, point: Vec3, radius: f32) -> Vec<(Entity, f32)> {
+        let mut found: Vec<(Entity, f32)> = self
+            .candidates(point - Vec3::splat(radius), point + Vec3::splat(radius))
+            .into_iter()
+            .filter_map(|entity| {
+                let distance = self.distance_to(entity, point)?;
+                (distance <= radius).then_some((entity, distance))
+            })
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1));
+        found
+    }
+
+    /// The `k` blocks nearest to `point`, nearest first, leaving out any further than `max_radius`.
+    pub fn nearest(&self, point: Vec3, k: usize, max_radius: f32) -> Vec<(Entity, f32)> {
+        if k == 0 || self.entries.is_empty() || max_radius < 0.0 {
+            return Vec::new();
+        }
+
+        // widen the search until it holds k blocks, covers every block or reaches max_radius
+        let mut radius = self.cell_size.min(max_radius);
+        loop {
+            let mut found = self.within_radius(point, radius);
+            if found.len() >= k || found.len() == self.entries.len() || radius >= max_radius {
+                found.truncate(k);
+                return found;
+            }
+            radius = (radius * 2.0).min(max_radius);
+        }
+    }
+}
+
+/// Drops blocks despawned without going through the index, e.g. by `despawn_recursive` on a parent.
+pub fn prune_spatial_index(
+    mut removed: RemovedComponents<BlockInstance>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+) {
+    for entity in removed.read() {
+        spatial_index.remove(entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(center: Vec3) -> Obb {
+        Obb {
+            center,
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::splat(0.5),
+        }
+    }
+
+    fn index(centers: &[Vec3]) -> (BlockSpatialIndex, Vec<Entity>) {
+        let mut index = BlockSpatialIndex::new(2.0);
+        let entities: Vec<Entity> = (0..centers.len() as u32).map(Entity::from_raw).collect();
+        for (entity, center) in entities.iter().zip(centers) {
+            index.insert(*entity, vec![cube(*center)]);
+        }
+        (index, entities)
+    }
+
+    #[test]
+    fn insert_and_query() {
+        let (index, entities) = index(&[Vec3::ZERO, Vec3::new(5.0, 0.0, 0.0)]);
+
+        assert_eq!(index.len(), 2);
+        assert_eq!(
+            index.overlapping(&cube(Vec3::new(0.5, 0.0, 0.0))),
+            vec![entities[0]]
+        );
+        assert!(!index.any_overlapping(&cube(Vec3::new(2.5, 0.0, 0.0))));
+        let near: Vec<Entity> = index
+            .within_radius(Vec3::new(4.0, 0.0, 0.0), 1.0)
+            .iter()
+            .map(|(entity, _)| *entity)
+            .collect();
+        assert_eq!(near, vec![entities[1]]);
+    }
+
+    #[test]
+    fn remove_clears_cells() {
+        let (mut index, entities) = index(&[Vec3::ZERO, Vec3::new(5.0, 0.0, 0.0)]);
+
+        index.remove(entities[0]);
+        assert!(!index.contains(entities[0]));
+        assert!(index.boxes(entities[0]).is_none());
+        assert!(!index.any_overlapping(&cube(Vec3::ZERO)));
+        assert!(index
+            .cells
+            .values()
+            .all(|cell| !cell.contains(&entities[0])));
+
+        // moving a block replaces its old entry
+        index.insert(entities[1], vec![cube(Vec3::ZERO)]);
+        assert_eq!(index.overlapping(&cube(Vec3::ZERO)), vec![entities[1]]);
+        assert!(!index.any_overlapping(&cube(Vec3::new(5.0, 0.0, 0.0))));
+        assert_eq!(index.take_changed().len(), 2);
+    }
+
+    #[test]
+    fn prefab_parts_resolve_to_the_prefab() {
+        let mut index = BlockSpatialIndex::new(2.0);
+        let (prefab, a, b) = (
+            Entity::from_raw(0),
+            Entity::from_raw(1),
+            Entity::from_raw(2),
+        );
+        let boxes = vec![cube(Vec3::ZERO), cube(Vec3::X)];
+        index.insert_prefab(prefab, boxes, vec![a, b]);
+
+        assert_eq!(index.block_of(b), Some(prefab));
+        assert_eq!(index.boxes(b).unwrap()[0].center, Vec3::X);
+        assert_eq!(index.overlapping(&cube(Vec3::X)), vec![prefab]);
+
+        index.remove(a);
+        assert!(index.contains(prefab));
+        index.remove(prefab);
+        assert!(!index.contains(a) && !index.contains(b));
+    }
+
+    #[test]
+    fn nearest_stops_at_max_radius() {
+        let (index, entities) = index(&[Vec3::ZERO, Vec3::new(100.0, 0.0, 0.0)]);
+
+        let nearest = index.nearest(Vec3::new(1.0, 0.0, 0.0), 2, 10.0);
+        assert_eq!(nearest.len(), 1);
+        assert_eq!(nearest[0].0, entities[0]);
+        assert_eq!(index.nearest(Vec3::new(1.0, 0.0, 0.0), 2, 1000.0).len(), 2);
+        assert!(index.nearest(Vec3::new(50.0, 0.0, 0.0), 1, 10.0).is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+use std::{cmp::Ordering, f32::consts::FRAC_PI_2};
+
+use bevy::prelude::*;
+use bevy_fps_controller::controller::LogicalPlayer;
+use bevy_rapier3d::{parry::query::ShapeCastOptions, prelude::*};
+
+use super::markov::Island;
+use super::spatial::BlockSpatialIndex;
+use crate::fps::{PLAYER_HEIGHT, PLAYER_RADIUS, SPAWN_POINT};
+
+// rays start this far above an island's highest block
+const CAST_MARGIN: f32 = 20.0;
+// a top is only stood on if its normal points at least this far up
+const FLAT_NORMAL: f32 = 0.9;
+// the collider may rest this much higher than the ray hit, the rim rays this much either way
+const FOOTING_TOLERANCE: f32 = 0.25;
+// room kept clear over the collider's head
+const HEADROOM: f32 = 0.5;
+// gap left under the collider so it drops onto the top instead of starting in it
+const LIFT: f32 = 0.05;
+const CONTACT_TOLERANCE: f32 = 0.05;
+
+/// Where the player starts, and is put back if they fall before reaching a checkpoint. Starts
+/// at the point the world generates around and is moved onto an island once generation is done.
+#[derive(Resource)]
+pub struct SpawnPoint(pub Vec3);
+
+impl Default for SpawnPoint {
+    fn default() -> Self {
+        SpawnPoint(SPAWN_POINT)
+    }
+}
+
+/// Picks the first clear, flat block top on the islands nearest the start and moves the player
+/// there. Islands and spots are tried in a fixed order, so a seed always spawns in the same place.
+pub fn choose_spawn_point(
+    mut spawn_point: ResMut<SpawnPoint>,
+    rapier_context: Res<RapierContext>,
+    spatial_index: Res<BlockSpatialIndex>,
+    island_query: Query<&Island>,
+    mut player_query: Query<(Entity, &mut Transform, &mut Velocity), With<LogicalPlayer>>,
+) {
+    let _span = info_span!("choose_spawn_point").entered();
+    let mut filter = QueryFilter::default().exclude_sensors();
+    if let Ok((player, _, _)) = player_query.get_single() {
+        filter = filter.exclude_rigid_body(player);
+    }
+
+    // nearest seed first, then by seed position so ties always break the same way
+    let mut islands: Vec<&Island> = island_query
+        .iter()
+        .filter(|island| !island.blocks.is_empty())
+        .collect();
+    islands.sort_by(|a, b| {
+        let key = |island: &Island| {
+            let seed = island.blocks[0].transform.translation;
+            (seed.xz().distance(SPAWN_POINT.xz()), seed.to_array())
+        };
+        let (a, b) = (key(a), key(b));
+        a.0.total_cmp(&b.0)
+            .then(a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+    });
+
+    let Some(position) = islands
+        .iter()
+        .find_map(|island| island_spawn(&rapier_context, &spatial_index, filter, island))
+    else {
+        warn!(
+            "No clear spot on any of {} islands, spawning at {}",
+            islands.len(),
+            spawn_point.0
+        );
+        return;
+    };
+
+    info!("Spawn point {}", position);
+    spawn_point.0 = position;
+    for (_, mut transform, mut velocity) in &mut player_query {
+        transform.translation = position;
+        velocity.linvel = Vec3::ZERO;
+    }
+}
+
+/// The first spot on the island the player can stand on, block tops nearest its middle first.
+pub fn island_spawn(
+    rapier_context: &RapierContext,
+    spatial_index: &BlockSpatialIndex,
+    filter: QueryFilter,
+    island: &Island,
+) -> Option<Vec3> {
+    let boxes: Vec<_> = island
+        .blocks
+        .iter()
+        .filter_map(|block| spatial_index.boxes(block.entity))
+        .flatten()
+        .collect();
+    let ceiling = boxes.iter().map(|obb| obb.max().y).reduce(f32::max)?;
+
+    let middle = island.centroid().xz();
+    let mut spots: Vec<Vec2> = boxes.iter().map(|obb| obb.center.xz()).collect();
+    spots.sort_by(|a, b| {
+        a.distance_squared(middle)
+            .total_cmp(&b.distance_squared(middle))
+            .then(a.x.total_cmp(&b.x))
+            .then(a.y.total_cmp(&b.y))
+    });
+    spots.dedup();
+
+    spots.into_iter().find_map(|spot| {
+        standing_spot(
+            rapier_context,
+            spatial_index,
+            filter,
+            spot,
+            ceiling + CAST_MARGIN,
+        )
+    })
+}
+
+/// Where the player's collider stands on the top under `spot`: a flat block top wide enough
+/// for the collider with nothing overhead.
+pub fn standing_spot(
+    rapier_context: &RapierContext,
+    spatial_index: &BlockSpatialIndex,
+    filter: QueryFilter,
+    spot: Vec2,
+    from: f32,
+) -> Option<Vec3> {
+    let down = |at: Vec2, height: f32, reach: f32| {
+        rapier_context.cast_ray_and_get_normal(
+            Vec3::new(at.x, height, at.y),
+            Vec3::NEG_Y,
+            reach,
+            true,
+            filter,
+        )
+    };
+
+    let (_, hit) = down(spot, from, from + 1.0)?;
+    if hit.normal.y < FLAT_NORMAL {
+        return None;
+    }
+    // the ground plane isn't an island
+    let inside = hit.point - hit.normal * CONTACT_TOLERANCE;
+    if spatial_index
+        .within_radius(inside, CONTACT_TOLERANCE * 2.0)
+        .is_empty()
+    {
+        return None;
+    }
+
+    // the top carries on under the collider's rim instead of dropping away
+    let footing = (0..4).all(|i| {
+        let rim = spot + Vec2::from_angle(i as f32 * FRAC_PI_2) * PLAYER_RADIUS;
+        down(
+            rim,
+            hit.point.y + FOOTING_TOLERANCE,
+            2.0 * FOOTING_TOLERANCE,
+        )
+        .is_some_and(|(_, rim_hit)| rim_hit.normal.y >= FLAT_NORMAL)
+    });
+    if !footing {
+        return None;
+    }
+
+    // drop the collider in from above its headroom, anything overhead or a higher edge
+    // under the rim stops it short of the top
+    let collider = Collider::cylinder(PLAYER_HEIGHT / 2.0, PLAYER_RADIUS);
+    let drop = HEADROOM + LIFT;
+    let start = hit.point + Vec3::Y * (PLAYER_HEIGHT / 2.0 + drop);
+    let (_, landing) = rapier_context.cast_shape(
+        start,
+        Quat::IDENTITY,
+        Vec3::NEG_Y,
+        &collider,
+        ShapeCastOptions::with_max_time_of_impact(drop + FOOTING_TOLERANCE),
+        filter,
+    )?;
+    if landing.time_of_impact < drop - FOOTING_TOLERANCE {
+        return None;
+    }
+    Some(start - Vec3::Y * (landing.time_of_impact - LIFT))
+}
@@ -0,0 +1,423 @@
+use bevy::{prelude::*, utils::HashSet};
+use bevy_fps_controller::controller::{LogicalPlayer, RenderPlayer};
+use bevy_rapier3d::prelude::*;
+
+use super::bake::{Baked, UnbakeIsland};
+use super::colliders_from_gltf_node;
+use super::deserialize::BlockId;
+use super::markov::{prefab_leaves, BlockAssets, BlockInstance, Island, Prefab};
+use super::obb::Obb;
+use super::spatial::BlockSpatialIndex;
+use crate::fps::respawn::RespawnSettings;
+
+// blocks closer than this to each other, or to the ground, are touching
+const CONTACT_TOLERANCE: f32 = 0.05;
+const GROUND_HEIGHT: f32 = 0.0;
+// mass per unit of block volume, measured on the catalog box
+const BLOCK_DENSITY: f32 = 200.0;
+// debris slower than this for SETTLE_TIME seconds is fixed in place again
+const SETTLE_SPEED: f32 = 0.1;
+const SETTLE_TIME: f32 = 1.0;
+const TARGET_DISTANCE: f32 = 20.0;
+
+/// Removes a block, anything only held up by it comes down.
+#[derive(Event)]
+pub struct DestroyBlock(pub Entity);
+
+/// A block that lost its support and is falling.
+#[derive(Component, Default)]
+pub struct Debris {
+    still_for: f32,
+}
+
+/// Whether a block reaches down to the ground.
+pub fn is_grounded(spatial_index: &BlockSpatialIndex, entity: Entity) -> bool {
+    spatial_index.boxes(entity).is_some_and(|boxes| {
+        boxes
+            .iter()
+            .any(|obb| obb.min().y <= GROUND_HEIGHT + CONTACT_TOLERANCE)
+    })
+}
+
+/// A thin slab just under a box, whatever the box rests on overlaps it.
+fn slab_under(obb: &Obb) -> Obb {
+    Obb {
+        center: Vec3::new(obb.center.x, obb.min().y - CONTACT_TOLERANCE, obb.center.z),
+        rotation: obb.rotation,
+        half_extents: Vec3::new(obb.half_extents.x, CONTACT_TOLERANCE, obb.half_extents.z),
+    }
+}
+
+/// Whether a block about to be placed would rest on the ground or on a placed block.
+pub fn is_supported(spatial_index: &BlockSpatialIndex, footprint: &[Obb]) -> bool {
+    footprint.iter().any(|obb| {
+        obb.min().y <= GROUND_HEIGHT + CONTACT_TOLERANCE
+            || spatial_index.any_overlapping(&slab_under(obb))
+    })
+}
+
+/// A placed block a block with this footprint rests on, its parent when it wasn't grown from one.
+pub fn resting_on(spatial_index: &BlockSpatialIndex, footprint: &[Obb]) -> Option<Entity> {
+    footprint
+        .iter()
+        .find_map(|obb| spatial_index.overlapping(&slab_under(obb)).first().copied())
+}
+
+/// Whether a block has a chain of parents down to a block on the ground, none of them falling.
+fn stands(spatial_index: &BlockSpatialIndex, falling: &HashSet<Entity>, entity: Entity) -> bool {
+    let mut seen: HashSet<Entity> = HashSet::new();
+    let mut current = entity;
+    loop {
+        if !spatial_index.contains(current) || falling.contains(&current) || !seen.insert(current) {
+            return false;
+        }
+        if is_grounded(spatial_index, current) {
+            return true;
+        }
+        let Some(parent) = spatial_index.parent(current) else {
+            return false;
+        };
+        current = parent;
+    }
+}
+
+/// The `candidates`, and the blocks grown from them, that no longer have a chain of parents down
+/// to the ground. Blocks that only touch, like neighbouring islands or a bridge's far end, don't
+/// hold each other up.
+pub fn unsupported(spatial_index: &BlockSpatialIndex, candidates: &[Entity]) -> Vec<Entity> {
+    let mut falling: HashSet<Entity> = HashSet::new();
+    let mut order = Vec::new();
+    let mut stack = candidates.to_vec();
+
+    while let Some(entity) = stack.pop() {
+        if falling.contains(&entity)
+            || !spatial_index.contains(entity)
+            || stands(spatial_index, &falling, entity)
+        {
+            continue;
+        }
+        falling.insert(entity);
+        order.push(entity);
+        stack.extend(spatial_index.children(entity));
+    }
+    order
+}
+
+/// The block the player is looking at, along with the ray hit point and normal.
+pub fn targeted_block(
+    rapier_context: &RapierContext,
+    spatial_index: &BlockSpatialIndex,
+    camera: &GlobalTransform,
+    player: Entity,
+) -> Option<(Entity, Vec3, Vec3)> {
+    let filter = QueryFilter::default()
+        .exclude_rigid_body(player)
+        .exclude_sensors();
+    let (_, hit) = rapier_context.cast_ray_and_get_normal(
+        camera.translation(),
+        *camera.forward(),
+        TARGET_DISTANCE,
+        true,
+        filter,
+    )?;
+
+    // colliders may belong to a block, a prefab part or a baked island, so look the block up by position
+    let inside = hit.point - hit.normal * CONTACT_TOLERANCE;
+    let (entity, _) = spatial_index
+        .within_radius(inside, CONTACT_TOLERANCE * 2.0)
+        .into_iter()
+        .next()?;
+    Some((entity, hit.point, hit.normal))
+}
+
+pub fn destroy_targeted_block(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    rapier_context: Res<RapierContext>,
+    spatial_index: Res<BlockSpatialIndex>,
+    camera_query: Query<&GlobalTransform, With<RenderPlayer>>,
+    player_query: Query<Entity, With<LogicalPlayer>>,
+    mut events: EventWriter<DestroyBlock>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::KeyH) {
+        return;
+    }
+    let (Ok(camera), Ok(player)) = (camera_query.get_single(), player_query.get_single()) else {
+        return;
+    };
+
+    if let Some((entity, _, _)) = targeted_block(&rapier_context, &spatial_index, camera, player) {
+        events.send(DestroyBlock(entity));
+    }
+}
+
+pub fn destroy_blocks(
+    mut commands: Commands,
+    mut events: EventReader<DestroyBlock>,
+    mut unbake_events: EventWriter<UnbakeIsland>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    block_query: Query<&BlockInstance>,
+    mut island_query: Query<(&mut Island, Has<Baked>)>,
+    children_query: Query<&Children>,
+    collider_query: Query<(), With<Collider>>,
+) {
+    let mut candidates = Vec::new();
+    let mut unbake: HashSet<Entity> = HashSet::new();
+
+    for DestroyBlock(target) in events.read() {
+        // a prefab part takes the whole prefab with it
+        let entity = spatial_index.block_of(*target).unwrap_or(*target);
+        let Ok(block) = block_query.get(entity) else {
+            continue;
+        };
+
+        candidates.extend(spatial_index.children(entity));
+        spatial_index.remove(entity);
+        if let Ok((mut island, baked)) = island_query.get_mut(block.island) {
+            island.remove_block(entity);
+            if baked {
+                unbake.insert(block.island);
+            }
+        }
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let falling = unsupported(&spatial_index, &candidates);
+    if !falling.is_empty() {
+        info!("{} blocks lost their support", falling.len());
+    }
+    for entity in falling {
+        spatial_index.remove(entity);
+        let Ok(block) = block_query.get(entity) else {
+            continue;
+        };
+        if island_query.get(block.island).is_ok_and(|(_, baked)| baked) {
+            unbake.insert(block.island);
+        }
+        make_debris(
+            &mut commands,
+            &block_assets,
+            entity,
+            &block.id(),
+            &children_query,
+            &collider_query,
+        );
+    }
+
+    for island in unbake {
+        unbake_events.send(UnbakeIsland(island));
+    }
+}
+
+/// Turns a fixed block into a falling body. Trimeshes don't collide as dynamic bodies, so its
+/// colliders are swapped for convex hulls of the same meshes.
+fn make_debris(
+    commands: &mut Commands,
+    block_assets: &BlockAssets,
+    entity: Entity,
+    id: &BlockId,
+    children_query: &Query<&Children>,
+    collider_query: &Query<(), With<Collider>>,
+) {
+    let mut shapes = Vec::new();
+    let mut volume = 0.0;
+
+    for (leaf, local) in block_assets.leaf_blocks(id, &Transform::IDENTITY) {
+        if let Some(data) = block_assets.block(&leaf) {
+            let [x, y, z] = data.aabb.half_extents;
+            volume += 8.0 * x * y * z;
+
+            let hulls: Vec<Collider> = block_assets
+                .gltf(&leaf)
+                .map(|gltf| {
+                    gltf.nodes
+                        .iter()
+                        .filter_map(|node| block_assets.gltf_node_assets.get(node))
+                        .flat_map(|node| {
+                            colliders_from_gltf_node(
+                                node,
+                                &block_assets.gltf_mesh_assets,
+                                &block_assets.mesh_assets,
+                                &ComputedColliderShape::ConvexHull,
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            if hulls.is_empty() {
+                // no usable mesh, fall back to the catalog box
+                let center = local.transform_point(Vec3::from(data.aabb.center));
+                shapes.push((center, local.rotation, Collider::cuboid(x, y, z)));
+            }
+            shapes.extend(
+                hulls
+                    .into_iter()
+                    .map(|hull| (local.translation, local.rotation, hull)),
+            );
+        }
+    }
+    if shapes.is_empty() {
+        return;
+    }
+
+    // prefab parts are fixed bodies of their own, only the root moves now
+    for descendant in children_query.iter_descendants(entity) {
+        if collider_query.contains(descendant) {
+            commands.entity(descendant).remove::<Collider>();
+        }
+        commands.entity(descendant).remove::<RigidBody>();
+    }
+
+    commands
+        .entity(entity)
+        .remove::<ColliderDisabled>()
+        .insert((
+            RigidBody::Dynamic,
+            Collider::compound(shapes),
+            ColliderMassProperties::Mass(volume * BLOCK_DENSITY),
+            Velocity::zero(),
+            Visibility::Inherited,
+            Debris::default(),
+        ));
+}
+
+/// Fixes debris in place once it has come to rest, it then hangs from the block it landed on and
+/// supports blocks again. Debris that falls below the kill height is despawned.
+pub fn settle_debris(
+    mut commands: Commands,
+    time: Res<Time>,
+    respawn_settings: Res<RespawnSettings>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    block_assets: BlockAssets,
+    mut debris_query: Query<(Entity, &mut Debris, &Velocity, &Transform, &BlockInstance)>,
+    mut island_query: Query<&mut Island>,
+    children_query: Query<&Children>,
+    prefab_query: Query<(), With<Prefab>>,
+) {
+    for (entity, mut debris, velocity, transform, block) in debris_query.iter_mut() {
+        if transform.translation.y < respawn_settings.kill_height {
+            spatial_index.remove(entity);
+            if let Ok(mut island) = island_query.get_mut(block.island) {
+                island.remove_block(entity);
+            }
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+        if velocity.linvel.length() > SETTLE_SPEED || velocity.angvel.length() > SETTLE_SPEED {
+            debris.still_for = 0.0;
+            continue;
+        }
+        debris.still_for += time.delta_seconds();
+        if debris.still_for < SETTLE_TIME {
+            continue;
+        }
+
+        commands
+            .entity(entity)
+            .remove::<(Debris, Velocity)>()
+            .insert(RigidBody::Fixed);
+        let footprint = block_assets.footprint(&block.id(), transform);
+        let landed_on = resting_on(&spatial_index, &footprint);
+        if prefab_query.contains(entity) {
+            let parts = prefab_leaves(entity, &children_query, &prefab_query);
+            spatial_index.insert_prefab(entity, footprint, parts);
+        } else {
+            spatial_index.insert(entity, footprint);
+        }
+        if let Some(parent) = landed_on {
+            spatial_index.set_parent(entity, parent);
+        }
+        if let Ok(mut island) = island_query.get_mut(block.island) {
+            island.move_block(entity, *transform);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube(x: f32, y: f32) -> Obb {
+        Obb {
+            center: Vec3::new(x, y, 0.0),
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::splat(0.5),
+        }
+    }
+
+    /// Blocks at the given spots, each grown from the block at its parent index.
+    fn blocks(placed: &[(f32, f32, Option<usize>)]) -> (BlockSpatialIndex, Vec<Entity>) {
+        let mut index = BlockSpatialIndex::new(2.0);
+        let entities: Vec<Entity> = (0..placed.len() as u32).map(Entity::from_raw).collect();
+        for (entity, (x, y, parent)) in entities.iter().zip(placed) {
+            index.insert(*entity, vec![cube(*x, *y)]);
+            if let Some(parent) = parent {
+                index.set_parent(*entity, entities[*parent]);
+            }
+        }
+        (index, entities)
+    }
+
+    /// Takes a block out the way `destroy_blocks` does and returns what falls.
+    fn destroy(index: &mut BlockSpatialIndex, entity: Entity) -> Vec<Entity> {
+        let candidates = index.children(entity).to_vec();
+        index.remove(entity);
+        let mut falling = unsupported(index, &candidates);
+        for entity in &falling {
+            index.remove(*entity);
+        }
+        falling.sort();
+        falling
+    }
+
+    #[test]
+    fn tower_falls_above_the_gap() {
+        let (mut index, e) = blocks(&[(0.0, 0.5, None), (0.0, 1.5, Some(0)), (0.0, 2.5, Some(1))]);
+
+        assert!(is_grounded(&index, e[0]));
+        assert!(!is_grounded(&index, e[1]));
+        assert!(unsupported(&index, &[e[1], e[2]]).is_empty());
+        assert_eq!(destroy(&mut index, e[1]), vec![e[2]]);
+    }
+
+    #[test]
+    fn bridge_hangs_from_its_start() {
+        // two towers with a bridge from the left one's top resting on the right one's
+        let (mut index, e) = blocks(&[
+            (0.0, 0.5, None),
+            (0.0, 1.5, Some(0)),
+            (1.0, 1.5, Some(1)),
+            (2.0, 1.5, Some(2)),
+            (3.0, 1.5, Some(3)),
+            (4.0, 0.5, None),
+            (4.0, 1.5, Some(5)),
+        ]);
+
+        // the bridge touching the right tower doesn't hold its top up
+        assert_eq!(destroy(&mut index, e[5]), vec![e[6]]);
+        // the bridge comes down with the block it starts from
+        assert_eq!(destroy(&mut index, e[1]), vec![e[2], e[3], e[4]]);
+    }
+
+    #[test]
+    fn cantilever_stands_on_its_parent() {
+        // a tower with an arm sticking out sideways over nothing, and a block grounded under
+        // the arm's end that was grown from the arm
+        let (mut index, e) = blocks(&[
+            (0.0, 0.5, None),
+            (0.0, 1.5, Some(0)),
+            (1.0, 1.5, Some(1)),
+            (2.0, 1.5, Some(2)),
+            (2.0, 0.5, Some(3)),
+        ]);
+
+        assert!(is_supported(&index, &[cube(1.0, 2.5)]));
+        assert!(!is_supported(&index, &[cube(5.0, 1.5)]));
+        assert!(unsupported(&index, &[e[2], e[3]]).is_empty());
+        // the grounded block stays when the arm goes
+        assert_eq!(destroy(&mut index, e[1]), vec![e[2], e[3]]);
+        assert!(index.contains(e[4]));
+        assert_eq!(index.parent(e[4]), None);
+    }
+}
@@ -0,0 +1,137 @@
+use std::ops::Range;
+
+use bevy::prelude::*;
+use rand::Rng;
+
+use super::obb::Obb;
+use super::reachability::MovementLimits;
+
+/// How tall islands may grow. Each island draws its own storey limit from `storeys`.
+#[derive(Resource)]
+pub struct VerticalSettings {
+    pub max_height: f32,
+    pub storey_height: f32,
+    pub min_storeys: usize,
+    pub storeys: Range<usize>,
+    pub must_be_supported: bool,
+    /// blocks have to be within the player's jumping reach of the ground or another block
+    pub reachable_only: bool,
+}
+
+impl Default for VerticalSettings {
+    fn default() -> Self {
+        VerticalSettings {
+            max_height: 30.0,
+            storey_height: 6.0,
+            min_storeys: 1,
+            storeys: 1..4,
+            must_be_supported: true,
+            reachable_only: false,
+        }
+    }
+}
+
+impl VerticalSettings {
+    /// An island's limits. `limits` is the player's reach, needed for `reachable_only`.
+    pub fn rules_for(&self, rng: &mut impl Rng, limits: Option<&MovementLimits>) -> VerticalRules {
+        let max_storeys = rng.gen_range(self.storeys.clone()).max(1);
+        VerticalRules {
+            max_height: self.max_height,
+            storey_height: self.storey_height,
+            min_storeys: self.min_storeys.min(max_storeys),
+            max_storeys,
+            must_be_supported: self.must_be_supported,
+            reach: limits.copied().filter(|_| self.reachable_only),
+        }
+    }
+}
+
+/// An island's height limits.
+#[derive(Clone)]
+pub struct VerticalRules {
+    /// no block may reach above this
+    pub max_height: f32,
+    /// blocks are counted in storeys of this height, by where their bottom sits
+    pub storey_height: f32,
+    /// the island isn't done until it reaches this many storeys
+    pub min_storeys: usize,
+    pub max_storeys: usize,
+    /// blocks have to rest on the ground or on another block
+    pub must_be_supported: bool,
+    /// set when blocks the player can't jump onto are turned down
+    pub reach: Option<MovementLimits>,
+}
+
+impl VerticalRules {
+    /// The storey a footprint stands on, counting the ground floor as 0.
+    pub fn storey(&self, footprint: &[Obb]) -> usize {
+        let bottom = footprint
+            .iter()
+            .map(|obb| obb.min().y)
+            .fold(f32::MAX, f32::min)
+            .max(0.0);
+        // a little slack so blocks sitting exactly on a storey line count as the storey above
+        ((bottom + 0.01) / self.storey_height.max(f32::EPSILON)) as usize
+    }
+
+    /// Whether a footprint stays under the height and storey limits.
+    pub fn fits(&self, footprint: &[Obb]) -> bool {
+        let top = footprint
+            .iter()
+            .map(|obb| obb.max().y)
+            .fold(f32::MIN, f32::max);
+        top <= self.max_height && self.storey(footprint) < self.max_storeys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules() -> VerticalRules {
+        VerticalRules {
+            max_height: 20.0,
+            storey_height: 6.0,
+            min_storeys: 1,
+            max_storeys: 2,
+            must_be_supported: true,
+            reach: None,
+        }
+    }
+
+    // a 2 high block with its bottom at `bottom`
+    fn block(bottom: f32) -> Obb {
+        Obb {
+            center: Vec3::new(0.0, bottom + 1.0, 0.0),
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::ONE,
+        }
+    }
+
+    #[test]
+    fn storey_is_counted_from_the_bottom() {
+        let rules = rules();
+        assert_eq!(rules.storey(&[block(0.0)]), 0);
+        assert_eq!(rules.storey(&[block(5.9)]), 0);
+        // sitting on the storey line counts as the storey above
+        assert_eq!(rules.storey(&[block(6.0)]), 1);
+        assert_eq!(rules.storey(&[block(12.0)]), 2);
+        // the lowest box of a footprint decides
+        assert_eq!(rules.storey(&[block(12.0), block(3.0)]), 0);
+    }
+
+    #[test]
+    fn fits_stops_at_the_storey_and_height_limits() {
+        let rules = rules();
+        assert!(rules.fits(&[block(0.0)]));
+        assert!(rules.fits(&[block(11.0)]));
+        assert!(!rules.fits(&[block(12.0)]));
+
+        let tall = VerticalRules {
+            max_storeys: 10,
+            ..rules
+        };
+        assert!(tall.fits(&[block(18.0)]));
+        assert!(!tall.fits(&[block(18.5)]));
+    }
+}
@@ -0,0 +1,391 @@
+use std::{collections::VecDeque, fs};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+use serde::{Deserialize, Serialize};
+
+use super::obb::Obb;
+use super::spatial::BlockSpatialIndex;
+
+// voxels along each side of a chunk
+const CHUNK: i32 = 16;
+const CHUNK_VOLUME: usize = (CHUNK * CHUNK * CHUNK) as usize;
+
+#[derive(Resource)]
+pub struct VoxelSettings {
+    /// side of a voxel, changing it rebuilds the grid
+    pub voxel_size: f32,
+}
+
+impl Default for VoxelSettings {
+    fn default() -> Self {
+        VoxelSettings { voxel_size: 1.0 }
+    }
+}
+
+/// Where to write the voxel grid once the world has generated, set with `--voxels <path>`.
+#[derive(Resource, Default)]
+pub struct VoxelExport {
+    pub path: Option<String>,
+}
+
+/// How many blocks cover each voxel of a chunk, so overlapping blocks can be removed one at a time.
+struct VoxelChunk {
+    counts: Vec<u8>,
+    /// voxels with a non-zero count
+    solid: usize,
+}
+
+impl VoxelChunk {
+    fn index(local: IVec3) -> usize {
+        (local.x + CHUNK * (local.z + CHUNK * local.y)) as usize
+    }
+}
+
+/// The world's blocks as a coarse occupancy grid, a voxel is solid when its centre lies inside
+/// a block's box. Kept in step with the spatial index.
+#[derive(Resource)]
+pub struct VoxelGrid {
+    voxel_size: f32,
+    chunks: HashMap<IVec3, VoxelChunk>,
+    /// the voxels each block fills, to take them out again
+    blocks: HashMap<Entity, Vec<IVec3>>,
+}
+
+impl Default for VoxelGrid {
+    fn default() -> Self {
+        VoxelGrid::new(VoxelSettings::default().voxel_size)
+    }
+}
+
+impl VoxelGrid {
+    pub fn new(voxel_size: f32) -> Self {
+        VoxelGrid {
+            voxel_size,
+            chunks: HashMap::new(),
+            blocks: HashMap::new(),
+        }
+    }
+
+    pub fn voxel_size(&self) -> f32 {
+        self.voxel_size
+    }
+
+    /// The voxel holding `point`.
+    pub fn voxel(&self, point: Vec3) -> IVec3 {
+        (point / self.voxel_size).floor().as_ivec3()
+    }
+
+    pub fn voxel_center(&self, voxel: IVec3) -> Vec3 {
+        (voxel.as_vec3() + Vec3::splat(0.5)) * self.voxel_size
+    }
+
+    fn split(voxel: IVec3) -> (IVec3, IVec3) {
+        let chunk = voxel.div_euclid(IVec3::splat(CHUNK));
+        (chunk, voxel - chunk * CHUNK)
+    }
+
+    pub fn is_solid(&self, voxel: IVec3) -> bool {
+        let (chunk, local) = Self::split(voxel);
+        self.chunks
+            .get(&chunk)
+            .is_some_and(|chunk| chunk.counts[VoxelChunk::index(local)] > 0)
+    }
+
+    pub fn is_solid_at(&self, point: Vec3) -> bool {
+        self.is_solid(self.voxel(point))
+    }
+
+    pub fn solid_count(&self) -> usize {
+        self.chunks.values().map(|chunk| chunk.solid).sum()
+    }
+
+    fn add(&mut self, voxel: IVec3) {
+        let (chunk, local) = Self::split(voxel);
+        let chunk = self.chunks.entry(chunk).or_insert_with(|| VoxelChunk {
+            counts: vec![0; CHUNK_VOLUME],
+            solid: 0,
+        });
+        let count = &mut chunk.counts[VoxelChunk::index(local)];
+        if *count == 0 {
+            chunk.solid += 1;
+        }
+        *count = count.saturating_add(1);
+    }
+
+    fn subtract(&mut self, voxel: IVec3) {
+        let (chunk_coord, local) = Self::split(voxel);
+        let Some(chunk) = self.chunks.get_mut(&chunk_coord) else {
+            return;
+        };
+        let count = &mut chunk.counts[VoxelChunk::index(local)];
+        if *count == 1 {
+            chunk.solid -= 1;
+        }
+        *count = count.saturating_sub(1);
+        if chunk.solid == 0 {
+            self.chunks.remove(&chunk_coord);
+        }
+    }
+
+    /// Fills the voxels whose centres lie in any of a block's boxes.
+    pub fn insert(&mut self, entity: Entity, boxes: &[Obb]) {
+        self.remove(entity);
+
+        let mut voxels: Vec<IVec3> = Vec::new();
+        for obb in boxes {
+            let (min, max) = (self.voxel(obb.min()), self.voxel(obb.max()));
+            for x in min.x..=max.x {
+                for y in min.y..=max.y {
+                    for z in min.z..=max.z {
+                        let voxel = IVec3::new(x, y, z);
+                        if obb.distance_to(self.voxel_center(voxel)) <= 0.0 {
+                            voxels.push(voxel);
+                        }
+                    }
+                }
+            }
+        }
+        voxels.sort_by_key(|v| (v.x, v.y, v.z));
+        voxels.dedup();
+
+        for voxel in &voxels {
+            self.add(*voxel);
+        }
+        self.blocks.insert(entity, voxels);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        for voxel in self.blocks.remove(&entity).unwrap_or_default() {
+            self.subtract(voxel);
+        }
+    }
+
+    /// Top of the highest solid voxel in the column over `x`, `z`. None for an empty column.
+    pub fn column_height(&self, x: f32, z: f32) -> Option<f32> {
+        let column = self.voxel(Vec3::new(x, 0.0, z));
+        let (chunk_column, local) = Self::split(column);
+
+        let mut chunk_ys: Vec<i32> = self
+            .chunks
+            .keys()
+            .filter(|chunk| chunk.x == chunk_column.x && chunk.z == chunk_column.z)
+            .map(|chunk| chunk.y)
+            .collect();
+        chunk_ys.sort_unstable_by(|a, b| b.cmp(a));
+
+        chunk_ys.into_iter().find_map(|chunk_y| {
+            let chunk = &self.chunks[&IVec3::new(chunk_column.x, chunk_y, chunk_column.z)];
+            (0..CHUNK).rev().find_map(|y| {
+                let index = VoxelChunk::index(IVec3::new(local.x, y, local.z));
+                (chunk.counts[index] > 0)
+                    .then(|| (chunk_y * CHUNK + y + 1) as f32 * self.voxel_size)
+            })
+        })
+    }
+
+    /// Empty voxels connected to `start` through their faces, at most `limit` of them and none
+    /// outside `min..=max`. Empty if `start` itself is solid.
+    pub fn flood_fill(&self, start: IVec3, min: IVec3, max: IVec3, limit: usize) -> Vec<IVec3> {
+        let inside = |voxel: IVec3| voxel.cmpge(min).all() && voxel.cmple(max).all();
+        if self.is_solid(start) || !inside(start) {
+            return Vec::new();
+        }
+
+        let mut filled = vec![start];
+        let mut seen: HashSet<IVec3> = HashSet::new();
+        seen.insert(start);
+        let mut queue = VecDeque::from([start]);
+        while let Some(voxel) = queue.pop_front() {
+            for offset in [
+                IVec3::X,
+                IVec3::NEG_X,
+                IVec3::Y,
+                IVec3::NEG_Y,
+                IVec3::Z,
+                IVec3::NEG_Z,
+            ] {
+                if filled.len() >= limit {
+                    return filled;
+                }
+                let next = voxel + offset;
+                if inside(next) && !self.is_solid(next) && seen.insert(next) {
+                    filled.push(next);
+                    queue.push_back(next);
+                }
+            }
+        }
+        filled
+    }
+
+    /// Writes the solid voxels, one bit each, chunk by chunk.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut chunks: Vec<SavedChunk> = self
+            .chunks
+            .iter()
+            .map(|(coord, chunk)| {
+                let mut bits = vec![0u64; CHUNK_VOLUME / 64];
+                for (i, count) in chunk.counts.iter().enumerate() {
+                    if *count > 0 {
+                        bits[i / 64] |= 1 << (i % 64);
+                    }
+                }
+                SavedChunk {
+                    coord: coord.to_array(),
+                    bits,
+                }
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.coord);
+
+        let saved = SavedGrid {
+            voxel_size: self.voxel_size,
+            chunk_size: CHUNK,
+            chunks,
+        };
+        let json = serde_json::to_string(&saved).map_err(|err| err.to_string())?;
+        fs::write(path, json).map_err(|err| err.to_string())
+    }
+
+    /// Reads a grid written by `save`. It knows which voxels are solid, not which blocks
+    /// filled them, so it is for looking at rather than keeping in step with the world.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let saved: SavedGrid = serde_json::from_str(&json).map_err(|err| err.to_string())?;
+        if saved.chunk_size != CHUNK {
+            return Err(format!(
+                "chunks of {} voxels, expected {}",
+                saved.chunk_size, CHUNK
+            ));
+        }
+
+        let mut grid = VoxelGrid::new(saved.voxel_size);
+        for chunk in saved.chunks {
+            let counts: Vec<u8> = (0..CHUNK_VOLUME)
+                .map(|i| ((chunk.bits.get(i / 64).unwrap_or(&0) >> (i % 64)) & 1) as u8)
+                .collect();
+            let solid = counts.iter().filter(|count| **count > 0).count();
+            grid.chunks
+                .insert(IVec3::from_array(chunk.coord), VoxelChunk { counts, solid });
+        }
+        Ok(grid)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedChunk {
+    coord: [i32; 3],
+    bits: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGrid {
+    voxel_size: f32,
+    chunk_size: i32,
+    chunks: Vec<SavedChunk>,
+}
+
+/// Brings the grid up to date with the blocks placed, moved or removed since last frame,
+/// or rebuilds it from every block when the voxel size has changed.
+pub fn update_voxel_grid(
+    mut grid: ResMut<VoxelGrid>,
+    mut spatial_index: ResMut<BlockSpatialIndex>,
+    settings: Res<VoxelSettings>,
+) {
+    let changed = spatial_index.take_changed();
+    if grid.voxel_size != settings.voxel_size {
+        let _span = info_span!("rebuild_voxel_grid").entered();
+        *grid = VoxelGrid::new(settings.voxel_size);
+        for entity in spatial_index.entities() {
+            if let Some(boxes) = spatial_index.boxes(entity) {
+                grid.insert(entity, boxes);
+            }
+        }
+        return;
+    }
+
+    if changed.is_empty() {
+        return;
+    }
+    let _span = info_span!("update_voxel_grid").entered();
+    for entity in changed {
+        match spatial_index.boxes(entity) {
+            Some(boxes) => grid.insert(entity, boxes),
+            None => grid.remove(entity),
+        }
+    }
+}
+
+pub fn write_voxel_grid(export: Res<VoxelExport>, grid: Res<VoxelGrid>) {
+    let Some(path) = &export.path else {
+        return;
+    };
+    match grid.save(path) {
+        Ok(()) => info!(
+            "Wrote {} solid voxels of {}m to {}",
+            grid.solid_count(),
+            grid.voxel_size,
+            path
+        ),
+        Err(err) => error!("Could not write {}: {}", path, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // two voxels along each side, covering `min..min + 2`
+    fn cube(min: Vec3) -> Obb {
+        Obb {
+            center: min + Vec3::ONE,
+            rotation: Quat::IDENTITY,
+            half_extents: Vec3::ONE,
+        }
+    }
+
+    #[test]
+    fn overlapping_blocks_share_voxels() {
+        let mut grid = VoxelGrid::new(1.0);
+        let (a, b) = (Entity::from_raw(1), Entity::from_raw(2));
+        grid.insert(a, &[cube(Vec3::ZERO)]);
+        assert_eq!(grid.solid_count(), 8);
+        grid.insert(b, &[cube(Vec3::X)]);
+        assert_eq!(grid.solid_count(), 12);
+
+        // the shared column stays solid until both blocks are gone
+        grid.remove(a);
+        assert_eq!(grid.solid_count(), 8);
+        assert!(grid.is_solid(IVec3::new(1, 0, 0)));
+        assert!(!grid.is_solid(IVec3::ZERO));
+        grid.remove(b);
+        assert_eq!(grid.solid_count(), 0);
+        assert!(!grid.is_solid(IVec3::new(1, 0, 0)));
+    }
+
+    #[test]
+    fn reinserting_replaces_a_block() {
+        let mut grid = VoxelGrid::new(1.0);
+        let a = Entity::from_raw(1);
+        grid.insert(a, &[cube(Vec3::ZERO)]);
+        grid.insert(a, &[cube(Vec3::splat(4.0))]);
+        assert_eq!(grid.solid_count(), 8);
+        assert!(!grid.is_solid_at(Vec3::splat(0.5)));
+        assert!(grid.is_solid_at(Vec3::splat(4.5)));
+    }
+
+    #[test]
+    fn empty_chunks_are_dropped() {
+        let mut grid = VoxelGrid::new(1.0);
+        let a = Entity::from_raw(1);
+        // straddles the corner of eight chunks
+        grid.insert(a, &[cube(Vec3::splat(-1.0))]);
+        assert_eq!(grid.solid_count(), 8);
+        assert_eq!(grid.chunks.len(), 8);
+        grid.remove(a);
+        assert!(grid.chunks.is_empty());
+        assert_eq!(grid.column_height(0.5, 0.5), None);
+    }
+}
@@ -59,6 +59,16 @@ def calculate_aabb(collection):
     return AABB.from_bounds(min_corner, max_corner, True)
 
 
+RULE_PROPERTIES = ["seed_weight", "min_count", "max_count", "landmark", "connector"]
+
+
+def collection_rules(collection):
+    """
+    Reads the generation rules stored as custom properties on a collection.
+    """
+    return {key: collection[key] for key in RULE_PROPERTIES if key in collection}
+
+
 def export_collections_as_glb(referenced_collections, output_dir):
     """
     Exports each referenced collection as a GLB file.
@@ -158,6 +168,7 @@ def main():
                     "file": f"{collection.name}.glb",
                     "aabb": aabb.to_dict(),
                     "transforms": [],
+                    "rules": collection_rules(collection),
                 }
             referenced_collections.add(collection)
 